@@ -0,0 +1,209 @@
+//! Benchmarks for the storage catalog with a generated library of about 20k items.
+//!
+//! Run with `cargo bench --bench storage`, filter with e.g. `cargo bench --bench storage -- page`.
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::LibrarySpec;
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use jelly_video_app_lib::storage::catalog;
+use jelly_video_app_lib::storage::query::{ItemFilter, Resolution, Sort, SortBy};
+use jelly_video_app_lib::storage::search::SearchIndex;
+use std::fs;
+
+fn page(c: &mut Criterion) {
+    let metadata = common::library(LibrarySpec::large());
+    let user_data = common::user_data(&metadata, 0.3);
+    let mut group = c.benchmark_group("page");
+
+    group.bench_function("first", |b| {
+        b.iter(|| {
+            catalog::page(&metadata, &user_data, "Episode", 50, None, Sort::default(), &ItemFilter::default()).unwrap()
+        })
+    });
+
+    // The cursor a client has after scrolling through 200 pages
+    let mut cursor = None;
+    for _ in 0..200 {
+        cursor = catalog::page(
+            &metadata,
+            &user_data,
+            "Episode",
+            50,
+            cursor.as_deref(),
+            Sort::default(),
+            &ItemFilter::default(),
+        )
+        .unwrap()
+        .next_cursor;
+    }
+    group.bench_function("deep", |b| {
+        b.iter(|| {
+            catalog::page(
+                &metadata,
+                &user_data,
+                "Episode",
+                50,
+                cursor.as_deref(),
+                Sort::default(),
+                &ItemFilter::default(),
+            )
+            .unwrap()
+        })
+    });
+
+    let by_name = Sort {
+        sort_by: SortBy::SortName,
+        sort_order: None,
+    };
+    group.bench_function("by_name", |b| {
+        b.iter(|| catalog::page(&metadata, &user_data, "Episode", 50, None, by_name, &ItemFilter::default()).unwrap())
+    });
+
+    let filter = ItemFilter {
+        genres: vec!["Drama".to_string(), "Comedy".to_string()],
+        min_year: Some(1990),
+        is_played: Some(false),
+        resolutions: vec![Resolution::Hd, Resolution::Uhd],
+        ..Default::default()
+    };
+    group.bench_function("filtered", |b| {
+        b.iter(|| catalog::page(&metadata, &user_data, "Movie", 50, None, Sort::default(), &filter).unwrap())
+    });
+
+    let in_series = ItemFilter {
+        container_id: Some("series0100".to_string()),
+        ..Default::default()
+    };
+    group.bench_function("in_container", |b| {
+        b.iter(|| catalog::page(&metadata, &user_data, "Episode", 50, None, Sort::default(), &in_series).unwrap())
+    });
+
+    group.finish();
+}
+
+fn search(c: &mut Criterion) {
+    let metadata = common::library(LibrarySpec::large());
+    let user_data = common::user_data(&metadata, 0.3);
+    let index = SearchIndex::build(&metadata);
+    let mut group = c.benchmark_group("search");
+    group.sample_size(20);
+
+    // Rebuilt after every catalog change
+    group.bench_function("build_index", |b| b.iter(|| SearchIndex::build(black_box(&metadata))));
+
+    group.bench_function("word", |b| b.iter(|| index.search(black_box("silent"), 50)));
+    group.bench_function("prefix", |b| b.iter(|| index.search(black_box("harb"), 50)));
+    group.bench_function("typo", |b| b.iter(|| index.search(black_box("golden kingdmo"), 50)));
+
+    let matches = index.search("silent", 50);
+    group.bench_function("items", |b| {
+        b.iter(|| catalog::media_items(&metadata, &user_data, matches.iter().map(|(id, _)| id.as_str())))
+    });
+
+    group.finish();
+}
+
+fn save(c: &mut Criterion) {
+    let metadata = common::library(LibrarySpec::large());
+    let storage_dir = common::temp_dir("bench-save");
+    let path = storage_dir.join("metadata.json");
+    let key = [7u8; 32];
+    let mut group = c.benchmark_group("save");
+    group.sample_size(20);
+
+    // What every `get_cached_metadata` call pays
+    group.bench_function("clone_catalog", |b| b.iter_with_large_drop(|| black_box(&metadata).clone()));
+
+    let mut data = metadata.tracks["movie00000"].clone();
+    data.container_id = None;
+    group.bench_function("plan_and_finish", |b| {
+        b.iter_batched(
+            || metadata.clone(),
+            |mut metadata| {
+                let plan = catalog::plan_save(&mut metadata, "new-item", &data, true).unwrap();
+                catalog::finish_save(&mut metadata, "new-item", data.clone(), None);
+                (plan, metadata)
+            },
+            BatchSize::LargeInput,
+        )
+    });
+
+    group.bench_function("write_catalog", |b| b.iter(|| catalog::save(&path, &metadata, None).unwrap()));
+    group.bench_function("write_catalog_encrypted", |b| {
+        b.iter(|| catalog::save(&path, &metadata, Some(&key)).unwrap())
+    });
+
+    catalog::save(&path, &metadata, None).unwrap();
+    group.bench_function("load_catalog", |b| b.iter_with_large_drop(|| catalog::load(&path, None).unwrap()));
+
+    group.finish();
+    let _ = fs::remove_dir_all(storage_dir);
+}
+
+fn remove(c: &mut Criterion) {
+    let metadata = common::library(LibrarySpec::large());
+    let storage_dir = common::temp_dir("bench-remove");
+    common::write_files(&storage_dir, &metadata);
+    let mut group = c.benchmark_group("remove");
+    group.sample_size(20);
+
+    // The catalog is returned so dropping it isn't measured
+    group.bench_function("movie", |b| {
+        b.iter_batched(
+            || metadata.clone(),
+            |mut metadata| {
+                let removed = catalog::remove(&mut metadata, "movie02000", None);
+                (removed, metadata)
+            },
+            BatchSize::LargeInput,
+        )
+    });
+
+    // Releases the 4 seasons and 80 episodes below it
+    group.bench_function("series", |b| {
+        b.iter_batched(
+            || metadata.clone(),
+            |mut metadata| {
+                let removed = catalog::remove(&mut metadata, "series0100", None);
+                (removed, metadata)
+            },
+            BatchSize::LargeInput,
+        )
+    });
+
+    // Deleting one item's files scans the whole storage directory
+    let movie = ["movie02000".to_string()];
+    group.bench_function("files", |b| {
+        b.iter_batched(
+            || {
+                fs::write(storage_dir.join("movie02000.blob"), [0u8; 64]).unwrap();
+                fs::write(storage_dir.join("movie02000.thumb"), [0u8; 16]).unwrap();
+            },
+            |_| catalog::remove_track_files(&storage_dir, &movie).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+
+    // A recursive remove deletes the files of all 85 items with one scan
+    let series = catalog::remove(&mut metadata.clone(), "series0100", None);
+    group.bench_function("series files", |b| {
+        b.iter_batched(
+            || {
+                for id in &series {
+                    fs::write(storage_dir.join(format!("{}.blob", id)), [0u8; 64]).unwrap();
+                    fs::write(storage_dir.join(format!("{}.thumb", id)), [0u8; 16]).unwrap();
+                }
+            },
+            |_| catalog::remove_track_files(&storage_dir, &series).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.finish();
+    let _ = fs::remove_dir_all(storage_dir);
+}
+
+criterion_group!(benches, page, search, save, remove);
+criterion_main!(benches);
@@ -1,4 +1,4 @@
-mod storage;
+pub mod storage;
 use tauri::{Manager, PhysicalSize, Size};
 use tauri_plugin_window_state::StateFlags;
 
@@ -11,7 +11,13 @@ pub fn run() {
         .plugin(tauri_plugin_fs::init())
         .plugin(tauri_plugin_shell::init())
         .manage(storage::DownloadManager::default())
+        .manage(storage::server::StreamServer::default())
         .setup(|app| {
+            storage::init_storage(app.handle())?;
+            storage::playback::spawn_playback_sync(app.handle().clone());
+            storage::refresh::spawn_metadata_refresh(app.handle().clone());
+            storage::sync::spawn_series_sync(app.handle().clone());
+
             let window = app.get_webview_window("main").unwrap();
 
             if let Ok(Some(monitor)) = window.current_monitor() {
@@ -51,9 +57,28 @@ pub fn run() {
             storage::storage_clear_all,
             storage::storage_get_page,
             storage::storage_search_items,
+            storage::search::storage_search_grouped,
             storage::storage_get_stats,
             storage::storage_abort_downloads,
             storage::get_storage_path,
+            storage::server::storage_get_stream_url,
+            storage::archive::storage_export_archive,
+            storage::archive::storage_import_archive,
+            storage::export::storage_export,
+            storage::import::storage_import,
+            storage::jellyfin::storage_set_server,
+            storage::playback::storage_report_playback,
+            storage::playback::storage_sync_playback,
+            storage::refresh::storage_refresh_metadata,
+            storage::settings::storage_get_settings,
+            storage::settings::storage_set_settings,
+            storage::sync::storage_add_sync,
+            storage::sync::storage_remove_sync,
+            storage::sync::storage_get_syncs,
+            storage::sync::storage_run_sync,
+            storage::tree::storage_get_container_tree,
+            storage::versions::storage_get_versions,
+            storage::versions::storage_remove_version,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -1,14 +1,38 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Mutex};
-use tauri::{AppHandle, Manager, State, Emitter};
-use futures_util::StreamExt;
-use tokio::io::AsyncWriteExt;
+use std::time::{Duration, Instant};
+use tauri::ipc::Channel;
+use tauri::{AppHandle, Manager, State};
+use tokio::sync::Notify;
 use tokio_util::sync::CancellationToken;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+pub mod archive;
+pub mod catalog;
+pub mod crypto;
+pub mod download;
+pub mod events;
+pub mod export;
+pub mod import;
+pub mod jellyfin;
+pub mod playback;
+pub mod progress;
+pub mod query;
+pub mod reconcile;
+pub mod search;
+pub mod refresh;
+pub mod server;
+pub mod settings;
+pub mod stats;
+pub mod sync;
+pub mod tree;
+pub mod upgrade;
+pub mod versions;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct StorageTrack {
     #[serde(rename = "type")]
@@ -22,24 +46,210 @@ pub struct StorageTrack {
     pub media_sources: Option<serde_json::Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub media_source_id: Option<String>,
+    /// Size of the downloaded blob in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Set by the reconciliation pass when the server copy no longer matches ours
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_status: Option<ServerStatus>,
+    /// Further containers this item was downloaded through, they all share the one blob
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub references: Vec<String>,
+    /// Also downloaded on its own, not only through `container_id`. Items without a container are always direct.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub direct: bool,
+    /// Other media sources downloaded next to `media_source_id`, which stays the preferred version
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub versions: Vec<versions::StorageVersion>,
+    /// Whether `{id}.thumb` was saved, so listing pages doesn't stat every file.
+    /// `None` in catalogs written before this was recorded, `init_storage` fills it in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub has_thumbnail: Option<bool>,
+}
+
+impl StorageTrack {
+    /// Every container holding this item
+    pub fn container_ids(&self) -> impl Iterator<Item = &str> {
+        self.container_id.iter().chain(self.references.iter()).map(|id| id.as_str())
+    }
+
+    pub fn is_direct(&self) -> bool {
+        self.direct || self.container_id.is_none()
+    }
+
+    /// Records that the item is also wanted by `container_id`, or on its own when `None`
+    pub fn add_reference(&mut self, container_id: Option<&str>) {
+        match container_id {
+            None => self.direct = self.direct || self.container_id.is_some(),
+            Some(container_id) if self.container_ids().any(|id| id == container_id) => {}
+            Some(container_id) if self.container_id.is_none() => {
+                self.direct = true;
+                self.container_id = Some(container_id.to_string());
+            }
+            Some(container_id) => self.references.push(container_id.to_string()),
+        }
+    }
+
+    /// Drops the reference held by `container_id`, or the direct download when `None`.
+    /// Returns true when nothing references the item anymore.
+    pub fn release(&mut self, container_id: Option<&str>) -> bool {
+        match container_id {
+            None if self.container_id.is_none() => return true,
+            None => self.direct = false,
+            Some(container_id) if self.container_id.as_deref() == Some(container_id) => {
+                // The next container takes over, or the item becomes a direct download if it was one
+                self.container_id = (!self.references.is_empty()).then(|| self.references.remove(0));
+                if self.container_id.is_none() {
+                    return !std::mem::take(&mut self.direct);
+                }
+            }
+            Some(container_id) => self.references.retain(|id| id != container_id),
+        }
+
+        false
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ServerStatus {
+    OrphanedOnServer,
+    NewerVersionAvailable,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
 pub struct StorageMetadata {
     pub tracks: HashMap<String, StorageTrack>,
+    #[serde(default)]
+    pub subscriptions: HashMap<String, sync::SyncSubscription>,
 }
 
 #[derive(Default)]
 pub struct DownloadManager {
     cancellation_token: Arc<Mutex<Option<CancellationToken>>>,
     cached_metadata: Arc<Mutex<Option<StorageMetadata>>>,
+    cached_playback: Arc<Mutex<Option<playback::PlaybackStore>>>,
+    jellyfin_auth: Arc<Mutex<Option<jellyfin::JellyfinAuth>>>,
+    syncing_playback: Arc<AtomicBool>,
+    cached_settings: Arc<Mutex<Option<settings::StorageSettings>>>,
+    refreshing_metadata: Arc<AtomicBool>,
+    upgrade_cancellation_token: Arc<Mutex<Option<CancellationToken>>>,
+    upgrading: Arc<AtomicBool>,
+    sync_cancellation_token: Arc<Mutex<Option<CancellationToken>>>,
+    syncing_series: Arc<AtomicBool>,
+    /// Woken whenever `syncing_series` is released
+    sync_finished: Arc<Notify>,
+    cached_search_index: Arc<Mutex<Option<search::SearchIndex>>>,
+    metadata_write_lock: Arc<Mutex<()>>,
+    playback_write_lock: Arc<Mutex<()>>,
+    /// Ids whose files are being written, see `claim_download`
+    download_claims: Arc<Mutex<HashSet<String>>>,
+    download_queue: Arc<Mutex<progress::DownloadQueue>>,
+    /// Items the player reported as playing until it reports them stopped
+    playing: Arc<Mutex<HashSet<String>>>,
+    /// Stream server responses still streaming an item's video, by item id
+    serving: Arc<Mutex<HashMap<String, usize>>>,
+    /// Loaded from the credential store on first use
+    storage_key: Arc<Mutex<Option<crypto::Key>>>,
+}
+
+/// Held while an item's files are written, released when dropped
+struct DownloadClaim {
+    claims: Arc<Mutex<HashSet<String>>>,
+    id: String,
 }
 
+impl Drop for DownloadClaim {
+    fn drop(&mut self) {
+        self.claims.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Claims `id` for writing its files, `None` while a save, sync or import of it is running.
+/// Taken inside `mutate_metadata` along with the catalog check, so two writers can't both find the id missing
+/// and write the same blob.
+fn claim_download(download_manager: &DownloadManager, id: &str) -> Option<DownloadClaim> {
+    let claims = download_manager.download_claims.clone();
+    if !claims.lock().unwrap().insert(id.to_string()) {
+        return None;
+    }
+    Some(DownloadClaim { claims, id: id.to_string() })
+}
+
+/// Whether the player or the stream server may have one of the item's videos open, those aren't rewritten
+fn item_in_use(download_manager: &DownloadManager, id: &str) -> bool {
+    download_manager.playing.lock().unwrap().contains(id) || download_manager.serving.lock().unwrap().contains_key(id)
+}
+
+fn now_millis() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+/// The directory is created once by `init_storage`, and again by `storage_clear_all`
 fn get_storage_dir(app: &AppHandle) -> tauri::Result<PathBuf> {
     let app_data_dir = app.path().app_data_dir()?;
-    let storage_dir = app_data_dir.join("offline_storage");
-    fs::create_dir_all(&storage_dir)?;
-    Ok(storage_dir)
+    Ok(app_data_dir.join("offline_storage"))
+}
+
+/// Runs blocking file work on the blocking thread pool instead of an async runtime worker
+async fn run_blocking<T, F>(f: F) -> tauri::Result<T>
+where
+    F: FnOnce() -> tauri::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tauri::async_runtime::spawn_blocking(f).await?
+}
+
+/// Creates the storage directory and loads the catalog and playback store in the background, filling in
+/// `has_thumbnail` for items stored before the catalog recorded it
+pub fn init_storage(app: &AppHandle) -> tauri::Result<()> {
+    fs::create_dir_all(get_storage_dir(app)?)?;
+
+    let app = app.clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        // Otherwise the first listing reads it, it patches every item with the offline progress
+        if let Err(e) = playback::preload_playback_store(&app) {
+            println!("init_storage: Failed to load the playback store: {}", e);
+        }
+
+        let result = (|| -> tauri::Result<()> {
+            let storage_dir = get_storage_dir(&app)?;
+            let download_manager = app.state::<DownloadManager>();
+            let _write = download_manager.metadata_write_lock.lock().unwrap();
+            let mut cache = download_manager.cached_metadata.lock().unwrap();
+            if cache.is_none() {
+                *cache = Some(load_metadata(&app)?);
+            }
+            let Some(metadata) = cache.as_mut() else {
+                return Ok(());
+            };
+
+            // Only changes anything on the first start after upgrading, later catalogs have every flag set
+            if fill_thumbnail_flags(&storage_dir, metadata) {
+                save_metadata(&app, metadata)?;
+            }
+            Ok(())
+        })();
+
+        if let Err(e) = result {
+            println!("init_storage: Failed to load the catalog: {}", e);
+        }
+    });
+
+    Ok(())
+}
+
+/// Sets `has_thumbnail` on items that don't have it yet, returns whether any changed
+fn fill_thumbnail_flags(storage_dir: &Path, metadata: &mut StorageMetadata) -> bool {
+    let mut changed = false;
+    for (id, track) in metadata.tracks.iter_mut().filter(|(_, track)| track.has_thumbnail.is_none()) {
+        track.has_thumbnail = Some(storage_dir.join(format!("{}.thumb", id)).exists());
+        changed = true;
+    }
+    changed
 }
 
 #[tauri::command]
@@ -54,71 +264,154 @@ fn get_metadata_path(app: &AppHandle) -> tauri::Result<PathBuf> {
 }
 
 fn load_metadata(app: &AppHandle) -> tauri::Result<StorageMetadata> {
-    let metadata_path = get_metadata_path(app)?;
-    
-    if !metadata_path.exists() {
-        return Ok(StorageMetadata {
-            tracks: HashMap::new(),
-        });
-    }
-    
-    let content = fs::read_to_string(&metadata_path)?;
-    let metadata: StorageMetadata = serde_json::from_str(&content)
-        .map_err(|e| tauri::Error::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, e)))?;
-    
-    Ok(metadata)
+    Ok(catalog::load(&get_metadata_path(app)?, crypto::get_key(app)?.as_ref())?)
 }
 
 fn save_metadata(app: &AppHandle, metadata: &StorageMetadata) -> tauri::Result<()> {
-    let metadata_path = get_metadata_path(app)?;
-    let content = serde_json::to_string(metadata)
-        .map_err(|e| tauri::Error::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, e)))?;
-    fs::write(&metadata_path, content)?;
-    Ok(())
+    let key = crypto::encryption_key(app)?;
+    Ok(catalog::save(&get_metadata_path(app)?, metadata, key.as_ref())?)
+}
+
+/// Reads the catalog for a cold cache on the blocking pool, it's file I/O and maybe a keychain lookup.
+/// The cache lock isn't held meanwhile, callers insert the result unless the cache was filled in the meantime.
+async fn load_metadata_blocking(app: &AppHandle) -> tauri::Result<StorageMetadata> {
+    let app = app.clone();
+    run_blocking(move || {
+        let download_manager = app.state::<DownloadManager>();
+        // Keeps a write in progress from being read half done
+        let _write = download_manager.metadata_write_lock.lock().unwrap();
+        load_metadata(&app)
+    })
+    .await
 }
 
-fn get_cached_metadata(
+async fn get_cached_metadata(
     app: &AppHandle,
-    download_manager: &State<DownloadManager>,
+    download_manager: &State<'_, DownloadManager>,
 ) -> tauri::Result<StorageMetadata> {
-    let mut cache = download_manager.cached_metadata.lock().unwrap();
-    
-    if cache.is_none() {
-        // Load from disk if not cached
-        *cache = Some(load_metadata(app)?);
+    if let Some(metadata) = download_manager.cached_metadata.lock().unwrap().as_ref() {
+        return Ok(metadata.clone());
     }
-    
-    Ok(cache.as_ref().unwrap().clone())
+
+    let metadata = load_metadata_blocking(app).await?;
+    Ok(download_manager.cached_metadata.lock().unwrap().get_or_insert(metadata).clone())
 }
 
-fn update_cached_metadata(
+/// Applies `f` to the cached catalog, tells the frontend what changed and writes the catalog on the blocking pool.
+/// `f` runs under the cache lock so changes can't overwrite each other, file work goes before or after it.
+async fn mutate_metadata<T>(
     app: &AppHandle,
-    download_manager: &State<DownloadManager>,
-    metadata: &StorageMetadata,
-) -> tauri::Result<()> {
-    // Save to disk
-    save_metadata(app, metadata)?;
-    
-    // Update cache
-    let mut cache = download_manager.cached_metadata.lock().unwrap();
-    *cache = Some(metadata.clone());
-    
-    Ok(())
+    download_manager: &State<'_, DownloadManager>,
+    f: impl FnOnce(&mut StorageMetadata) -> T,
+) -> tauri::Result<T> {
+    let (previous, current, result) = loop {
+        {
+            let mut cache = download_manager.cached_metadata.lock().unwrap();
+            if let Some(metadata) = cache.as_mut() {
+                let previous = metadata.clone();
+                let result = f(metadata);
+
+                if *metadata == previous {
+                    return Ok(result);
+                }
+                break (previous, metadata.clone(), result);
+            }
+        }
+        let metadata = load_metadata_blocking(app).await?;
+        download_manager.cached_metadata.lock().unwrap().get_or_insert(metadata);
+    };
+
+    // The search index is rebuilt from the cache on the next search
+    *download_manager.cached_search_index.lock().unwrap() = None;
+    // Without it the listing falls back to the snapshot's progress, which is stale once watched offline
+    let user_data = playback::get_user_data_snapshot(app, download_manager).await.unwrap_or_default();
+    events::emit_changes(app, Some(&previous), &current, &user_data);
+
+    write_cached_metadata(app).await?;
+    Ok(result)
+}
+
+/// Writes the cached catalog on the blocking pool. Writes are serialised and each one saves the catalog as cached
+/// at that point, so the file never ends up older than the cache.
+async fn write_cached_metadata(app: &AppHandle) -> tauri::Result<()> {
+    let app = app.clone();
+    run_blocking(move || {
+        let download_manager = app.state::<DownloadManager>();
+        let key = crypto::encryption_key(&app)?;
+        let _write = download_manager.metadata_write_lock.lock().unwrap();
+        let content = match download_manager.cached_metadata.lock().unwrap().as_ref() {
+            Some(metadata) => catalog::encode(metadata, key.as_ref())?,
+            // Cleared in the meantime
+            None => return Ok(()),
+        };
+        fs::write(get_metadata_path(&app)?, content)?;
+        Ok(())
+    })
+    .await
 }
 
 fn invalidate_cache(download_manager: &State<DownloadManager>) {
-    let mut cache = download_manager.cached_metadata.lock().unwrap();
-    *cache = None;
+    *download_manager.cached_metadata.lock().unwrap() = None;
+    *download_manager.cached_playback.lock().unwrap() = None;
+    *download_manager.cached_search_index.lock().unwrap() = None;
 }
 
+/// Attempts of a download whose connection keeps dropping
+const DOWNLOAD_ATTEMPTS: u32 = 3;
+
+/// Downloads the video of `storage_save_track`, retrying when the connection drops
+async fn download_video(
+    app: &AppHandle,
+    client: &reqwest::Client,
+    url: &str,
+    path: &Path,
+    cancel_token: &CancellationToken,
+    meter: &mut progress::ProgressMeter,
+    channel: Option<&Channel<progress::DownloadProgress>>,
+) -> Result<u64, String> {
+    let key = crypto::encryption_key(app).map_err(|e| e.to_string())?;
+    
+    loop {
+        progress::report(app, channel, meter.report(progress::DownloadState::Connecting, None));
+        
+        let result = download::download_to_file(client, url, path, key.as_ref(), cancel_token, |downloaded, total_size| {
+            if let Some(sample) = meter.sample(downloaded, total_size, Instant::now()) {
+                progress::report(app, channel, sample);
+            }
+        })
+        .await;
+        
+        match result {
+            Ok(downloaded) => return Ok(downloaded),
+            Err(e) if e.is_retryable() && meter.attempt() < DOWNLOAD_ATTEMPTS => {
+                println!("download_video: Attempt {} failed, retrying: {}", meter.attempt(), e);
+                progress::report(app, channel, meter.report(progress::DownloadState::Retrying, Some(e.to_string())));
+                
+                let backoff = Duration::from_secs(2u64.pow(meter.attempt()));
+                tokio::select! {
+                    _ = cancel_token.cancelled() => return Err(download::DownloadError::Cancelled.into()),
+                    _ = tokio::time::sleep(backoff) => {}
+                }
+                meter.retry(Instant::now());
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Progress is streamed through `on_progress`, `queued` is the number of downloads waiting behind this one
+/// for the queue summary every window gets.
 #[tauri::command]
+#[allow(clippy::too_many_arguments)]
 pub async fn storage_save_track(
     app: AppHandle,
     download_manager: State<'_, DownloadManager>,
     id: String,
-    data: StorageTrack,
+    mut data: StorageTrack,
     video_url: Option<String>,
     thumbnail_url: Option<String>,
+    queued: Option<usize>,
+    on_progress: Option<Channel<progress::DownloadProgress>>,
 ) -> Result<(), String> {
     println!("storage_save_track: Starting to save track with id: {}", id);
     
@@ -133,145 +426,109 @@ pub async fn storage_save_track(
     }
     
     let storage_dir = get_storage_dir(&app).map_err(|e| e.to_string())?;
-    
-    // Create cancellation token for this download
     let cancel_token = CancellationToken::new();
-    {
-        let mut token = download_manager.cancellation_token.lock().unwrap();
-        *token = Some(cancel_token.clone());
-    }
-    
-    // Download video blob if URL is provided
-    if let Some(url) = video_url {
-        println!("storage_save_track: Downloading video from URL for id: {}", id);
+    let mut meter = progress::ProgressMeter::new(&id, 1, Instant::now());
+    progress::start(&app, &id, queued.unwrap_or(0));
+    
+    let result = async {
+        // Already stored, e.g. through another container: share the blob instead of downloading it again.
+        // A different media source of a stored item is kept next to it as another version.
+        let (plan, _claim) = mutate_metadata(&app, &download_manager, |metadata| {
+            let plan = catalog::plan_save(metadata, &id, &data, video_url.is_some())?;
+            let claim = match plan {
+                catalog::SavePlan::Referenced => None,
+                _ => Some(claim_download(&download_manager, &id).ok_or(format!("{} is already being downloaded", id))?),
+            };
+            Ok::<_, String>((plan, claim))
+        })
+        .await
+        .map_err(|e| e.to_string())??;
+        let new_version = match plan {
+            catalog::SavePlan::Referenced => {
+                println!("storage_save_track: Added reference to existing track with id: {}", id);
+                return Ok(());
+            }
+            catalog::SavePlan::NewVersion(source) => Some(source),
+            catalog::SavePlan::Download => None,
+        };
+        
+        // Register the cancellation token for this download
+        *download_manager.cancellation_token.lock().unwrap() = Some(cancel_token.clone());
+        
         let client = reqwest::Client::new();
-        let response = client.get(&url).send().await.map_err(|e| e.to_string())?;
         
-        if !response.status().is_success() {
-            let err_msg = format!("Failed to download video: HTTP {}", response.status());
-            println!("storage_save_track: Error - {}", err_msg);
-            // Cleanup token on error
-            *download_manager.cancellation_token.lock().unwrap() = None;
-            return Err(err_msg);
+        // Download video blob if URL is provided
+        if let Some(url) = video_url {
+            println!("storage_save_track: Downloading video from URL for id: {}", id);
+            let blob_path = match &new_version {
+                Some(source) => versions::version_path(&storage_dir, &id, source).map_err(|e| e.to_string())?,
+                None => storage_dir.join(format!("{}.blob", id)),
+            };
+            
+            let downloaded = download_video(&app, &client, &url, &blob_path, &cancel_token, &mut meter, on_progress.as_ref())
+                .await
+                .inspect_err(|e| println!("storage_save_track: Error for id {} - {}", id, e))?;
+            
+            println!("storage_save_track: Video saved successfully ({} bytes) for id: {}", downloaded, id);
+            data.size = Some(downloaded);
+            progress::report(&app, on_progress.as_ref(), meter.report(progress::DownloadState::Verifying, None));
         }
         
-        let total_size = response.content_length().unwrap_or(0);
-        let blob_path = storage_dir.join(format!("{}.blob", id));
+        data.has_thumbnail = Some(false);
         
-        // Create file for writing
-        let mut file = tokio::fs::File::create(&blob_path).await.map_err(|e| {
-            *download_manager.cancellation_token.lock().unwrap() = None;
-            e.to_string()
-        })?;
-        let mut stream = response.bytes_stream();
-        let mut downloaded: u64 = 0;
-        let mut last_emit_time = std::time::Instant::now();
-        let mut last_emit_downloaded: u64 = 0;
-        
-        // Download in chunks and emit progress
-        loop {
-            tokio::select! {
-                _ = cancel_token.cancelled() => {
-                    println!("storage_save_track: Download cancelled for id: {}", id);
-                    // Remove partial file
-                    let _ = tokio::fs::remove_file(&blob_path).await;
-                    *download_manager.cancellation_token.lock().unwrap() = None;
-                    return Err("Download cancelled".to_string());
-                }
-                chunk_result = stream.next() => {
-                    match chunk_result {
-                        Some(Ok(chunk)) => {
-                            file.write_all(&chunk).await.map_err(|e| {
-                                *download_manager.cancellation_token.lock().unwrap() = None;
-                                e.to_string()
-                            })?;
-                            downloaded += chunk.len() as u64;
-                            
-                            let now = std::time::Instant::now();
-                            let elapsed = now.duration_since(last_emit_time).as_secs_f64();
-                            
-                            // Emit progress event every 0.5 seconds (debounced)
-                            if elapsed >= 0.5 {
-                                let progress = if total_size > 0 {
-                                    (downloaded as f64 / total_size as f64 * 100.0) as u32
-                                } else {
-                                    0
-                                };
-                                
-                                // Calculate speed (bytes per second)
-                                let bytes_since_last = downloaded - last_emit_downloaded;
-                                let speed = if elapsed > 0.0 {
-                                    bytes_since_last as f64 / elapsed
-                                } else {
-                                    0.0
-                                };
-                                
-                                // Calculate time remaining (seconds)
-                                let remaining_bytes = total_size.saturating_sub(downloaded);
-                                let time_remaining = if speed > 0.0 {
-                                    remaining_bytes as f64 / speed
-                                } else {
-                                    0.0
-                                };
-                                
-                                let _ = app.emit("download-progress", serde_json::json!({
-                                    "id": id,
-                                    "downloaded": downloaded,
-                                    "total": total_size,
-                                    "progress": progress,
-                                    "speed": speed,
-                                    "timeRemaining": time_remaining
-                                }));
-                                
-                                last_emit_time = now;
-                                last_emit_downloaded = downloaded;
-                            }
-                        }
-                        Some(Err(e)) => {
-                            *download_manager.cancellation_token.lock().unwrap() = None;
-                            return Err(e.to_string());
-                        }
-                        None => break,
-                    }
-                }
+        // Download thumbnail if URL is provided
+        if let Some(url) = thumbnail_url {
+            println!("storage_save_track: Downloading thumbnail from URL for id: {}", id);
+            let response = client.get(&url).send().await.map_err(|e| e.to_string())?;
+            
+            if response.status().is_success() {
+                let thumbnail_data = response.bytes().await.map_err(|e| e.to_string())?;
+                let thumbnail_size = thumbnail_data.len();
+                let thumbnail_path = storage_dir.join(format!("{}.thumb", id));
+                let write_app = app.clone();
+                run_blocking(move || crypto::write_file(&write_app, &thumbnail_path, &thumbnail_data))
+                    .await
+                    .map_err(|e| e.to_string())?;
+                data.has_thumbnail = Some(true);
+                println!("storage_save_track: Thumbnail saved successfully ({} bytes) for id: {}", thumbnail_size, id);
+            } else {
+                println!("storage_save_track: Thumbnail download failed with status: {}", response.status());
             }
         }
         
-        file.flush().await.map_err(|e| {
-            *download_manager.cancellation_token.lock().unwrap() = None;
-            e.to_string()
-        })?;
-        println!("storage_save_track: Video saved successfully ({} bytes) for id: {}", downloaded, id);
-    }
-    
-    // Download thumbnail if URL is provided
-    if let Some(url) = thumbnail_url {
-        println!("storage_save_track: Downloading thumbnail from URL for id: {}", id);
-        let client = reqwest::Client::new();
-        let response = client.get(&url).send().await.map_err(|e| e.to_string())?;
-        
-        if response.status().is_success() {
-            let thumbnail_data = response.bytes().await.map_err(|e| e.to_string())?;
-            let thumbnail_size = thumbnail_data.len();
-            let thumbnail_path = storage_dir.join(format!("{}.thumb", id));
-            fs::write(thumbnail_path, thumbnail_data).map_err(|e| e.to_string())?;
-            println!("storage_save_track: Thumbnail saved successfully ({} bytes) for id: {}", thumbnail_size, id);
-        } else {
-            println!("storage_save_track: Thumbnail download failed with status: {}", response.status());
+        // Update metadata
+        println!("storage_save_track: Updating metadata for id: {}", id);
+        let promoted = mutate_metadata(&app, &download_manager, |metadata| {
+            catalog::finish_save(metadata, &id, data, new_version.clone())
+        })
+        .await
+        .map_err(|e| e.to_string())?;
+
+        // Removed while we were downloading, the new version became the only one
+        if let (true, Some(source)) = (promoted, &new_version) {
+            let version_path = versions::version_path(&storage_dir, &id, source).map_err(|e| e.to_string())?;
+            tokio::fs::rename(version_path, storage_dir.join(format!("{}.blob", id)))
+                .await
+                .map_err(|e| e.to_string())?;
         }
+        println!("storage_save_track: Track saved successfully with id: {}", id);
+        
+        Ok(())
     }
+    .await;
     
-    // Update metadata
-    println!("storage_save_track: Updating metadata for id: {}", id);
-    let mut metadata = get_cached_metadata(&app, &download_manager).map_err(|e| e.to_string())?;
-    metadata.tracks.insert(id.clone(), data);
-    update_cached_metadata(&app, &download_manager, &metadata).map_err(|e| e.to_string())?;
-    println!("storage_save_track: Track saved successfully with id: {}", id);
-    
-    // Remove cancellation token on success
+    // Remove cancellation token, also on error so the next download can start
     *download_manager.cancellation_token.lock().unwrap() = None;
     
-    Ok(())
+    // Partial downloads are removed, only finished ones count towards the queue's bytes
+    let (state, downloaded) = match &result {
+        Ok(()) => (progress::DownloadState::Done, meter.downloaded()),
+        Err(_) => (progress::DownloadState::Failed, 0),
+    };
+    progress::report(&app, on_progress.as_ref(), meter.report(state, result.as_ref().err().cloned()));
+    progress::finish(&app, (!cancel_token.is_cancelled()).then_some(state), downloaded);
+    
+    result
 }
 
 #[tauri::command]
@@ -280,84 +537,82 @@ pub async fn storage_get_track(
     download_manager: State<'_, DownloadManager>,
     id: String,
 ) -> Result<Option<StorageTrack>, String> {
-    let metadata = get_cached_metadata(&app, &download_manager).map_err(|e| e.to_string())?;
-    Ok(metadata.tracks.get(&id).cloned())
+    let metadata = get_cached_metadata(&app, &download_manager).await.map_err(|e| e.to_string())?;
+    let user_data = playback::get_user_data_snapshot(&app, &download_manager).await.map_err(|e| e.to_string())?;
+
+    Ok(metadata.tracks.get(&id).cloned().map(|mut track| {
+        if let Some(user_data) = user_data.get(&id) {
+            playback::apply_user_data(&mut track.media_item, user_data);
+        }
+        track
+    }))
 }
 
+/// With `media_source_id` only true when that version of the item is stored
 #[tauri::command]
 pub async fn storage_has_track(
     app: AppHandle,
     download_manager: State<'_, DownloadManager>,
     id: String,
+    media_source_id: Option<String>,
 ) -> Result<bool, String> {
-    let metadata = get_cached_metadata(&app, &download_manager).map_err(|e| e.to_string())?;
-    Ok(metadata.tracks.contains_key(&id))
+    let metadata = get_cached_metadata(&app, &download_manager).await.map_err(|e| e.to_string())?;
+    Ok(metadata.tracks.get(&id).is_some_and(|track| match media_source_id.as_deref() {
+        Some(source) => versions::has_version(&id, track, source),
+        None => true,
+    }))
 }
 
+/// Drops the reference `container_id` holds on the track, or its direct download when `None`.
+/// Files are only deleted once nothing references the track anymore, removing a container releases
+/// everything below it the same way. An item that was only downloaded through containers is removed
+/// from all of them when it's removed on its own.
 #[tauri::command]
 pub async fn storage_remove_track(
     app: AppHandle,
     download_manager: State<'_, DownloadManager>,
     id: String,
+    container_id: Option<String>,
 ) -> Result<(), String> {
     let storage_dir = get_storage_dir(&app).map_err(|e| e.to_string())?;
-    
-    // Remove blob file
-    let blob_path = storage_dir.join(format!("{}.blob", id));
-    if blob_path.exists() {
-        fs::remove_file(blob_path).map_err(|e| e.to_string())?;
-    }
-    
-    // Remove thumbnail file
-    let thumbnail_path = storage_dir.join(format!("{}.thumb", id));
-    if thumbnail_path.exists() {
-        fs::remove_file(thumbnail_path).map_err(|e| e.to_string())?;
-    }
-    
-    // Update metadata
-    let mut metadata = get_cached_metadata(&app, &download_manager).map_err(|e| e.to_string())?;
-    
-    // If this is a container, also remove all children
-    if let Some(track) = metadata.tracks.get(&id) {
-        if track.track_type == "container" {
-            let children: Vec<String> = metadata
-                .tracks
-                .iter()
-                .filter(|(_, t)| t.container_id.as_ref() == Some(&id))
-                .map(|(k, _)| k.clone())
-                .collect();
-            
-            for child_id in children {
-                // Remove child files
-                let child_blob_path = storage_dir.join(format!("{}.blob", child_id));
-                if child_blob_path.exists() {
-                    fs::remove_file(child_blob_path).map_err(|e| e.to_string())?;
-                }
-                let child_thumb_path = storage_dir.join(format!("{}.thumb", child_id));
-                if child_thumb_path.exists() {
-                    fs::remove_file(child_thumb_path).map_err(|e| e.to_string())?;
-                }
-                metadata.tracks.remove(&child_id);
-            }
-        }
-    }
-    
-    metadata.tracks.remove(&id);
-    update_cached_metadata(&app, &download_manager, &metadata).map_err(|e| e.to_string())?;
-    
-    Ok(())
+
+    // Out of the catalog first, nothing can pick up the files while they're deleted
+    let removed = mutate_metadata(&app, &download_manager, |metadata| {
+        catalog::remove(metadata, &id, container_id.as_deref())
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+    
+    run_blocking(move || Ok(catalog::remove_track_files(&storage_dir, &removed)?))
+        .await
+        .map_err(|e| e.to_string())
 }
 
+/// Path of the requested version, or of the preferred one when `media_source_id` is `None`.
+/// `None` for encrypted files, those have to be played through the stream server.
 #[tauri::command]
-pub async fn storage_get_file_path(app: AppHandle, id: String) -> Result<Option<String>, String> {
+pub async fn storage_get_file_path(
+    app: AppHandle,
+    download_manager: State<'_, DownloadManager>,
+    id: String,
+    media_source_id: Option<String>,
+) -> Result<Option<String>, String> {
     let storage_dir = get_storage_dir(&app).map_err(|e| e.to_string())?;
-    let blob_path = storage_dir.join(format!("{}.blob", id));
-    
-    if !blob_path.exists() {
-        return Ok(None);
-    }
-    
-    Ok(Some(blob_path.to_string_lossy().to_string()))
+    let metadata = get_cached_metadata(&app, &download_manager).await.map_err(|e| e.to_string())?;
+    
+    let blob_path = match metadata.tracks.get(&id) {
+        Some(track) => versions::blob_path(&storage_dir, &id, track, media_source_id.as_deref()),
+        None => Some(storage_dir.join(format!("{}.blob", id))),
+    };
+    
+    // Encrypted files can only be played through `storage_get_stream_url`
+    run_blocking(move || {
+        Ok(blob_path
+            .filter(|path| path.exists() && !crypto::is_encrypted_file(path))
+            .map(|path| path.to_string_lossy().to_string()))
+    })
+    .await
+    .map_err(|e| e.to_string())
 }
 
 #[tauri::command]
@@ -365,12 +620,14 @@ pub async fn storage_get_thumbnail(app: AppHandle, id: String) -> Result<Option<
     let storage_dir = get_storage_dir(&app).map_err(|e| e.to_string())?;
     let thumbnail_path = storage_dir.join(format!("{}.thumb", id));
     
-    if !thumbnail_path.exists() {
-        return Ok(None);
-    }
-    
-    let data = fs::read(thumbnail_path).map_err(|e| e.to_string())?;
-    Ok(Some(data))
+    run_blocking(move || {
+        if !thumbnail_path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(crypto::read_file(&app, &thumbnail_path)?))
+    })
+    .await
+    .map_err(|e| e.to_string())
 }
 
 #[tauri::command]
@@ -378,20 +635,11 @@ pub async fn storage_get_track_count(
     app: AppHandle,
     download_manager: State<'_, DownloadManager>,
     kind: String,
+    filter: Option<query::ItemFilter>,
 ) -> Result<usize, String> {
-    let metadata = get_cached_metadata(&app, &download_manager).map_err(|e| e.to_string())?;
-    let count = metadata
-        .tracks
-        .values()
-        .filter(|track| {
-            if let Some(media_item) = track.media_item.as_object() {
-                if let Some(item_type) = media_item.get("Type") {
-                    return item_type.as_str() == Some(&kind);
-                }
-            }
-            false
-        })
-        .count();
+    let metadata = get_cached_metadata(&app, &download_manager).await.map_err(|e| e.to_string())?;
+    let user_data = playback::get_user_data_snapshot(&app, &download_manager).await.map_err(|e| e.to_string())?;
+    let count = query::filter_tracks(&metadata, &kind, &filter.unwrap_or_default(), &user_data).len();
     Ok(count)
 }
 
@@ -402,14 +650,28 @@ pub async fn storage_clear_all(
 ) -> Result<(), String> {
     let storage_dir = get_storage_dir(&app).map_err(|e| e.to_string())?;
     
-    // Remove all files in storage directory
-    if storage_dir.exists() {
-        fs::remove_dir_all(&storage_dir).map_err(|e| e.to_string())?;
-        fs::create_dir_all(&storage_dir).map_err(|e| e.to_string())?;
+    // Stop background upgrades and syncs, their files are about to disappear
+    if let Some(cancel_token) = download_manager.upgrade_cancellation_token.lock().unwrap().take() {
+        cancel_token.cancel();
+    }
+    if let Some(cancel_token) = download_manager.sync_cancellation_token.lock().unwrap().take() {
+        cancel_token.cancel();
     }
     
+    // Remove all files in storage directory
+    run_blocking(move || {
+        if storage_dir.exists() {
+            fs::remove_dir_all(&storage_dir)?;
+        }
+        fs::create_dir_all(&storage_dir)?;
+        Ok(())
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+    
     // Invalidate cache
     invalidate_cache(&download_manager);
+    events::emit_cleared(&app);
     
     Ok(())
 }
@@ -418,58 +680,25 @@ pub async fn storage_clear_all(
 pub async fn storage_get_page(
     app: AppHandle,
     download_manager: State<'_, DownloadManager>,
-    page_index: usize,
     item_kind: String,
     items_per_page: usize,
-) -> Result<Vec<serde_json::Value>, String> {
-    let metadata = get_cached_metadata(&app, &download_manager).map_err(|e| e.to_string())?;
-    
-    // Filter and sort by timestamp (descending)
-    let mut filtered: Vec<_> = metadata
-        .tracks
-        .iter()
-        .filter(|(_, track)| {
-            if let Some(media_item) = track.media_item.as_object() {
-                if let Some(item_type) = media_item.get("Type") {
-                    return item_type.as_str() == Some(&item_kind);
-                }
-            }
-            false
-        })
-        .collect();
-    
-    filtered.sort_by(|a, b| b.1.timestamp.cmp(&a.1.timestamp));
-    
-    let start = page_index * items_per_page;
-    
-    let page_items: Vec<serde_json::Value> = filtered
-        .iter()
-        .skip(start)
-        .take(items_per_page)
-        .map(|(id, track)| {
-            let mut media_item = track.media_item.clone();
-            
-            // Add media sources if present
-            if let Some(media_sources) = &track.media_sources {
-                if let Some(obj) = media_item.as_object_mut() {
-                    obj.insert("MediaSources".to_string(), media_sources.clone());
-                }
-            }
-            
-            // Mark that thumbnail is available (will be loaded separately)
-            let storage_dir = get_storage_dir(&app).unwrap_or_default();
-            let thumbnail_path = storage_dir.join(format!("{}.thumb", id));
-            if thumbnail_path.exists() {
-                if let Some(obj) = media_item.as_object_mut() {
-                    obj.insert("hasThumbnail".to_string(), serde_json::Value::Bool(true));
-                }
-            }
-            
-            media_item
-        })
-        .collect();
-    
-    Ok(page_items)
+    cursor: Option<String>,
+    sort: Option<query::Sort>,
+    filter: Option<query::ItemFilter>,
+) -> Result<query::Page, String> {
+    let metadata = get_cached_metadata(&app, &download_manager).await.map_err(|e| e.to_string())?;
+    let user_data = playback::get_user_data_snapshot(&app, &download_manager).await.map_err(|e| e.to_string())?;
+    
+    // Filter and sort, newest downloads first unless asked otherwise
+    catalog::page(
+        &metadata,
+        &user_data,
+        &item_kind,
+        items_per_page,
+        cursor.as_deref(),
+        sort.unwrap_or_default(),
+        &filter.unwrap_or_default(),
+    )
 }
 
 #[tauri::command]
@@ -479,74 +708,33 @@ pub async fn storage_search_items(
     search_term: String,
     limit: usize,
 ) -> Result<Vec<serde_json::Value>, String> {
-    let metadata = get_cached_metadata(&app, &download_manager).map_err(|e| e.to_string())?;
-    let search_lower = search_term.to_lowercase();
-    
     if search_term.trim().is_empty() {
         return Ok(vec![]);
     }
     
-    let results: Vec<serde_json::Value> = metadata
-        .tracks
-        .iter()
-        .filter(|(_, track)| {
-            if let Some(media_item) = track.media_item.as_object() {
-                if let Some(name) = media_item.get("Name").and_then(|n| n.as_str()) {
-                    return name.to_lowercase().contains(&search_lower);
-                }
-            }
-            false
-        })
-        .take(limit)
-        .map(|(id, track)| {
-            let mut media_item = track.media_item.clone();
-            
-            if let Some(media_sources) = &track.media_sources {
-                if let Some(obj) = media_item.as_object_mut() {
-                    obj.insert("MediaSources".to_string(), media_sources.clone());
-                }
-            }
-            
-            let storage_dir = get_storage_dir(&app).unwrap_or_default();
-            let thumbnail_path = storage_dir.join(format!("{}.thumb", id));
-            if thumbnail_path.exists() {
-                if let Some(obj) = media_item.as_object_mut() {
-                    obj.insert("hasThumbnail".to_string(), serde_json::Value::Bool(true));
-                }
-            }
-            
-            media_item
-        })
-        .collect();
+    // Best matches first
+    let matches = search::search(&app, &download_manager, &search_term, limit).await.map_err(|e| e.to_string())?;
+    let metadata = get_cached_metadata(&app, &download_manager).await.map_err(|e| e.to_string())?;
+    let user_data = playback::get_user_data_snapshot(&app, &download_manager).await.map_err(|e| e.to_string())?;
     
-    Ok(results)
+    Ok(catalog::media_items(&metadata, &user_data, matches.iter().map(|(id, _)| id.as_str())))
 }
 
 #[tauri::command]
 pub async fn storage_get_stats(
     app: AppHandle,
     download_manager: State<'_, DownloadManager>,
-) -> Result<serde_json::Value, String> {
+    largest_count: Option<usize>,
+) -> Result<stats::StorageStats, String> {
     let storage_dir = get_storage_dir(&app).map_err(|e| e.to_string())?;
-    let metadata = get_cached_metadata(&app, &download_manager).map_err(|e| e.to_string())?;
-    
-    let mut total_size: u64 = 0;
-    
-    // Calculate total size of all files
-    if let Ok(entries) = fs::read_dir(&storage_dir) {
-        for entry in entries.flatten() {
-            if let Ok(metadata) = entry.metadata() {
-                total_size += metadata.len();
-            }
-        }
-    }
-    
-    let track_count = metadata.tracks.len();
-    
-    Ok(serde_json::json!({
-        "usage": total_size,
-        "trackCount": track_count
-    }))
+    let metadata = get_cached_metadata(&app, &download_manager).await.map_err(|e| e.to_string())?;
+    let settings = settings::get_settings(&app, &download_manager).map_err(|e| e.to_string())?;
+    
+    run_blocking(move || {
+        Ok(stats::collect_stats(&storage_dir, &metadata, settings.storage_quota_bytes, largest_count.unwrap_or(10))?)
+    })
+    .await
+    .map_err(|e| e.to_string())
 }
 
 #[tauri::command]
@@ -558,5 +746,11 @@ pub async fn storage_abort_downloads(
         cancel_token.cancel();
         println!("storage_abort_downloads: Cancelled download");
     }
+
+    // Subscriptions download through their own token, stop those too
+    if let Some(cancel_token) = download_manager.sync_cancellation_token.lock().unwrap().take() {
+        cancel_token.cancel();
+        println!("storage_abort_downloads: Cancelled sync");
+    }
     Ok(())
 }
@@ -0,0 +1,613 @@
+use super::catalog::{merge_track, remove_track_files};
+use super::crypto::{self, BlobReader, Key};
+use super::download::download_to_file;
+use super::export::{external_subtitles, subtitle_path};
+use super::jellyfin::JellyfinClient;
+use super::playback::{get_store_snapshot, update_playback_store, PlaybackStore};
+use super::stats::SUBTITLE_EXTENSIONS;
+use super::versions::{preferred_source_id, version_path, StorageVersion};
+use super::{
+    claim_download, get_cached_metadata, get_storage_dir, mutate_metadata, now_millis, run_blocking, DownloadManager,
+    StorageMetadata, StorageTrack,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{BufReader, BufWriter, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tauri::{AppHandle, Manager, State};
+use tokio_util::sync::CancellationToken;
+
+const ARCHIVE_FORMAT: &str = "jelly-video-app/offline-library";
+/// Bumped whenever the archive layout changes incompatibly, newer archives are refused
+const ARCHIVE_VERSION: u32 = 1;
+
+/// First entry of every archive, followed by `metadata.json`, `playback.json` and the item files under `files/`:
+/// videos, thumbnails and sidecar subtitles
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ArchiveManifest {
+    pub format: String,
+    pub version: u32,
+    pub created_at: i64,
+    pub includes_blobs: bool,
+    pub track_count: usize,
+}
+
+#[derive(Debug, Serialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct RestoreReport {
+    /// Items that weren't in the library before
+    pub added: usize,
+    /// Items that were already stored, their references and versions were merged
+    pub merged: usize,
+    /// Items the archive doesn't contain the video of. They're downloaded again in the background and added once
+    /// their video is back, without a server connection they're left out.
+    pub missing_blobs: Vec<String>,
+}
+
+/// Writes the whole library into a single tar archive at `path`: catalog, playback state and user data,
+/// thumbnails, the stored sidecar subtitles plus the external subtitles missing locally when connected to the
+/// server, and with `include_blobs` the downloaded videos of every version.
+/// Encrypted files are archived decrypted so the archive can be restored on another machine.
+#[tauri::command]
+pub async fn storage_export_archive(
+    app: AppHandle,
+    download_manager: State<'_, DownloadManager>,
+    path: String,
+    include_blobs: bool,
+) -> Result<ArchiveManifest, String> {
+    let storage_dir = get_storage_dir(&app).map_err(|e| e.to_string())?;
+    let metadata = get_cached_metadata(&app, &download_manager).await.map_err(|e| e.to_string())?;
+    let store = get_store_snapshot(&app, &download_manager).await.map_err(|e| e.to_string())?;
+
+    let client = download_manager.jellyfin_auth.lock().unwrap().clone().map(JellyfinClient::new);
+    let subtitles = match &client {
+        Some(client) => fetch_subtitles(client, &metadata, &storage_dir).await,
+        None => Vec::new(),
+    };
+
+    let manifest = ArchiveManifest {
+        format: ARCHIVE_FORMAT.to_string(),
+        version: ARCHIVE_VERSION,
+        created_at: now_millis(),
+        includes_blobs: include_blobs,
+        track_count: metadata.tracks.len(),
+    };
+
+    let path = PathBuf::from(path);
+    let result = {
+        let app = app.clone();
+        let manifest = manifest.clone();
+        let path = path.clone();
+        run_blocking(move || {
+            // Reading the key can be a keychain call
+            let key = crypto::get_key(&app)?;
+            let contents = ArchiveContents {
+                manifest: &manifest,
+                metadata: &metadata,
+                store: &store,
+                subtitles: &subtitles,
+            };
+            Ok(write_archive(&path, &storage_dir, &contents, key.as_ref(), include_blobs)?)
+        })
+        .await
+    };
+
+    if let Err(e) = result {
+        let _ = fs::remove_file(&path);
+        return Err(e.to_string());
+    }
+
+    println!("storage_export_archive: Wrote {} tracks to {:?}", manifest.track_count, path);
+    Ok(manifest)
+}
+
+/// Restores an archive written by `storage_export_archive` into the current library.
+/// Items and files that already exist locally are kept, archived items only add their references, versions,
+/// subscriptions and newer user data.
+#[tauri::command]
+pub async fn storage_import_archive(
+    app: AppHandle,
+    download_manager: State<'_, DownloadManager>,
+    path: String,
+) -> Result<RestoreReport, String> {
+    let storage_dir = get_storage_dir(&app).map_err(|e| e.to_string())?;
+
+    // Archived videos of another source than the one stored here are restored as versions
+    let local_sources: Arc<HashMap<String, String>> = Arc::new(
+        get_cached_metadata(&app, &download_manager)
+            .await
+            .map_err(|e| e.to_string())?
+            .tracks
+            .iter()
+            .map(|(id, track)| (id.clone(), preferred_source_id(id, track).to_string()))
+            .collect(),
+    );
+
+    let (manifest, archived, archived_store) = {
+        let app = app.clone();
+        let storage_dir = storage_dir.clone();
+        let local_sources = local_sources.clone();
+        run_blocking(move || {
+            // Reading the key can be a keychain call
+            let key = crypto::encryption_key(&app)?;
+            Ok(read_archive(Path::new(&path), &storage_dir, &local_sources, key.as_ref())?)
+        })
+        .await
+        .map_err(|e| e.to_string())?
+    };
+
+    println!(
+        "storage_import_archive: Restoring {} tracks from archive created at {}",
+        manifest.track_count, manifest.created_at
+    );
+
+    // Checks which restored files made it to disk, away from the async runtime
+    let restored = run_blocking(move || Ok(check_restored(&storage_dir, archived.tracks, &local_sources)))
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let (report, missing) = mutate_metadata(&app, &download_manager, |metadata| {
+        for (id, subscription) in archived.subscriptions {
+            metadata.subscriptions.entry(id).or_insert(subscription);
+        }
+        merge_tracks(metadata, restored)
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+
+    update_playback_store(&app, &download_manager, |store| {
+        for (id, user_data) in archived_store.user_data {
+            match store.user_data.get(&id) {
+                Some(local) if local.last_played_date >= user_data.last_played_date => {}
+                _ => {
+                    store.user_data.insert(id, user_data);
+                }
+            }
+        }
+
+        // Events recorded on the other machine that never reached the server
+        for (id, events) in archived_store.pending {
+            let pending = store.pending.entry(id).or_default();
+            for event in events {
+                if !pending.iter().any(|local| local.timestamp == event.timestamp) {
+                    pending.push(event);
+                }
+            }
+            pending.sort_by_key(|event| event.timestamp);
+        }
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+
+    if !missing.is_empty() {
+        let app = app.clone();
+        tauri::async_runtime::spawn(async move { redownload_missing(&app, missing).await });
+    }
+
+    Ok(report)
+}
+
+/// Archived items whose video wasn't restored, with their id
+type MissingTracks = Vec<(String, StorageTrack)>;
+
+/// An archived track along with what the restore put on disk for it
+struct RestoredTrack {
+    track: StorageTrack,
+    has_blob: bool,
+}
+
+/// Archived preferred source of an item that's stored here with another one, its video is restored as a version
+fn diverted_source<'a>(id: &'a str, track: &'a StorageTrack, local_sources: &HashMap<String, String>) -> Option<&'a str> {
+    let source = preferred_source_id(id, track);
+    local_sources.get(id).filter(|local| local.as_str() != source).map(|_| source)
+}
+
+/// Drops archived versions whose blobs weren't restored and notes which items got their video and thumbnail.
+/// A preferred video restored as a version is added to the versions.
+fn check_restored(
+    storage_dir: &Path,
+    tracks: HashMap<String, StorageTrack>,
+    local_sources: &HashMap<String, String>,
+) -> HashMap<String, RestoredTrack> {
+    tracks
+        .into_iter()
+        .map(|(id, mut track)| {
+            track
+                .versions
+                .retain(|version| version_path(storage_dir, &id, &version.media_source_id).is_ok_and(|path| path.exists()));
+            if let Some(source) = diverted_source(&id, &track, local_sources).map(str::to_string) {
+                let restored = version_path(storage_dir, &id, &source).is_ok_and(|path| path.exists());
+                if restored && !track.versions.iter().any(|version| version.media_source_id == source) {
+                    track.versions.push(StorageVersion {
+                        media_source_id: source,
+                        timestamp: track.timestamp,
+                        bitrate: track.bitrate,
+                        size: track.size,
+                    });
+                }
+            }
+            track.has_thumbnail = Some(storage_dir.join(format!("{}.thumb", id)).exists());
+            let has_blob = track.track_type == "container" || storage_dir.join(format!("{}.blob", id)).exists();
+            (id, RestoredTrack { track, has_blob })
+        })
+        .collect()
+}
+
+/// Adds the restored tracks to `metadata`.
+/// Items without their video are returned separately to be downloaded again.
+fn merge_tracks(metadata: &mut StorageMetadata, tracks: HashMap<String, RestoredTrack>) -> (RestoreReport, MissingTracks) {
+    let mut report = RestoreReport::default();
+    let mut missing = Vec::new();
+
+    for (id, RestoredTrack { track, has_blob }) in tracks {
+        match metadata.tracks.get_mut(&id) {
+            Some(local) => {
+                // Restored when we didn't have one
+                if local.has_thumbnail != Some(true) {
+                    local.has_thumbnail = track.has_thumbnail;
+                }
+                merge_track(&id, local, track);
+                report.merged += 1;
+            }
+            None if !has_blob => {
+                report.missing_blobs.push(id.clone());
+                missing.push((id, track));
+            }
+            None => {
+                metadata.tracks.insert(id, track);
+                report.added += 1;
+            }
+        }
+    }
+
+    (report, missing)
+}
+
+/// Downloads the videos a restored archive didn't contain and adds their items as they were archived.
+/// Runs as a sync so `storage_abort_downloads` stops it. Items that can't be downloaded are left out and the
+/// files restored for them are removed.
+async fn redownload_missing(app: &AppHandle, missing: MissingTracks) {
+    let download_manager = app.state::<DownloadManager>();
+    let mut left_out = Vec::new();
+
+    let auth = download_manager.jellyfin_auth.lock().unwrap().clone();
+    match auth {
+        Some(auth) => {
+            // One sync at a time, the restore waits for a running one
+            loop {
+                // Created before the check so a sync finishing in between still wakes it
+                let finished = download_manager.sync_finished.notified();
+                if !download_manager.syncing_series.swap(true, Ordering::SeqCst) {
+                    break;
+                }
+                finished.await;
+            }
+
+            let cancel_token = CancellationToken::new();
+            *download_manager.sync_cancellation_token.lock().unwrap() = Some(cancel_token.clone());
+            let client = JellyfinClient::new(auth);
+
+            for (id, track) in missing {
+                if cancel_token.is_cancelled() {
+                    left_out.push(id);
+                    continue;
+                }
+
+                if let Err(e) = redownload(app, &client, &id, track, &cancel_token).await {
+                    println!("redownload_missing: Failed for id {}: {}", id, e);
+                    left_out.push(id);
+                }
+            }
+
+            *download_manager.sync_cancellation_token.lock().unwrap() = None;
+            download_manager.syncing_series.store(false, Ordering::SeqCst);
+            download_manager.sync_finished.notify_waiters();
+        }
+        None => {
+            println!("redownload_missing: Not connected to a server, left out {} items", missing.len());
+            left_out.extend(missing.into_iter().map(|(id, _)| id));
+        }
+    }
+
+    if left_out.is_empty() {
+        return;
+    }
+
+    let app = app.clone();
+    let result = run_blocking(move || {
+        let storage_dir = get_storage_dir(&app)?;
+        // Unless it was downloaded in the meantime its thumbnail and subtitles were restored for nothing
+        let left_out: Vec<String> = left_out
+            .into_iter()
+            .filter(|id| !storage_dir.join(format!("{}.blob", id)).exists())
+            .collect();
+        Ok(remove_track_files(&storage_dir, &left_out)?)
+    })
+    .await;
+
+    if let Err(e) = result {
+        println!("redownload_missing: Failed to remove restored files: {}", e);
+    }
+}
+
+/// Downloads the preferred version of an archived item and adds it to the catalog
+async fn redownload(
+    app: &AppHandle,
+    client: &JellyfinClient,
+    id: &str,
+    track: StorageTrack,
+    cancel_token: &CancellationToken,
+) -> Result<(), String> {
+    let download_manager = app.state::<DownloadManager>();
+
+    // Downloaded again since the restore, it only needs the archived references
+    let claimed = mutate_metadata(app, &download_manager, |metadata| match metadata.tracks.get_mut(id) {
+        Some(local) => {
+            merge_track(id, local, track);
+            Ok(None)
+        }
+        None => claim_download(&download_manager, id).map(|claim| Some((track, claim))).ok_or(()),
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+    let (mut track, _claim) = match claimed {
+        Ok(Some(claimed)) => claimed,
+        Ok(None) => return Ok(()),
+        Err(()) => {
+            println!("redownload: {} is already being downloaded, keeping that download", id);
+            return Ok(());
+        }
+    };
+
+    let storage_dir = get_storage_dir(app).map_err(|e| e.to_string())?;
+    let key_app = app.clone();
+    let key = run_blocking(move || crypto::encryption_key(&key_app)).await.map_err(|e| e.to_string())?;
+    let url = client.stream_url(id, preferred_source_id(id, &track));
+
+    // Only renamed into place once complete, a partial file never sits at the blob path
+    let part_path = storage_dir.join(format!("{}.blob.part", id));
+    let downloaded =
+        download_to_file(&reqwest::Client::new(), &url, &part_path, key.as_ref(), cancel_token, |_, _| {}).await?;
+    if let Err(e) = tokio::fs::rename(&part_path, storage_dir.join(format!("{}.blob", id))).await {
+        let _ = tokio::fs::remove_file(&part_path).await;
+        return Err(e.to_string());
+    }
+    track.size = Some(downloaded);
+
+    mutate_metadata(app, &download_manager, |metadata| match metadata.tracks.get_mut(id) {
+        Some(local) => merge_track(id, local, track),
+        None => {
+            metadata.tracks.insert(id.to_string(), track);
+        }
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+
+    println!("redownload: Restored {} from the server", id);
+    Ok(())
+}
+
+/// External subtitles of every downloaded version that aren't stored as sidecar files yet, named like the sidecar
+/// files they're restored as
+async fn fetch_subtitles(
+    client: &JellyfinClient,
+    metadata: &StorageMetadata,
+    storage_dir: &Path,
+) -> Vec<(String, Vec<u8>)> {
+    let mut subtitles = Vec::new();
+
+    for (id, track) in &metadata.tracks {
+        let sources = track.media_sources.as_ref().and_then(|sources| sources.as_array());
+        let source_ids = std::iter::once(preferred_source_id(id, track))
+            .chain(track.versions.iter().map(|version| version.media_source_id.as_str()));
+
+        for source_id in source_ids {
+            let source = sources
+                .into_iter()
+                .flatten()
+                .find(|source| source.get("Id").and_then(|s| s.as_str()) == Some(source_id));
+            let Some(source) = source else {
+                continue;
+            };
+
+            for (index, extension, _) in external_subtitles(source) {
+                let file_name = format!("{}.{}.{}.{}", id, source_id, index, extension);
+                // Archived from disk, that works offline too
+                if tokio::fs::try_exists(storage_dir.join(&file_name)).await.unwrap_or(false) {
+                    continue;
+                }
+
+                match client.get_bytes(&subtitle_path(id, source_id, index, extension), &[]).await {
+                    Ok(data) => subtitles.push((file_name, data)),
+                    Err(e) => println!("fetch_subtitles: Failed for id {} stream {}: {}", id, index, e),
+                }
+            }
+        }
+    }
+
+    subtitles
+}
+
+fn append_json(
+    builder: &mut tar::Builder<impl std::io::Write>,
+    name: &str,
+    value: &impl Serialize,
+) -> std::io::Result<()> {
+    let data = serde_json::to_vec(value)?;
+    append_file(builder, name, data.len() as u64, data.as_slice())
+}
+
+struct ArchiveContents<'a> {
+    manifest: &'a ArchiveManifest,
+    metadata: &'a StorageMetadata,
+    store: &'a PlaybackStore,
+    /// Sidecar subtitle files fetched from the server because they weren't stored, by file name
+    subtitles: &'a [(String, Vec<u8>)],
+}
+
+fn append_file(
+    builder: &mut tar::Builder<impl std::io::Write>,
+    name: &str,
+    size: u64,
+    data: impl Read,
+) -> std::io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime((now_millis() / 1000) as u64);
+    header.set_cksum();
+    builder.append_data(&mut header, name, data)
+}
+
+fn write_archive(
+    path: &Path,
+    storage_dir: &Path,
+    contents: &ArchiveContents,
+    key: Option<&Key>,
+    include_blobs: bool,
+) -> std::io::Result<()> {
+    let metadata = contents.metadata;
+    let mut builder = tar::Builder::new(BufWriter::new(fs::File::create(path)?));
+
+    append_json(&mut builder, "manifest.json", contents.manifest)?;
+    append_json(&mut builder, "metadata.json", metadata)?;
+    append_json(&mut builder, "playback.json", contents.store)?;
+
+    for (file_name, data) in contents.subtitles {
+        append_file(&mut builder, &format!("files/{}", file_name), data.len() as u64, data.as_slice())?;
+    }
+    let fetched: HashSet<&str> = contents.subtitles.iter().map(|(file_name, _)| file_name.as_str()).collect();
+
+    for entry in fs::read_dir(storage_dir)?.flatten() {
+        let file_name = entry.file_name().to_string_lossy().to_string();
+
+        // Partial downloads and upgrades end in `.blob.upgrade` and friends, only finished files are archived
+        let Some((id, _)) = file_name.split_once('.') else {
+            continue;
+        };
+        let extension = file_name.rsplit('.').next().unwrap_or_default();
+        let is_blob = extension == "blob";
+        let is_subtitle = SUBTITLE_EXTENSIONS.contains(&extension);
+        if !metadata.tracks.contains_key(id) || (is_blob && !include_blobs) {
+            continue;
+        }
+        if !is_blob && !is_subtitle && extension != "thumb" {
+            continue;
+        }
+        // Stored since the fetch, it's archived once
+        if is_subtitle && fetched.contains(file_name.as_str()) {
+            continue;
+        }
+
+        let name = format!("files/{}", file_name);
+        if is_blob {
+            let reader = BlobReader::open(&entry.path(), key)?;
+            append_file(&mut builder, &name, reader.len(), reader)?;
+        } else {
+            let data = crypto::open(fs::read(entry.path())?, key)?;
+            append_file(&mut builder, &name, data.len() as u64, data.as_slice())?;
+        }
+    }
+
+    builder.into_inner()?.into_inner().map_err(|e| e.into_error())?.sync_all()
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(entry: impl Read) -> std::io::Result<T> {
+    serde_json::from_reader(entry).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// Extracts the files of the archived items into `storage_dir`, files that already exist are left alone.
+/// The preferred video of an item stored with another source in `local_sources` goes next to it as a version.
+/// With `key` they're encrypted on the way in.
+fn read_archive(
+    path: &Path,
+    storage_dir: &Path,
+    local_sources: &HashMap<String, String>,
+    key: Option<&Key>,
+) -> std::io::Result<(ArchiveManifest, StorageMetadata, PlaybackStore)> {
+    let invalid = |message: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string());
+
+    let mut archive = tar::Archive::new(BufReader::new(fs::File::open(path)?));
+    let mut manifest: Option<ArchiveManifest> = None;
+    let mut metadata = StorageMetadata::default();
+    let mut store = PlaybackStore::default();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().to_string();
+
+        if manifest.is_none() {
+            if name != "manifest.json" {
+                return Err(invalid("Not an offline library archive"));
+            }
+
+            let archived: ArchiveManifest = read_json(&mut entry)?;
+            if archived.format != ARCHIVE_FORMAT {
+                return Err(invalid("Not an offline library archive"));
+            }
+            if archived.version > ARCHIVE_VERSION {
+                return Err(invalid("Archive was written by a newer version of the app"));
+            }
+
+            manifest = Some(archived);
+            continue;
+        }
+
+        match name.as_str() {
+            "metadata.json" => metadata = read_json(&mut entry)?,
+            "playback.json" => store = read_json(&mut entry)?,
+            _ => {
+                // Only plain file names, anything else could write outside the storage directory
+                let Some(file_name) = name.strip_prefix("files/") else {
+                    continue;
+                };
+                if file_name.is_empty() || file_name.contains(['/', '\\']) || file_name.starts_with('.') {
+                    continue;
+                }
+
+                // `metadata.json` comes first, files of items it doesn't list would never be cleaned up
+                let Some((id, track)) = file_name.split_once('.').and_then(|(id, _)| metadata.tracks.get_key_value(id))
+                else {
+                    continue;
+                };
+
+                let mut target = storage_dir.join(file_name);
+                if file_name == format!("{}.blob", id) {
+                    if let Some(source) = diverted_source(id, track, local_sources) {
+                        let Ok(path) = version_path(storage_dir, id, source) else {
+                            continue;
+                        };
+                        target = path;
+                    }
+                }
+                if target.exists() {
+                    continue;
+                }
+
+                // Written next to the target first so an interrupted restore doesn't leave a truncated blob
+                let partial = storage_dir.join(format!("{}.restore", file_name));
+                match key {
+                    Some(key) if file_name.ends_with(".blob") => {
+                        crypto::copy_blob(&mut entry, &partial, Some(key))?;
+                    }
+                    Some(key) => {
+                        let mut data = Vec::new();
+                        entry.read_to_end(&mut data)?;
+                        fs::write(&partial, crypto::seal(key, &data)?)?;
+                    }
+                    None => {
+                        entry.unpack(&partial)?;
+                    }
+                }
+                fs::rename(&partial, &target)?;
+            }
+        }
+    }
+
+    let manifest = manifest.ok_or_else(|| invalid("Archive is empty"))?;
+    Ok((manifest, metadata, store))
+}
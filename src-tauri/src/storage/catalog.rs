@@ -0,0 +1,242 @@
+//! Catalog operations behind the storage commands. They only take the catalog, the offline user data and
+//! the storage directory, so tests and benchmarks run them without a Tauri app.
+
+use super::crypto::{self, Key};
+use super::playback::{self, OfflineUserData};
+use super::query::{self, ItemFilter, Page, Sort};
+use super::stats::SUBTITLE_EXTENSIONS;
+use super::{tree, versions, StorageMetadata, StorageTrack};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// How saving an item has to go about it
+#[derive(Debug, Clone, PartialEq)]
+pub enum SavePlan {
+    /// Already stored, e.g. through another container. The reference was added and nothing needs downloading.
+    Referenced,
+    /// Another media source of a stored item, downloaded next to it as a version
+    NewVersion(String),
+    Download,
+}
+
+/// Reads the catalog at `path`, an empty one when there is no file yet
+pub fn load(path: &Path, key: Option<&Key>) -> io::Result<StorageMetadata> {
+    let content = match fs::read(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(StorageMetadata::default()),
+        Err(e) => return Err(e),
+    };
+
+    serde_json::from_slice(&crypto::open(content, key)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Contents of the catalog file, sealed when a key is given
+pub fn encode(metadata: &StorageMetadata, key: Option<&Key>) -> io::Result<Vec<u8>> {
+    let content = serde_json::to_vec(metadata)?;
+    match key {
+        Some(key) => crypto::seal(key, &content),
+        None => Ok(content),
+    }
+}
+
+pub fn save(path: &Path, metadata: &StorageMetadata, key: Option<&Key>) -> io::Result<()> {
+    fs::write(path, encode(metadata, key)?)
+}
+
+/// The stored item as the frontend lists it, with its media sources, offline user data and server status
+pub fn offline_media_item(track: &StorageTrack, user_data: Option<&OfflineUserData>) -> serde_json::Value {
+    let mut media_item = track.media_item.clone();
+
+    // Add media sources if present
+    if let Some(media_sources) = &track.media_sources {
+        if let Some(obj) = media_item.as_object_mut() {
+            obj.insert("MediaSources".to_string(), media_sources.clone());
+        }
+    }
+
+    if let Some(user_data) = user_data {
+        playback::apply_user_data(&mut media_item, user_data);
+    }
+
+    if let Some(server_status) = track.server_status {
+        if let Some(obj) = media_item.as_object_mut() {
+            obj.insert("offlineServerStatus".to_string(), serde_json::json!(server_status));
+        }
+    }
+
+    // Mark that thumbnail is available (will be loaded separately)
+    if track.has_thumbnail == Some(true) {
+        if let Some(obj) = media_item.as_object_mut() {
+            obj.insert("hasThumbnail".to_string(), serde_json::Value::Bool(true));
+        }
+    }
+
+    media_item
+}
+
+/// Items with the given ids in that order, ids that aren't stored are skipped
+pub fn media_items<'a>(
+    metadata: &StorageMetadata,
+    user_data: &HashMap<String, OfflineUserData>,
+    ids: impl IntoIterator<Item = &'a str>,
+) -> Vec<serde_json::Value> {
+    ids.into_iter()
+        .filter_map(|id| Some(offline_media_item(metadata.tracks.get(id)?, user_data.get(id))))
+        .collect()
+}
+
+/// The `items_per_page` items of `item_kind` matching `filter` that follow `cursor`
+pub fn page(
+    metadata: &StorageMetadata,
+    user_data: &HashMap<String, OfflineUserData>,
+    item_kind: &str,
+    items_per_page: usize,
+    cursor: Option<&str>,
+    sort: Sort,
+    filter: &ItemFilter,
+) -> Result<Page, String> {
+    let filtered = query::filter_tracks(metadata, item_kind, filter, user_data);
+    let page = query::page_tracks(filtered, sort, cursor, items_per_page)?;
+
+    Ok(Page {
+        items: page
+            .tracks
+            .iter()
+            .map(|(id, track)| offline_media_item(track, user_data.get(*id)))
+            .collect(),
+        total_count: page.total_count,
+        next_cursor: page.next_cursor,
+    })
+}
+
+/// Decides how `data` gets stored, adding the reference right away when the item is already there
+pub fn plan_save(
+    metadata: &mut StorageMetadata,
+    id: &str,
+    data: &StorageTrack,
+    has_video: bool,
+) -> Result<SavePlan, String> {
+    // Both ids end up in file names
+    if !versions::is_valid_id(id) {
+        return Err(format!("Invalid item id {}", id));
+    }
+    if let Some(source) = data.media_source_id.as_deref().filter(|source| !versions::is_valid_id(source)) {
+        return Err(format!("Invalid media source {} of {}", source, id));
+    }
+
+    if let Some(container_id) = &data.container_id {
+        if tree::creates_cycle(metadata, id, container_id) {
+            return Err(format!("Container {} can't be placed inside itself", id));
+        }
+    }
+
+    let Some(track) = metadata.tracks.get_mut(id) else {
+        return Ok(SavePlan::Download);
+    };
+
+    match data.media_source_id.as_deref() {
+        Some(source) if has_video && !versions::has_version(id, track, source) => {
+            Ok(SavePlan::NewVersion(source.to_string()))
+        }
+        _ => {
+            track.add_reference(data.container_id.as_deref());
+            Ok(SavePlan::Referenced)
+        }
+    }
+}
+
+/// Adds a finished download to the catalog, keeping references another container added meanwhile.
+/// Returns true when the item was removed while its new version was downloading, that version then has to be
+/// renamed to the item's blob.
+pub fn finish_save(metadata: &mut StorageMetadata, id: &str, data: StorageTrack, new_version: Option<String>) -> bool {
+    match (new_version, metadata.tracks.get_mut(id)) {
+        (None, _) => {
+            let mut data = data;
+            // Referenced through another container while it was downloading, that reference stays
+            if let Some(stored) = metadata.tracks.remove(id) {
+                if stored.has_thumbnail == Some(true) {
+                    data.has_thumbnail = Some(true);
+                }
+                merge_track(id, &mut data, stored);
+            }
+            metadata.tracks.insert(id.to_string(), data);
+            false
+        }
+        (Some(source), Some(track)) => {
+            track.versions.push(versions::StorageVersion {
+                media_source_id: source,
+                timestamp: data.timestamp,
+                bitrate: data.bitrate,
+                size: data.size,
+            });
+            track.add_reference(data.container_id.as_deref());
+            if data.has_thumbnail == Some(true) {
+                track.has_thumbnail = Some(true);
+            }
+            false
+        }
+        (Some(_), None) => {
+            metadata.tracks.insert(id.to_string(), data);
+            true
+        }
+    }
+}
+
+/// Adds the references and versions of another copy of the item to `local`
+pub(super) fn merge_track(id: &str, local: &mut StorageTrack, track: StorageTrack) {
+    for holder in track.container_ids() {
+        local.add_reference(Some(holder));
+    }
+    if track.is_direct() {
+        local.add_reference(None);
+    }
+    for version in track.versions {
+        if !versions::has_version(id, local, &version.media_source_id) {
+            local.versions.push(version);
+        }
+    }
+}
+
+/// Drops the reference `container_id` holds on the item, see `storage_remove_track`.
+/// Returns the ids nothing references anymore, their files can go.
+pub fn remove(metadata: &mut StorageMetadata, id: &str, container_id: Option<&str>) -> Vec<String> {
+    let holders: Vec<Option<String>> = match metadata.tracks.get(id) {
+        Some(track) if container_id.is_none() && !track.is_direct() => {
+            track.container_ids().map(|holder| Some(holder.to_string())).collect()
+        }
+        _ => vec![container_id.map(str::to_string)],
+    };
+
+    let mut removed = Vec::new();
+    for holder in holders {
+        removed.extend(tree::release(metadata, id, holder.as_deref()));
+    }
+    removed
+}
+
+/// Removes the blobs of every version, the sidecar subtitles and the thumbnails of the tracks, the catalog
+/// entries are left to the caller. The storage directory is listed once for the whole batch.
+pub fn remove_track_files(storage_dir: &Path, ids: &[String]) -> io::Result<()> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+
+    let ids: HashSet<&str> = ids.iter().map(String::as_str).collect();
+    for entry in fs::read_dir(storage_dir)?.flatten() {
+        let file_name = entry.file_name();
+        let file_name = file_name.to_string_lossy();
+
+        // `{id}.blob`, `{id}.{media_source_id}.blob`, `{id}.thumb` and subtitles, ids never contain a dot
+        let Some((id, _)) = file_name.split_once('.') else {
+            continue;
+        };
+        let extension = file_name.rsplit('.').next().unwrap_or_default();
+        if ids.contains(id) && (extension == "blob" || extension == "thumb" || SUBTITLE_EXTENSIONS.contains(&extension)) {
+            fs::remove_file(entry.path())?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,418 @@
+//! Encryption at rest for the offline storage.
+//!
+//! Blobs are encrypted with XChaCha20 so any byte range can be decrypted on its own, which the stream
+//! server needs for seeking. Small files (catalog, playback state, thumbnails) are sealed with
+//! XChaCha20-Poly1305. Both formats start with a magic and a random nonce. Files without the magic are
+//! plain and stay readable, so turning encryption on or off never breaks existing downloads.
+
+use super::settings::get_settings;
+use super::{
+    get_cached_metadata, get_storage_dir, item_in_use, playback, run_blocking, write_cached_metadata, DownloadManager,
+};
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::XChaCha20;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tauri::{AppHandle, Manager};
+
+pub type Key = [u8; 32];
+
+const BLOB_MAGIC: &[u8; 4] = b"JVB1";
+const SEALED_MAGIC: &[u8; 4] = b"JVS1";
+const NONCE_LEN: usize = 24;
+pub const BLOB_HEADER_LEN: usize = BLOB_MAGIC.len() + NONCE_LEN;
+/// How long encrypting existing files waits before retrying videos that were in use
+const IN_USE_RETRY: Duration = Duration::from_secs(30);
+
+/// Entry of the key in the OS credential store
+#[cfg(not(target_os = "android"))]
+const KEYRING_SERVICE: &str = "com.stan.jelly-video-app";
+#[cfg(not(target_os = "android"))]
+const KEYRING_USER: &str = "offline-storage";
+
+/// Where the key was kept before it moved to the credential store, and still is on Android which has none
+fn key_file_path(app: &AppHandle) -> tauri::Result<PathBuf> {
+    Ok(app.path().app_config_dir()?.join("storage.key"))
+}
+
+fn read_key_file(path: &Path) -> io::Result<Option<Key>> {
+    match fs::read(path) {
+        Ok(data) => Key::try_from(data.as_slice())
+            .map(Some)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Storage key is corrupt")),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "android"))]
+fn keyring_entry() -> io::Result<keyring::Entry> {
+    keyring::Entry::new(KEYRING_SERVICE, KEYRING_USER).map_err(io::Error::other)
+}
+
+#[cfg(not(target_os = "android"))]
+fn load_key(app: &AppHandle) -> tauri::Result<Option<Key>> {
+    let entry = keyring_entry()?;
+
+    match entry.get_secret() {
+        Ok(secret) => Ok(Some(Key::try_from(secret.as_slice()).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "Storage key is corrupt")
+        })?)),
+        Err(keyring::Error::NoEntry) => {
+            // Move a key from an earlier version into the credential store
+            let path = key_file_path(app)?;
+            let Some(key) = read_key_file(&path)? else {
+                return Ok(None);
+            };
+
+            entry.set_secret(&key).map_err(io::Error::other)?;
+            fs::remove_file(&path)?;
+            println!("load_key: Moved the storage key into the credential store");
+            Ok(Some(key))
+        }
+        Err(e) => Err(io::Error::other(e).into()),
+    }
+}
+
+#[cfg(not(target_os = "android"))]
+fn store_key(_app: &AppHandle, key: &Key) -> tauri::Result<()> {
+    Ok(keyring_entry()?.set_secret(key).map_err(io::Error::other)?)
+}
+
+#[cfg(target_os = "android")]
+fn load_key(app: &AppHandle) -> tauri::Result<Option<Key>> {
+    Ok(read_key_file(&key_file_path(app)?)?)
+}
+
+#[cfg(target_os = "android")]
+fn store_key(app: &AppHandle, key: &Key) -> tauri::Result<()> {
+    let path = key_file_path(app)?;
+    fs::create_dir_all(path.parent().unwrap_or(&path))?;
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(&path)?.write_all(key)?;
+
+    Ok(())
+}
+
+/// The key from the cache, loading it on first use and creating it when `create` is set.
+/// The credential store can block on the OS, so the first call should run on the blocking pool.
+fn cached_key(app: &AppHandle, create: bool) -> tauri::Result<Option<Key>> {
+    let download_manager = app.state::<DownloadManager>();
+    let mut cached = download_manager.storage_key.lock().unwrap();
+
+    if cached.is_none() {
+        *cached = load_key(app)?;
+    }
+
+    if cached.is_none() && create {
+        let mut key = Key::default();
+        OsRng.fill_bytes(&mut key);
+        store_key(app, &key)?;
+        *cached = Some(key);
+    }
+
+    Ok(*cached)
+}
+
+/// Key to decrypt existing files with, it outlives the setting so files encrypted earlier stay readable
+pub fn get_key(app: &AppHandle) -> tauri::Result<Option<Key>> {
+    cached_key(app, false)
+}
+
+/// Key new files are encrypted with, `None` while encryption is turned off
+pub fn encryption_key(app: &AppHandle) -> tauri::Result<Option<Key>> {
+    if !get_settings(app, &app.state::<DownloadManager>())?.encrypt_storage {
+        return Ok(None);
+    }
+
+    cached_key(app, true)
+}
+
+/// Header to write before the ciphertext of a new blob and the cipher to encrypt what follows it
+pub fn new_blob(key: &Key) -> ([u8; BLOB_HEADER_LEN], XChaCha20) {
+    let mut header = [0u8; BLOB_HEADER_LEN];
+    header[..BLOB_MAGIC.len()].copy_from_slice(BLOB_MAGIC);
+    OsRng.fill_bytes(&mut header[BLOB_MAGIC.len()..]);
+
+    let cipher = XChaCha20::new(key.into(), header[BLOB_MAGIC.len()..].into());
+    (header, cipher)
+}
+
+pub fn is_encrypted_blob(header: &[u8]) -> bool {
+    header.len() >= BLOB_HEADER_LEN && header.starts_with(BLOB_MAGIC)
+}
+
+/// Cipher positioned at the start of the plaintext, `None` for plain blobs
+pub fn blob_cipher(header: &[u8], key: Option<&Key>) -> io::Result<Option<XChaCha20>> {
+    if !is_encrypted_blob(header) {
+        return Ok(None);
+    }
+
+    let key = key.ok_or_else(|| io::Error::new(io::ErrorKind::PermissionDenied, "Storage key is missing"))?;
+    Ok(Some(XChaCha20::new(
+        key.into(),
+        header[BLOB_MAGIC.len()..BLOB_HEADER_LEN].into(),
+    )))
+}
+
+/// True when the file at `path` is an encrypted blob and can't be handed to a player directly
+pub fn is_encrypted_file(path: &Path) -> bool {
+    let mut header = [0u8; BLOB_HEADER_LEN];
+    fs::File::open(path)
+        .and_then(|mut file| file.read_exact(&mut header))
+        .is_ok_and(|()| is_encrypted_blob(&header))
+}
+
+pub fn seal(key: &Key, plain: &[u8]) -> io::Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+
+    let ciphertext = XChaCha20Poly1305::new(key.into())
+        .encrypt(XNonce::from_slice(&nonce), plain)
+        .map_err(|_| io::Error::other("Encryption failed"))?;
+
+    let mut sealed = Vec::with_capacity(SEALED_MAGIC.len() + NONCE_LEN + ciphertext.len());
+    sealed.extend_from_slice(SEALED_MAGIC);
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+/// Plaintext of a file written by `seal`, plain data is returned as is
+pub fn open(data: Vec<u8>, key: Option<&Key>) -> io::Result<Vec<u8>> {
+    let header_len = SEALED_MAGIC.len() + NONCE_LEN;
+    if data.len() < header_len || !data.starts_with(SEALED_MAGIC) {
+        return Ok(data);
+    }
+
+    let key = key.ok_or_else(|| io::Error::new(io::ErrorKind::PermissionDenied, "Storage key is missing"))?;
+    XChaCha20Poly1305::new(key.into())
+        .decrypt(XNonce::from_slice(&data[SEALED_MAGIC.len()..header_len]), &data[header_len..])
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Decryption failed"))
+}
+
+/// Reads a small storage file, decrypting it if needed
+pub fn read_file(app: &AppHandle, path: &Path) -> tauri::Result<Vec<u8>> {
+    let data = fs::read(path)?;
+    if !data.starts_with(SEALED_MAGIC) {
+        return Ok(data);
+    }
+
+    Ok(open(data, get_key(app)?.as_ref())?)
+}
+
+/// Writes a small storage file, sealed while encryption is turned on
+pub fn write_file(app: &AppHandle, path: &Path, data: &[u8]) -> tauri::Result<()> {
+    match encryption_key(app)? {
+        Some(key) => fs::write(path, seal(&key, data)?)?,
+        None => fs::write(path, data)?,
+    }
+    Ok(())
+}
+
+/// Plaintext view of a blob, for copying it out of the store
+pub struct BlobReader {
+    reader: BufReader<fs::File>,
+    cipher: Option<XChaCha20>,
+    len: u64,
+}
+
+impl BlobReader {
+    pub fn open(path: &Path, key: Option<&Key>) -> io::Result<Self> {
+        let mut file = fs::File::open(path)?;
+        let file_len = file.metadata()?.len();
+
+        let mut header = [0u8; BLOB_HEADER_LEN];
+        let header_read = file.read_exact(&mut header).is_ok();
+        let cipher = if header_read { blob_cipher(&header, key)? } else { None };
+
+        let offset = if cipher.is_some() { BLOB_HEADER_LEN as u64 } else { 0 };
+        file.seek(SeekFrom::Start(offset))?;
+
+        Ok(Self {
+            reader: BufReader::new(file),
+            cipher,
+            len: file_len - offset,
+        })
+    }
+
+    /// Size of the plaintext
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Read for BlobReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        if let Some(cipher) = self.cipher.as_mut() {
+            cipher.apply_keystream(&mut buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+/// Writes the plaintext of `reader` to `target`, encrypted when `key` is set. Returns the plaintext size.
+pub fn copy_blob(mut reader: impl Read, target: &Path, key: Option<&Key>) -> io::Result<u64> {
+    let mut writer = BufWriter::new(fs::File::create(target)?);
+    let mut cipher = match key {
+        Some(key) => {
+            let (header, cipher) = new_blob(key);
+            writer.write_all(&header)?;
+            Some(cipher)
+        }
+        None => None,
+    };
+
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut total = 0;
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        if let Some(cipher) = cipher.as_mut() {
+            cipher.apply_keystream(&mut buf[..n]);
+        }
+        writer.write_all(&buf[..n])?;
+        total += n as u64;
+    }
+
+    writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    Ok(total)
+}
+
+/// Encrypts what was stored before encryption was turned on. Only files of finished downloads are touched,
+/// each one is written next to the original and renamed over it once complete. Videos the player or the stream
+/// server has open are left for a later pass, until they're closed or encryption is turned off again.
+pub fn spawn_encrypt_existing(app: AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        let result = async {
+            let Some(mut key) = read_encryption_key(&app).await.map_err(|e| e.to_string())? else {
+                return Ok(());
+            };
+
+            // Rewriting them through the usual paths seals them
+            let download_manager = app.state::<DownloadManager>();
+            let metadata = get_cached_metadata(&app, &download_manager).await.map_err(|e| e.to_string())?;
+            write_cached_metadata(&app).await.map_err(|e| e.to_string())?;
+            playback::update_playback_store(&app, &download_manager, |_| {}).await.map_err(|e| e.to_string())?;
+
+            let storage_dir = get_storage_dir(&app).map_err(|e| e.to_string())?;
+            let mut blobs = Vec::new();
+            let mut thumbnails = Vec::new();
+            for (id, track) in &metadata.tracks {
+                blobs.push((id.clone(), storage_dir.join(format!("{}.blob", id))));
+                blobs.extend(track.versions.iter().filter_map(|version| {
+                    let path = super::versions::version_path(&storage_dir, id, &version.media_source_id).ok()?;
+                    Some((id.clone(), path))
+                }));
+                thumbnails.push(storage_dir.join(format!("{}.thumb", id)));
+            }
+
+            loop {
+                let task_app = app.clone();
+                let thumbnails = std::mem::take(&mut thumbnails);
+                let (encrypted, in_use) = run_blocking(move || {
+                    let download_manager = task_app.state::<DownloadManager>();
+                    Ok(encrypt_files(&key, blobs, &thumbnails, |id| item_in_use(&download_manager, id)))
+                })
+                .await
+                .map_err(|e| e.to_string())?;
+                println!("spawn_encrypt_existing: Encrypted {} files", encrypted);
+
+                if in_use.is_empty() {
+                    break;
+                }
+                println!("spawn_encrypt_existing: {} videos are in use, retrying later", in_use.len());
+                tokio::time::sleep(IN_USE_RETRY).await;
+
+                key = match read_encryption_key(&app).await.map_err(|e| e.to_string())? {
+                    Some(key) => key,
+                    None => break,
+                };
+                blobs = in_use;
+            }
+
+            Ok::<_, String>(())
+        }
+        .await;
+
+        if let Err(e) = result {
+            println!("spawn_encrypt_existing: {}", e);
+        }
+    });
+}
+
+/// Reads the key on the blocking pool, it can be a keychain call
+async fn read_encryption_key(app: &AppHandle) -> tauri::Result<Option<Key>> {
+    let app = app.clone();
+    run_blocking(move || encryption_key(&app)).await
+}
+
+/// Encrypts the plain files among `blobs` and `thumbnails`, returns how many it encrypted and the blobs it skipped
+/// because their item was `in_use`
+fn encrypt_files(
+    key: &Key,
+    blobs: Vec<(String, PathBuf)>,
+    thumbnails: &[PathBuf],
+    in_use: impl Fn(&str) -> bool,
+) -> (usize, Vec<(String, PathBuf)>) {
+    let mut encrypted = 0;
+    let mut skipped = Vec::new();
+
+    for (id, path) in blobs {
+        if !path.exists() || is_encrypted_file(&path) {
+            continue;
+        }
+        if in_use(&id) {
+            skipped.push((id, path));
+            continue;
+        }
+
+        let partial = path.with_extension("blob.encrypting");
+        let result = BlobReader::open(&path, None)
+            .and_then(|reader| copy_blob(reader, &partial, Some(key)))
+            .and_then(|_| fs::rename(&partial, &path));
+
+        match result {
+            Ok(()) => encrypted += 1,
+            Err(e) => {
+                let _ = fs::remove_file(&partial);
+                println!("encrypt_files: Failed for {:?}: {}", path, e);
+            }
+        }
+    }
+
+    for path in thumbnails {
+        let Ok(data) = fs::read(path) else {
+            continue;
+        };
+        if data.starts_with(SEALED_MAGIC) {
+            continue;
+        }
+
+        match seal(key, &data).and_then(|sealed| fs::write(path, sealed)) {
+            Ok(()) => encrypted += 1,
+            Err(e) => println!("encrypt_files: Failed for {:?}: {}", path, e),
+        }
+    }
+
+    (encrypted, skipped)
+}
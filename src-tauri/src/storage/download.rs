@@ -0,0 +1,126 @@
+use super::crypto::{self, Key};
+use chacha20::cipher::StreamCipher;
+use futures_util::StreamExt;
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
+use tokio_util::sync::CancellationToken;
+
+/// Why a download failed, split so callers can tell a dropped connection worth retrying from the rest
+#[derive(Debug)]
+pub enum DownloadError {
+    Cancelled,
+    Status(reqwest::StatusCode),
+    /// The connection failed or broke off, or the body ended short of `Content-Length`
+    Interrupted(String),
+    Io(String),
+}
+
+impl DownloadError {
+    /// Dropped connections and server errors, the server rejecting the request won't change on retry
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            DownloadError::Interrupted(_) => true,
+            DownloadError::Status(status) => status.is_server_error(),
+            DownloadError::Cancelled | DownloadError::Io(_) => false,
+        }
+    }
+}
+
+impl std::fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DownloadError::Cancelled => write!(f, "Download cancelled"),
+            DownloadError::Status(status) => write!(f, "Failed to download video: HTTP {}", status),
+            DownloadError::Interrupted(e) => write!(f, "Download interrupted: {}", e),
+            DownloadError::Io(e) => write!(f, "Failed to write download: {}", e),
+        }
+    }
+}
+
+impl From<DownloadError> for String {
+    fn from(e: DownloadError) -> Self {
+        e.to_string()
+    }
+}
+
+impl From<std::io::Error> for DownloadError {
+    fn from(e: std::io::Error) -> Self {
+        DownloadError::Io(e.to_string())
+    }
+}
+
+/// Streams `url` into `path`, calling `on_progress(downloaded, total)` after every chunk.
+/// With `key` the file is encrypted as it's written, sizes passed around are always those of the plaintext.
+/// The partial file is removed when the download is cancelled, fails or ends short of `Content-Length`.
+pub async fn download_to_file(
+    client: &reqwest::Client,
+    url: &str,
+    path: &Path,
+    key: Option<&Key>,
+    cancel_token: &CancellationToken,
+    mut on_progress: impl FnMut(u64, u64),
+) -> Result<u64, DownloadError> {
+    let response = client.get(url).send().await.map_err(|e| DownloadError::Interrupted(e.to_string()))?;
+
+    if !response.status().is_success() {
+        return Err(DownloadError::Status(response.status()));
+    }
+
+    let total_size = response.content_length().unwrap_or(0);
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut stream = response.bytes_stream();
+    let mut downloaded: u64 = 0;
+
+    let result = async {
+        let mut cipher = match key {
+            Some(key) => {
+                let (header, cipher) = crypto::new_blob(key);
+                file.write_all(&header).await?;
+                Some(cipher)
+            }
+            None => None,
+        };
+
+        loop {
+            tokio::select! {
+                _ = cancel_token.cancelled() => {
+                    return Err(DownloadError::Cancelled);
+                }
+                chunk_result = stream.next() => {
+                    match chunk_result {
+                        Some(Ok(chunk)) => {
+                            match cipher.as_mut() {
+                                Some(cipher) => {
+                                    let mut chunk = chunk.to_vec();
+                                    cipher.apply_keystream(&mut chunk);
+                                    file.write_all(&chunk).await?;
+                                }
+                                None => file.write_all(&chunk).await?,
+                            }
+                            downloaded += chunk.len() as u64;
+                            on_progress(downloaded, total_size);
+                        }
+                        Some(Err(e)) => return Err(DownloadError::Interrupted(e.to_string())),
+                        None => break,
+                    }
+                }
+            }
+        }
+
+        file.flush().await?;
+
+        if total_size > 0 && downloaded != total_size {
+            return Err(DownloadError::Interrupted(format!("got {} of {} bytes", downloaded, total_size)));
+        }
+
+        Ok(downloaded)
+    }
+    .await;
+
+    if result.is_err() {
+        drop(file);
+        let _ = tokio::fs::remove_file(path).await;
+    }
+
+    result
+}
@@ -0,0 +1,116 @@
+//! Events emitted whenever the catalog changes, whether a command or a background task changed it.
+//! The frontend patches its queries from them instead of refetching after every save or remove.
+
+use super::catalog::offline_media_item;
+use super::playback::OfflineUserData;
+use super::StorageMetadata;
+use serde::Serialize;
+use std::collections::HashMap;
+use tauri::{AppHandle, Emitter};
+
+pub const ITEM_ADDED: &str = "storage-item-added";
+pub const ITEM_REMOVED: &str = "storage-item-removed";
+pub const CLEARED: &str = "storage-cleared";
+pub const STATS_CHANGED: &str = "storage-stats-changed";
+
+/// Catalog totals, the file system isn't scanned for them
+#[derive(Debug, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageTotals {
+    pub track_count: usize,
+    /// Recorded size of every stored version
+    pub size: u64,
+}
+
+impl StorageTotals {
+    pub fn of(metadata: &StorageMetadata) -> Self {
+        Self {
+            track_count: metadata.tracks.len(),
+            size: metadata
+                .tracks
+                .values()
+                .map(|track| {
+                    track.size.unwrap_or(0) + track.versions.iter().filter_map(|version| version.size).sum::<u64>()
+                })
+                .sum(),
+        }
+    }
+}
+
+/// Payload of `storage-item-added` and `storage-item-removed`
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ItemsChanged {
+    pub ids: Vec<String>,
+    /// The items as listed offline, removed ones as they were before removal
+    pub items: Vec<serde_json::Value>,
+    pub totals: StorageTotals,
+}
+
+/// What changed between two versions of the catalog
+#[derive(Debug, Clone)]
+pub struct CatalogChanges {
+    pub added: ItemsChanged,
+    pub removed: ItemsChanged,
+    pub totals_changed: bool,
+}
+
+/// Items are listed with their offline `user_data` applied, like every other offline listing
+pub fn diff(
+    previous: &StorageMetadata,
+    current: &StorageMetadata,
+    user_data: &HashMap<String, OfflineUserData>,
+) -> CatalogChanges {
+    let totals = StorageTotals::of(current);
+
+    let changed = |from: &StorageMetadata, to: &StorageMetadata| {
+        let mut ids: Vec<&String> = to.tracks.keys().filter(|id| !from.tracks.contains_key(*id)).collect();
+        ids.sort();
+
+        ItemsChanged {
+            items: ids
+                .iter()
+                .map(|id| offline_media_item(&to.tracks[*id], user_data.get(*id)))
+                .collect(),
+            ids: ids.into_iter().cloned().collect(),
+            totals,
+        }
+    };
+
+    CatalogChanges {
+        added: changed(previous, current),
+        removed: changed(current, previous),
+        totals_changed: StorageTotals::of(previous) != totals,
+    }
+}
+
+/// Emits the events for going from `previous` to `current`. Without a previous catalog only the totals are known.
+pub fn emit_changes(
+    app: &AppHandle,
+    previous: Option<&StorageMetadata>,
+    current: &StorageMetadata,
+    user_data: &HashMap<String, OfflineUserData>,
+) {
+    let Some(previous) = previous else {
+        let _ = app.emit(STATS_CHANGED, StorageTotals::of(current));
+        return;
+    };
+
+    let changes = diff(previous, current, user_data);
+
+    if !changes.removed.ids.is_empty() {
+        let _ = app.emit(ITEM_REMOVED, &changes.removed);
+    }
+    if !changes.added.ids.is_empty() {
+        let _ = app.emit(ITEM_ADDED, &changes.added);
+    }
+    if changes.totals_changed {
+        let _ = app.emit(STATS_CHANGED, changes.added.totals);
+    }
+}
+
+pub fn emit_cleared(app: &AppHandle) {
+    let totals = StorageTotals::default();
+    let _ = app.emit(CLEARED, totals);
+    let _ = app.emit(STATS_CHANGED, totals);
+}
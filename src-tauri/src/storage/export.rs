@@ -0,0 +1,459 @@
+use super::crypto::{self, BlobReader, Key};
+use super::jellyfin::JellyfinClient;
+use super::tree::descendants;
+use super::versions::preferred_source_id;
+use super::{get_cached_metadata, get_storage_dir, run_blocking, DownloadManager, StorageMetadata, StorageTrack};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use tauri::{AppHandle, State};
+
+#[derive(Debug, Serialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportReport {
+    pub exported: usize,
+    /// Ids that couldn't be exported, details are logged
+    pub failed: Vec<String>,
+}
+
+/// Everything the export of a single item needs
+struct ExportContext {
+    storage_dir: PathBuf,
+    destination: PathBuf,
+    metadata: StorageMetadata,
+    client: Option<JellyfinClient>,
+    /// Encrypted files are decrypted while copying
+    key: Option<Key>,
+    hard_link: bool,
+}
+
+/// Copies the selected items into `destination` the way media centers expect them:
+/// `Show/Season 01/Show - S01E01 - Title.mkv` for episodes and `Movie (2020)/Movie (2020).mkv` for everything else,
+/// each with a Kodi-style `.nfo`, its artwork and, when connected to the server, its external subtitles.
+/// Containers export everything below them. With `hard_link` files are linked instead of copied where the
+/// file system allows it, encrypted files are always decrypted into a copy.
+#[tauri::command]
+pub async fn storage_export(
+    app: AppHandle,
+    download_manager: State<'_, DownloadManager>,
+    ids: Vec<String>,
+    destination: String,
+    hard_link: bool,
+) -> Result<ExportReport, String> {
+    // Reading the key can be a keychain call
+    let key_app = app.clone();
+    let key = run_blocking(move || crypto::get_key(&key_app)).await.map_err(|e| e.to_string())?;
+
+    let ctx = ExportContext {
+        storage_dir: get_storage_dir(&app).map_err(|e| e.to_string())?,
+        destination: PathBuf::from(destination),
+        metadata: get_cached_metadata(&app, &download_manager).await.map_err(|e| e.to_string())?,
+        client: download_manager.jellyfin_auth.lock().unwrap().clone().map(JellyfinClient::new),
+        key,
+        hard_link,
+    };
+
+    let mut selected: Vec<String> = Vec::new();
+    for id in &ids {
+        selected.push(id.clone());
+        selected.extend(descendants(&ctx.metadata, id));
+    }
+
+    let mut seen = HashSet::new();
+    let mut report = ExportReport::default();
+
+    for id in selected {
+        if !seen.insert(id.clone()) {
+            continue;
+        }
+        let Some(track) = ctx.metadata.tracks.get(&id) else {
+            continue;
+        };
+
+        let result = if track.track_type == "container" {
+            export_container(&ctx, &id, track).await
+        } else {
+            export_item(&ctx, &id, track).await
+        };
+
+        match result {
+            Ok(true) => report.exported += 1,
+            Ok(false) => {}
+            Err(e) => {
+                println!("storage_export: Failed to export id {}: {}", id, e);
+                report.failed.push(id);
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+fn str_field<'a>(item: &'a serde_json::Value, key: &str) -> Option<&'a str> {
+    item.get(key).and_then(|v| v.as_str())
+}
+
+fn int_field(item: &serde_json::Value, key: &str) -> Option<i64> {
+    item.get(key).and_then(|v| v.as_i64())
+}
+
+/// Drops characters Windows, macOS and FAT file systems don't allow in names
+fn sanitize(name: &str) -> String {
+    let cleaned: String = name
+        .chars()
+        .filter(|c| !c.is_control() && !matches!(c, '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*'))
+        .collect();
+    let cleaned = cleaned.trim().trim_end_matches('.').trim();
+
+    if cleaned.is_empty() {
+        "Unknown".to_string()
+    } else {
+        cleaned.to_string()
+    }
+}
+
+fn series_dir(destination: &Path, item: &serde_json::Value) -> PathBuf {
+    let series = str_field(item, "SeriesName").or_else(|| str_field(item, "Name")).unwrap_or_default();
+    destination.join(sanitize(series))
+}
+
+fn movie_name(item: &serde_json::Value) -> String {
+    let name = str_field(item, "Name").unwrap_or_default();
+    match int_field(item, "ProductionYear") {
+        Some(year) => sanitize(&format!("{} ({})", name, year)),
+        None => sanitize(name),
+    }
+}
+
+/// Directory and file name without extension an item is exported as
+fn export_location(destination: &Path, item: &serde_json::Value) -> (PathBuf, String) {
+    if str_field(item, "Type") == Some("Episode") {
+        let series = str_field(item, "SeriesName").unwrap_or_default();
+        let season = int_field(item, "ParentIndexNumber").unwrap_or(0);
+        let episode = int_field(item, "IndexNumber").unwrap_or(0);
+        let title = str_field(item, "Name").unwrap_or_default();
+
+        let dir = series_dir(destination, item).join(format!("Season {:02}", season));
+        let name = sanitize(&format!("{} - S{:02}E{:02} - {}", series, season, episode, title));
+        (dir, name)
+    } else {
+        let name = movie_name(item);
+        (destination.join(&name), name)
+    }
+}
+
+/// File extension of the downloaded source, from its original path or else its container
+fn container_extension(source: Option<&serde_json::Value>) -> String {
+    let from_path = source
+        .and_then(|source| str_field(source, "Path"))
+        .and_then(|path| Path::new(path).extension())
+        .map(|ext| ext.to_string_lossy().to_lowercase());
+
+    if let Some(ext) = from_path {
+        return ext;
+    }
+
+    // Jellyfin reports ffmpeg format names, possibly several like "mov,mp4,m4a"
+    match source
+        .and_then(|source| str_field(source, "Container"))
+        .and_then(|container| container.split(',').next())
+    {
+        Some("matroska") | None => "mkv".to_string(),
+        Some("mpegts") => "ts".to_string(),
+        Some("mov") => "mp4".to_string(),
+        Some(container) => container.to_lowercase(),
+    }
+}
+
+fn image_extension(data: &[u8]) -> &'static str {
+    match data {
+        [0xFF, 0xD8, ..] => "jpg",
+        [0x89, b'P', b'N', b'G', ..] => "png",
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => "webp",
+        _ => "jpg",
+    }
+}
+
+/// Copies the item's thumbnail to `{dir}/{name}.{ext}`, returns false when there is none
+async fn export_artwork(ctx: &ExportContext, id: &str, dir: &Path, name: &str) -> std::io::Result<bool> {
+    let data = match tokio::fs::read(ctx.storage_dir.join(format!("{}.thumb", id))).await {
+        Ok(data) => crypto::open(data, ctx.key.as_ref())?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+
+    tokio::fs::write(dir.join(format!("{}.{}", name, image_extension(&data))), data).await?;
+    Ok(true)
+}
+
+async fn export_container(ctx: &ExportContext, id: &str, track: &StorageTrack) -> Result<bool, String> {
+    let item = &track.media_item;
+
+    match str_field(item, "Type") {
+        Some("Series") => {
+            let dir = series_dir(&ctx.destination, item);
+            tokio::fs::create_dir_all(&dir).await.map_err(|e| e.to_string())?;
+            tokio::fs::write(dir.join("tvshow.nfo"), build_nfo("tvshow", item, None))
+                .await
+                .map_err(|e| e.to_string())?;
+            export_artwork(ctx, id, &dir, "poster").await.map_err(|e| e.to_string())?;
+        }
+        Some("Season") => {
+            // Season artwork lives next to the show, named after the season number
+            let series = str_field(item, "SeriesId").and_then(|series_id| ctx.metadata.tracks.get(series_id));
+            let dir = series_dir(&ctx.destination, series.map_or(item, |series| &series.media_item));
+            tokio::fs::create_dir_all(&dir).await.map_err(|e| e.to_string())?;
+            let season = int_field(item, "IndexNumber").unwrap_or(0);
+            export_artwork(ctx, id, &dir, &format!("season{:02}-poster", season))
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        _ => return Ok(false),
+    }
+
+    Ok(true)
+}
+
+async fn export_item(ctx: &ExportContext, id: &str, track: &StorageTrack) -> Result<bool, String> {
+    let item = &track.media_item;
+    let source_id = preferred_source_id(id, track);
+    let sources = track.media_sources.as_ref().and_then(|s| s.as_array());
+    let source = sources.and_then(|sources| {
+        sources
+            .iter()
+            .find(|source| str_field(source, "Id") == Some(source_id))
+            .or_else(|| sources.first())
+    });
+
+    let blob_path = ctx.storage_dir.join(format!("{}.blob", id));
+    if !tokio::fs::try_exists(&blob_path).await.unwrap_or(false) {
+        return Err("Blob is missing".to_string());
+    }
+
+    let (dir, name) = export_location(&ctx.destination, item);
+    tokio::fs::create_dir_all(&dir).await.map_err(|e| e.to_string())?;
+
+    let target = dir.join(format!("{}.{}", name, container_extension(source)));
+    if tokio::fs::try_exists(&target).await.unwrap_or(false) {
+        tokio::fs::remove_file(&target).await.map_err(|e| e.to_string())?;
+    }
+
+    let header_path = blob_path.clone();
+    let encrypted = run_blocking(move || Ok(crypto::is_encrypted_file(&header_path)))
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if encrypted {
+        let key = ctx.key;
+        let target = target.clone();
+        run_blocking(move || {
+            Ok(BlobReader::open(&blob_path, key.as_ref()).and_then(|reader| crypto::copy_blob(reader, &target, None))?)
+        })
+        .await
+        .map_err(|e| e.to_string())?;
+    } else {
+        // Hard links fail across file systems, e.g. to a USB stick
+        let linked = ctx.hard_link && tokio::fs::hard_link(&blob_path, &target).await.is_ok();
+        if !linked {
+            tokio::fs::copy(&blob_path, &target).await.map_err(|e| e.to_string())?;
+        }
+    }
+
+    let root = if str_field(item, "Type") == Some("Episode") {
+        "episodedetails"
+    } else {
+        "movie"
+    };
+    let series = str_field(item, "SeriesId").and_then(|series_id| ctx.metadata.tracks.get(series_id));
+    tokio::fs::write(
+        dir.join(format!("{}.nfo", name)),
+        build_nfo(root, item, series.map(|series| &series.media_item)),
+    )
+    .await
+    .map_err(|e| e.to_string())?;
+
+    let artwork_name = if root == "movie" {
+        "poster".to_string()
+    } else {
+        format!("{}-thumb", name)
+    };
+    export_artwork(ctx, id, &dir, &artwork_name)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if let (Some(client), Some(source)) = (&ctx.client, source) {
+        export_subtitles(client, id, source_id, source, &dir, &name).await;
+    }
+
+    Ok(true)
+}
+
+/// External subtitle streams of `source` in a format that works as a sidecar file, with their index and extension
+pub(super) fn external_subtitles(source: &serde_json::Value) -> Vec<(i64, &'static str, &serde_json::Value)> {
+    source
+        .get("MediaStreams")
+        .and_then(|streams| streams.as_array())
+        .into_iter()
+        .flatten()
+        .filter(|stream| str_field(stream, "Type") == Some("Subtitle"))
+        .filter(|stream| stream.get("IsExternal").and_then(|e| e.as_bool()) == Some(true))
+        .filter_map(|stream| {
+            let extension = match str_field(stream, "Codec").map(|codec| codec.to_lowercase()).as_deref() {
+                Some("subrip") | Some("srt") => "srt",
+                Some("webvtt") | Some("vtt") => "vtt",
+                Some("ass") => "ass",
+                Some("ssa") => "ssa",
+                _ => return None,
+            };
+            Some((int_field(stream, "Index")?, extension, stream))
+        })
+        .collect()
+}
+
+pub(super) fn subtitle_path(id: &str, source_id: &str, index: i64, extension: &str) -> String {
+    format!("Videos/{}/{}/Subtitles/{}/Stream.{}", id, source_id, index, extension)
+}
+
+/// External subtitles aren't part of the downloaded file, fetch them from the server as sidecar files
+async fn export_subtitles(
+    client: &JellyfinClient,
+    id: &str,
+    source_id: &str,
+    source: &serde_json::Value,
+    dir: &Path,
+    name: &str,
+) {
+    for (index, extension, stream) in external_subtitles(source) {
+        let mut file_name = name.to_string();
+        if let Some(language) = str_field(stream, "Language") {
+            file_name.push('.');
+            file_name.push_str(&sanitize(language));
+        }
+        if stream.get("IsForced").and_then(|f| f.as_bool()) == Some(true) {
+            file_name.push_str(".forced");
+        }
+
+        let result = match client.get_bytes(&subtitle_path(id, source_id, index, extension), &[]).await {
+            Ok(data) => tokio::fs::write(dir.join(format!("{}.{}", file_name, extension)), data)
+                .await
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+
+        if let Err(e) = result {
+            println!("export_subtitles: Failed for id {} stream {}: {}", id, index, e);
+        }
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn push_tag(nfo: &mut String, tag: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(nfo, "  <{}>{}</{}>", tag, escape_xml(&value.to_string()), tag);
+}
+
+/// Kodi `.nfo` for `root` (`movie`, `episodedetails` or `tvshow`) built from the stored Jellyfin item
+fn build_nfo(root: &str, item: &serde_json::Value, series: Option<&serde_json::Value>) -> String {
+    let mut nfo = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n");
+    let _ = writeln!(nfo, "<{}>", root);
+
+    if let Some(name) = str_field(item, "Name") {
+        push_tag(&mut nfo, "title", name);
+    }
+    if let Some(original_title) = str_field(item, "OriginalTitle") {
+        push_tag(&mut nfo, "originaltitle", original_title);
+    }
+    if let Some(sort_name) = str_field(item, "SortName") {
+        push_tag(&mut nfo, "sorttitle", sort_name);
+    }
+
+    if root == "episodedetails" {
+        if let Some(series_name) = str_field(item, "SeriesName") {
+            push_tag(&mut nfo, "showtitle", series_name);
+        }
+        if let Some(season) = int_field(item, "ParentIndexNumber") {
+            push_tag(&mut nfo, "season", season);
+        }
+        if let Some(episode) = int_field(item, "IndexNumber") {
+            push_tag(&mut nfo, "episode", episode);
+        }
+    }
+
+    if let Some(overview) = str_field(item, "Overview") {
+        push_tag(&mut nfo, "plot", overview);
+    }
+    if let Some(year) = int_field(item, "ProductionYear") {
+        push_tag(&mut nfo, "year", year);
+    }
+    if let Some(date) = str_field(item, "PremiereDate").and_then(|date| date.get(..10)) {
+        push_tag(&mut nfo, if root == "episodedetails" { "aired" } else { "premiered" }, date);
+    }
+    if let Some(rating) = item.get("CommunityRating").and_then(|r| r.as_f64()) {
+        push_tag(&mut nfo, "rating", rating);
+    }
+    if let Some(official_rating) = str_field(item, "OfficialRating") {
+        push_tag(&mut nfo, "mpaa", official_rating);
+    }
+    if let Some(ticks) = int_field(item, "RunTimeTicks") {
+        push_tag(&mut nfo, "runtime", ticks / 600_000_000);
+    }
+
+    // Episodes usually don't carry genres and studios themselves, take them from the show
+    let show_fields = series.unwrap_or(item);
+    for genre in show_fields.get("Genres").and_then(|g| g.as_array()).into_iter().flatten() {
+        if let Some(genre) = genre.as_str() {
+            push_tag(&mut nfo, "genre", genre);
+        }
+    }
+    for studio in show_fields.get("Studios").and_then(|s| s.as_array()).into_iter().flatten() {
+        if let Some(studio) = str_field(studio, "Name") {
+            push_tag(&mut nfo, "studio", studio);
+        }
+    }
+
+    if let Some(provider_ids) = item.get("ProviderIds").and_then(|p| p.as_object()) {
+        for (provider, value) in provider_ids {
+            if let Some(value) = value.as_str() {
+                let _ = writeln!(
+                    nfo,
+                    "  <uniqueid type=\"{}\">{}</uniqueid>",
+                    escape_xml(&provider.to_lowercase()),
+                    escape_xml(value)
+                );
+            }
+        }
+    }
+    if let Some(id) = str_field(item, "Id") {
+        let _ = writeln!(nfo, "  <uniqueid type=\"jellyfin\" default=\"true\">{}</uniqueid>", escape_xml(id));
+    }
+
+    for person in item.get("People").and_then(|p| p.as_array()).into_iter().flatten() {
+        let Some(name) = str_field(person, "Name") else {
+            continue;
+        };
+
+        match str_field(person, "Type") {
+            Some("Actor") | Some("GuestStar") => {
+                nfo.push_str("  <actor>\n");
+                let _ = writeln!(nfo, "    <name>{}</name>", escape_xml(name));
+                if let Some(role) = str_field(person, "Role") {
+                    let _ = writeln!(nfo, "    <role>{}</role>", escape_xml(role));
+                }
+                nfo.push_str("  </actor>\n");
+            }
+            Some("Director") => push_tag(&mut nfo, "director", name),
+            Some("Writer") => push_tag(&mut nfo, "credits", name),
+            _ => {}
+        }
+    }
+
+    let _ = writeln!(nfo, "</{}>", root);
+    nfo
+}
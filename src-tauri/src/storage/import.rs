@@ -0,0 +1,222 @@
+use super::crypto::{self, BlobReader, Key};
+use super::jellyfin::JellyfinClient;
+use super::sync::save_thumbnail;
+use super::versions::{has_version, version_path, StorageVersion};
+use super::{claim_download, get_storage_dir, mutate_metadata, now_millis, DownloadManager, StorageTrack};
+use serde::Deserialize;
+use std::io::SeekFrom;
+use std::path::Path;
+use tauri::{AppHandle, State};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+/// Bytes compared at the start and at the end of the file when verifying content
+const VERIFY_CHUNK_SIZE: u64 = 1024 * 1024;
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ImportOptions {
+    /// The server's first media source when not set
+    pub media_source_id: Option<String>,
+    pub container_id: Option<String>,
+    /// Also compare the first and last megabyte with the server's copy, not just the size
+    pub verify_content: bool,
+    /// Hard-link the file into the store instead of moving it
+    pub link: bool,
+}
+
+/// Adds a local copy of an item to the library without downloading it, e.g. a file copied from the NAS.
+/// The file has to match the size of the server's media source and is moved or linked into the store,
+/// a media source other than the stored one is added as another version.
+#[tauri::command]
+pub async fn storage_import(
+    app: AppHandle,
+    download_manager: State<'_, DownloadManager>,
+    path: String,
+    id: String,
+    options: ImportOptions,
+) -> Result<(), String> {
+    let ImportOptions {
+        media_source_id,
+        container_id,
+        verify_content,
+        link,
+    } = options;
+
+    let Some(auth) = download_manager.jellyfin_auth.lock().unwrap().clone() else {
+        return Err("Not connected to a server".to_string());
+    };
+
+    let client = JellyfinClient::new(auth);
+    let storage_dir = get_storage_dir(&app).map_err(|e| e.to_string())?;
+    let path = Path::new(&path);
+
+    let mut item = client
+        .get_items_by_ids(std::slice::from_ref(&id))
+        .await
+        .map_err(|e| e.to_string())?
+        .remove(&id)
+        .ok_or("Item not found on server")?;
+
+    let media_sources = item.as_object_mut().and_then(|obj| {
+        obj.insert("offlineState".to_string(), "downloaded".into());
+        obj.remove("MediaSources")
+    });
+    let source = media_sources
+        .as_ref()
+        .and_then(|sources| sources.as_array())
+        .and_then(|sources| match media_source_id.as_deref() {
+            Some(source_id) => sources
+                .iter()
+                .find(|source| source.get("Id").and_then(|s| s.as_str()) == Some(source_id)),
+            None => sources.first(),
+        })
+        .ok_or("Media source not found on server")?;
+    let source_id = source
+        .get("Id")
+        .and_then(|s| s.as_str())
+        .ok_or("Media source without id")?
+        .to_string();
+    let bitrate = source.get("Bitrate").and_then(|b| b.as_i64()).unwrap_or(0) as i32;
+
+    let size = tokio::fs::metadata(path).await.map_err(|e| e.to_string())?.len();
+    match source.get("Size").and_then(|s| s.as_u64()) {
+        Some(server_size) if server_size != size => {
+            return Err(format!("Size mismatch: server has {} bytes, file has {}", server_size, size));
+        }
+        Some(_) => {}
+        None => return Err("Server doesn't report a size for this media source".to_string()),
+    }
+
+    if verify_content {
+        verify_ranges(&client, &id, &source_id, path, size).await?;
+    }
+
+    // Claimed along with the check so a save or sync of the same item can't write to the target meanwhile
+    let (exists, _claim) = mutate_metadata(&app, &download_manager, |metadata| {
+        let existing = metadata.tracks.get(&id);
+        if existing.is_some_and(|track| has_version(&id, track, &source_id)) {
+            return Err(format!("Media source {} of {} is already stored", source_id, id));
+        }
+        let claim = claim_download(&download_manager, &id).ok_or(format!("{} is already being downloaded", id))?;
+        Ok((existing.is_some(), claim))
+    })
+    .await
+    .map_err(|e| e.to_string())??;
+
+    let target = match exists {
+        true => version_path(&storage_dir, &id, &source_id).map_err(|e| e.to_string())?,
+        false => storage_dir.join(format!("{}.blob", id)),
+    };
+    let key = crypto::encryption_key(&app).map_err(|e| e.to_string())?;
+    place_file(path, &target, link, key).await?;
+    println!("storage_import: Imported {:?} as {:?}", path, target);
+
+    let has_thumbnail = !exists && save_thumbnail(&app, &client, &id).await;
+
+    mutate_metadata(&app, &download_manager, |metadata| match metadata.tracks.get_mut(&id) {
+        Some(track) => {
+            track.versions.push(StorageVersion {
+                media_source_id: source_id,
+                timestamp: now_millis(),
+                bitrate,
+                size: Some(size),
+            });
+            track.add_reference(container_id.as_deref());
+        }
+        None => {
+            metadata.tracks.insert(
+                id,
+                StorageTrack {
+                    track_type: "video".to_string(),
+                    timestamp: now_millis(),
+                    media_item: item,
+                    bitrate,
+                    container_id,
+                    media_sources,
+                    media_source_id: Some(source_id),
+                    size: Some(size),
+                    server_status: None,
+                    references: Vec::new(),
+                    direct: false,
+                    versions: Vec::new(),
+                    has_thumbnail: Some(has_thumbnail),
+                },
+            );
+        }
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+/// Jellyfin doesn't expose file hashes, so the start and the end of the file are compared with the server's copy
+async fn verify_ranges(
+    client: &JellyfinClient,
+    id: &str,
+    source_id: &str,
+    path: &Path,
+    size: u64,
+) -> Result<(), String> {
+    let mut file = tokio::fs::File::open(path).await.map_err(|e| e.to_string())?;
+    let head_end = size.min(VERIFY_CHUNK_SIZE);
+    let tail_start = size.saturating_sub(VERIFY_CHUNK_SIZE).max(head_end);
+
+    for (start, end) in [(0, head_end), (tail_start, size)] {
+        if start >= end {
+            continue;
+        }
+
+        let remote = client
+            .get_stream_range(id, source_id, start, end - 1)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let mut local = vec![0; (end - start) as usize];
+        file.seek(SeekFrom::Start(start)).await.map_err(|e| e.to_string())?;
+        file.read_exact(&mut local).await.map_err(|e| e.to_string())?;
+
+        if remote != local {
+            return Err(format!("Content mismatch in bytes {}-{}", start, end - 1));
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves `path` into the store, falling back to copy and delete across file systems.
+/// With `key` the file is encrypted into the store instead, a linked original is left in place.
+async fn place_file(path: &Path, target: &Path, link: bool, key: Option<Key>) -> Result<(), String> {
+    if let Some(key) = key {
+        let (source, destination) = (path.to_path_buf(), target.to_path_buf());
+        tauri::async_runtime::spawn_blocking(move || {
+            BlobReader::open(&source, None).and_then(|reader| crypto::copy_blob(reader, &destination, Some(&key)))
+        })
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())?;
+
+        if !link {
+            if let Err(e) = tokio::fs::remove_file(path).await {
+                println!("place_file: Encrypted but failed to remove {:?}: {}", path, e);
+            }
+        }
+
+        return Ok(());
+    }
+
+    if link {
+        return tokio::fs::hard_link(path, target).await.map_err(|e| e.to_string());
+    }
+
+    if tokio::fs::rename(path, target).await.is_ok() {
+        return Ok(());
+    }
+
+    tokio::fs::copy(path, target).await.map_err(|e| e.to_string())?;
+    if let Err(e) = tokio::fs::remove_file(path).await {
+        println!("place_file: Copied but failed to remove {:?}: {}", path, e);
+    }
+
+    Ok(())
+}
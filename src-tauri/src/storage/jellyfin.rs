@@ -0,0 +1,210 @@
+use super::DownloadManager;
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, State};
+
+/// Credentials handed over by the frontend after login, mirrors `IJellyfinAuth`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct JellyfinAuth {
+    pub server_url: String,
+    pub user_id: String,
+    pub token: String,
+}
+
+/// Errors from talking to the server, split so background tasks can tell
+/// "server unreachable, retry later" apart from "server rejected this request"
+#[derive(Debug)]
+pub enum JellyfinError {
+    Unreachable(String),
+    Status(reqwest::StatusCode),
+    Invalid(String),
+    Storage(String),
+}
+
+impl From<tauri::Error> for JellyfinError {
+    fn from(e: tauri::Error) -> Self {
+        JellyfinError::Storage(e.to_string())
+    }
+}
+
+impl std::fmt::Display for JellyfinError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JellyfinError::Unreachable(e) => write!(f, "Server unreachable: {}", e),
+            JellyfinError::Status(status) => write!(f, "Server responded with HTTP {}", status),
+            JellyfinError::Invalid(e) => write!(f, "Invalid server response: {}", e),
+            JellyfinError::Storage(e) => write!(f, "Storage error: {}", e),
+        }
+    }
+}
+
+pub struct JellyfinClient {
+    client: reqwest::Client,
+    auth: JellyfinAuth,
+}
+
+impl JellyfinClient {
+    pub fn new(auth: JellyfinAuth) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(std::time::Duration::from_secs(20))
+                .build()
+                .unwrap_or_default(),
+            auth,
+        }
+    }
+
+    pub fn user_id(&self) -> &str {
+        &self.auth.user_id
+    }
+
+    /// Direct (non transcoded) stream of a media source, same as the frontend's `getStreamUrl`
+    pub fn stream_url(&self, id: &str, media_source_id: &str) -> String {
+        format!(
+            "{}?MediaSourceId={}&UserId={}&api_key={}&static=true",
+            self.url(&format!("Videos/{}/stream", id)),
+            media_source_id,
+            self.auth.user_id,
+            self.auth.token
+        )
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.auth.server_url.trim_end_matches('/'), path.trim_start_matches('/'))
+    }
+
+    async fn send(&self, request: reqwest::RequestBuilder) -> Result<reqwest::Response, JellyfinError> {
+        let response = request
+            .header("X-Emby-Token", &self.auth.token)
+            .send()
+            .await
+            .map_err(|e| JellyfinError::Unreachable(e.to_string()))?;
+
+        if !response.status().is_success() {
+            return Err(JellyfinError::Status(response.status()));
+        }
+
+        Ok(response)
+    }
+
+    pub async fn get_json(&self, path: &str, query: &[(&str, &str)]) -> Result<serde_json::Value, JellyfinError> {
+        let response = self.send(self.client.get(self.url(path)).query(query)).await?;
+        response.json().await.map_err(|e| JellyfinError::Invalid(e.to_string()))
+    }
+
+    pub async fn post_json(&self, path: &str, body: &serde_json::Value) -> Result<(), JellyfinError> {
+        self.send(self.client.post(self.url(path)).json(body)).await?;
+        Ok(())
+    }
+
+    pub async fn post(&self, path: &str, query: &[(&str, &str)]) -> Result<(), JellyfinError> {
+        self.send(self.client.post(self.url(path)).query(query)).await?;
+        Ok(())
+    }
+}
+
+/// Fields requested when re-fetching stored items, a superset of what the frontend asks for
+pub const ITEM_FIELDS: &str = "MediaSources,MediaStreams,Chapters,Trickplay,Overview,Genres,People,OriginalTitle,SortName,ProviderIds";
+
+impl JellyfinClient {
+    /// Fetches items by id, ids missing from the result no longer exist on the server
+    pub async fn get_items_by_ids(
+        &self,
+        ids: &[String],
+    ) -> Result<std::collections::HashMap<String, serde_json::Value>, JellyfinError> {
+        let ids = ids.join(",");
+        let response = self
+            .get_json(
+                "Items",
+                &[("userId", self.user_id()), ("ids", &ids), ("fields", ITEM_FIELDS)],
+            )
+            .await?;
+
+        Ok(response
+            .get("Items")
+            .and_then(|items| items.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|item| Some((item.get("Id")?.as_str()?.to_string(), item.clone())))
+            .collect())
+    }
+
+    /// Primary image at the size the frontend uses for download thumbnails
+    pub async fn get_thumbnail(&self, id: &str) -> Result<Vec<u8>, JellyfinError> {
+        self.get_bytes(
+            &format!("Items/{}/Images/Primary", id),
+            &[("fillWidth", "360"), ("fillHeight", "360"), ("quality", "100"), ("format", "webp")],
+        )
+        .await
+    }
+
+    pub async fn get_bytes(&self, path: &str, query: &[(&str, &str)]) -> Result<Vec<u8>, JellyfinError> {
+        let response = self.send(self.client.get(self.url(path)).query(query)).await?;
+        let bytes = response
+            .bytes()
+            .await
+            .map_err(|e| JellyfinError::Unreachable(e.to_string()))?;
+        Ok(bytes.to_vec())
+    }
+
+    /// Bytes `start..=end` of the original file of a media source
+    pub async fn get_stream_range(
+        &self,
+        id: &str,
+        media_source_id: &str,
+        start: u64,
+        end: u64,
+    ) -> Result<Vec<u8>, JellyfinError> {
+        let request = self
+            .client
+            .get(self.url(&format!("Videos/{}/stream", id)))
+            .query(&[("MediaSourceId", media_source_id), ("static", "true")])
+            .header(reqwest::header::RANGE, format!("bytes={}-{}", start, end));
+        let response = self.send(request).await?;
+
+        // A plain 200 would be the whole file, not what we asked for
+        if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+            return Err(JellyfinError::Invalid("Server ignored the range request".to_string()));
+        }
+
+        let bytes = response
+            .bytes()
+            .await
+            .map_err(|e| JellyfinError::Unreachable(e.to_string()))?;
+        Ok(bytes.to_vec())
+    }
+}
+
+/// Parses Jellyfin's ISO dates (`2024-05-01T12:34:56.1234567Z`) into unix millis
+pub fn parse_date(date: &str) -> Option<i64> {
+    chrono::DateTime::parse_from_rfc3339(date)
+        .ok()
+        .map(|d| d.timestamp_millis())
+}
+
+pub fn format_date(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp_millis(timestamp)
+        .unwrap_or_default()
+        .to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
+
+#[tauri::command]
+pub async fn storage_set_server(
+    app: AppHandle,
+    download_manager: State<'_, DownloadManager>,
+    auth: Option<JellyfinAuth>,
+) -> Result<(), String> {
+    let connected = auth.is_some();
+    *download_manager.jellyfin_auth.lock().unwrap() = auth;
+
+    // A new session is usually the first moment the server is reachable again
+    if connected {
+        tauri::async_runtime::spawn(async move {
+            if let Err(e) = super::playback::sync_pending_playback(&app).await {
+                println!("storage_set_server: Playback sync failed: {}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
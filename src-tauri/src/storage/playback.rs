@@ -0,0 +1,482 @@
+use super::jellyfin::{format_date, parse_date, JellyfinClient, JellyfinError};
+use super::{crypto, get_storage_dir, now_millis, run_blocking, DownloadManager};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use tauri::{AppHandle, Manager};
+
+const SYNC_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum PlaybackEventKind {
+    Start,
+    Progress,
+    Stop,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaybackEvent {
+    pub kind: PlaybackEventKind,
+    pub position_ticks: i64,
+    #[serde(default)]
+    pub is_paused: bool,
+    /// Only meaningful on `Stop`, marks the item as watched on the server
+    #[serde(default)]
+    pub played: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media_source_id: Option<String>,
+    /// Set on our side when the event is recorded
+    #[serde(default)]
+    pub timestamp: i64,
+}
+
+/// Local resume state for a downloaded item, the `media_item` snapshot goes stale as soon as we watch offline
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct OfflineUserData {
+    pub playback_position_ticks: i64,
+    pub played: bool,
+    pub play_count: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_played_date: Option<i64>,
+}
+
+/// Playback events recorded while offline, waiting to be replayed to the server
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct PlaybackStore {
+    pub pending: HashMap<String, Vec<PlaybackEvent>>,
+    #[serde(default)]
+    pub user_data: HashMap<String, OfflineUserData>,
+}
+
+fn load_playback_store(app: &AppHandle) -> tauri::Result<PlaybackStore> {
+    let download_manager = app.state::<DownloadManager>();
+    // Keeps a write in progress from being read half done
+    let _write = download_manager.playback_write_lock.lock().unwrap();
+    let path = get_storage_dir(app)?.join("playback.json");
+
+    if !path.exists() {
+        return Ok(PlaybackStore::default());
+    }
+
+    let content = crypto::read_file(app, &path)?;
+    serde_json::from_slice(&content)
+        .map_err(|e| tauri::Error::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, e)))
+}
+
+fn save_playback_store(app: &AppHandle, store: &PlaybackStore) -> tauri::Result<()> {
+    let path = get_storage_dir(app)?.join("playback.json");
+    let content = serde_json::to_string(store)
+        .map_err(|e| tauri::Error::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, e)))?;
+    crypto::write_file(app, &path, content.as_bytes())?;
+    Ok(())
+}
+
+/// Fills the cache at startup, it's read from the blocking pool there already
+pub(super) fn preload_playback_store(app: &AppHandle) -> tauri::Result<()> {
+    let download_manager = app.state::<DownloadManager>();
+    if download_manager.cached_playback.lock().unwrap().is_none() {
+        let store = load_playback_store(app)?;
+        download_manager.cached_playback.lock().unwrap().get_or_insert(store);
+    }
+    Ok(())
+}
+
+/// Reads the store for a cold cache on the blocking pool, the cache lock isn't held meanwhile
+async fn load_playback_blocking(app: &AppHandle) -> tauri::Result<PlaybackStore> {
+    let app = app.clone();
+    run_blocking(move || load_playback_store(&app)).await
+}
+
+/// Runs `f` against the cached store, loading a cold cache first
+async fn read_playback_store<R>(
+    app: &AppHandle,
+    download_manager: &DownloadManager,
+    f: impl FnOnce(&PlaybackStore) -> R,
+) -> tauri::Result<R> {
+    if let Some(store) = download_manager.cached_playback.lock().unwrap().as_ref() {
+        return Ok(f(store));
+    }
+
+    let store = load_playback_blocking(app).await?;
+    Ok(f(download_manager.cached_playback.lock().unwrap().get_or_insert(store)))
+}
+
+/// Runs `f` against the cached store and persists the result on the blocking pool.
+/// Writes are serialised and each one saves the store as cached at that point.
+pub(super) async fn update_playback_store<R>(
+    app: &AppHandle,
+    download_manager: &DownloadManager,
+    f: impl FnOnce(&mut PlaybackStore) -> R,
+) -> tauri::Result<R> {
+    let result = loop {
+        {
+            let mut cache = download_manager.cached_playback.lock().unwrap();
+            if let Some(store) = cache.as_mut() {
+                break f(store);
+            }
+        }
+        let store = load_playback_blocking(app).await?;
+        download_manager.cached_playback.lock().unwrap().get_or_insert(store);
+    };
+
+    let app = app.clone();
+    run_blocking(move || {
+        let download_manager = app.state::<DownloadManager>();
+        let _write = download_manager.playback_write_lock.lock().unwrap();
+        let store = download_manager.cached_playback.lock().unwrap().clone();
+        match store {
+            Some(store) => save_playback_store(&app, &store),
+            // Cleared in the meantime
+            None => Ok(()),
+        }
+    })
+    .await?;
+
+    Ok(result)
+}
+
+async fn get_pending_snapshot(
+    app: &AppHandle,
+    download_manager: &DownloadManager,
+) -> tauri::Result<HashMap<String, Vec<PlaybackEvent>>> {
+    read_playback_store(app, download_manager, |store| store.pending.clone()).await
+}
+
+pub(super) async fn get_store_snapshot(
+    app: &AppHandle,
+    download_manager: &DownloadManager,
+) -> tauri::Result<PlaybackStore> {
+    read_playback_store(app, download_manager, PlaybackStore::clone).await
+}
+
+/// Offline user data of every item, used to patch `media_item` snapshots before returning them
+pub(super) async fn get_user_data_snapshot(
+    app: &AppHandle,
+    download_manager: &DownloadManager,
+) -> tauri::Result<HashMap<String, OfflineUserData>> {
+    read_playback_store(app, download_manager, |store| store.user_data.clone()).await
+}
+
+/// Played state `apply_user_data` would leave in the item, without cloning it
+pub fn is_played(media_item: &serde_json::Value, user_data: Option<&OfflineUserData>) -> bool {
+    let snapshot_played = media_item
+        .pointer("/UserData/Played")
+        .and_then(|p| p.as_bool())
+        .unwrap_or(false);
+
+    let Some((local_last_played, local_played)) =
+        user_data.and_then(|data| Some((data.last_played_date?, data.played)))
+    else {
+        return snapshot_played;
+    };
+
+    let snapshot_last_played = media_item
+        .pointer("/UserData/LastPlayedDate")
+        .and_then(|d| d.as_str())
+        .and_then(parse_date);
+
+    if snapshot_last_played.is_some_and(|snapshot| snapshot > local_last_played) {
+        snapshot_played
+    } else {
+        local_played
+    }
+}
+
+/// Overrides `UserData` in the item unless the snapshot was taken after our last local playback
+pub fn apply_user_data(media_item: &mut serde_json::Value, user_data: &OfflineUserData) {
+    let Some(local_last_played) = user_data.last_played_date else {
+        return;
+    };
+
+    let snapshot_last_played = media_item
+        .pointer("/UserData/LastPlayedDate")
+        .and_then(|d| d.as_str())
+        .and_then(parse_date);
+
+    if snapshot_last_played.is_some_and(|snapshot| snapshot > local_last_played) {
+        return;
+    }
+
+    let run_time_ticks = media_item.get("RunTimeTicks").and_then(|t| t.as_i64());
+
+    let Some(obj) = media_item.as_object_mut() else {
+        return;
+    };
+
+    let entry = obj
+        .entry("UserData")
+        .or_insert_with(|| serde_json::Value::Object(Default::default()));
+
+    if let Some(data) = entry.as_object_mut() {
+        data.insert("PlaybackPositionTicks".to_string(), user_data.playback_position_ticks.into());
+        data.insert("Played".to_string(), user_data.played.into());
+        data.insert("PlayCount".to_string(), user_data.play_count.into());
+        data.insert("LastPlayedDate".to_string(), format_date(local_last_played).into());
+
+        match run_time_ticks {
+            Some(run_time) if run_time > 0 && user_data.playback_position_ticks > 0 => {
+                let percentage = user_data.playback_position_ticks as f64 / run_time as f64 * 100.0;
+                data.insert("PlayedPercentage".to_string(), percentage.into());
+            }
+            _ => {
+                data.remove("PlayedPercentage");
+            }
+        }
+    }
+}
+
+fn update_user_data(store: &mut PlaybackStore, id: &str, event: &PlaybackEvent) {
+    let user_data = store.user_data.entry(id.to_string()).or_default();
+    user_data.last_played_date = Some(event.timestamp);
+
+    match event.kind {
+        PlaybackEventKind::Start => {}
+        PlaybackEventKind::Progress => {
+            user_data.playback_position_ticks = event.position_ticks;
+        }
+        PlaybackEventKind::Stop if event.played => {
+            user_data.playback_position_ticks = 0;
+            user_data.played = true;
+            user_data.play_count += 1;
+        }
+        // Latest timestamp wins, stopping part way through a rewatch leaves it unplayed like the server does
+        PlaybackEventKind::Stop => {
+            user_data.playback_position_ticks = event.position_ticks;
+            user_data.played = false;
+        }
+    }
+}
+
+/// Queues `event` for the server and applies it to the item's offline user data
+pub fn record_event(store: &mut PlaybackStore, id: &str, event: PlaybackEvent) {
+    update_user_data(store, id, &event);
+
+    let events = store.pending.entry(id.to_string()).or_default();
+
+    // Only the latest progress report matters, keep the queue short
+    if event.kind == PlaybackEventKind::Progress {
+        if let Some(last) = events.last_mut() {
+            if last.kind == PlaybackEventKind::Progress {
+                *last = event;
+                return;
+            }
+        }
+    }
+
+    events.push(event);
+}
+
+#[tauri::command]
+pub async fn storage_report_playback(
+    app: AppHandle,
+    id: String,
+    event: PlaybackEvent,
+) -> Result<(), String> {
+    let event = PlaybackEvent {
+        timestamp: now_millis(),
+        ..event
+    };
+
+    let download_manager = app.state::<DownloadManager>();
+    // Progress also counts, in case the start was missed
+    match event.kind {
+        PlaybackEventKind::Stop => download_manager.playing.lock().unwrap().remove(&id),
+        _ => download_manager.playing.lock().unwrap().insert(id.clone()),
+    };
+
+    update_playback_store(&app, &download_manager, |store| record_event(store, &id, event))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn storage_sync_playback(app: AppHandle) -> Result<usize, String> {
+    sync_pending_playback(&app).await
+}
+
+/// Replays pending events to the server, returns the number of items synced.
+/// Stops at the first connection error so the remaining events are kept for the next attempt.
+pub async fn sync_pending_playback(app: &AppHandle) -> Result<usize, String> {
+    let download_manager = app.state::<DownloadManager>();
+
+    let Some(auth) = download_manager.jellyfin_auth.lock().unwrap().clone() else {
+        return Err("Not connected to a server".to_string());
+    };
+
+    if download_manager.syncing_playback.swap(true, Ordering::SeqCst) {
+        return Ok(0);
+    }
+
+    let result = async {
+        let pending = get_pending_snapshot(app, &download_manager).await.map_err(|e| e.to_string())?;
+        let client = JellyfinClient::new(auth);
+        let mut synced = 0;
+
+        for (id, events) in pending {
+            let mut posted = 0;
+            let result = sync_item(&client, &id, &events, &mut posted).await;
+            let unreachable = matches!(result, Err(JellyfinError::Unreachable(_)));
+
+            // Events already posted are dropped even when a later one failed, a retry would post them twice
+            let done = match result {
+                Ok(()) => {
+                    synced += 1;
+                    events.len()
+                }
+                Err(JellyfinError::Unreachable(e)) => {
+                    println!("sync_pending_playback: Server unreachable, will retry: {}", e);
+                    posted
+                }
+                Err(JellyfinError::Status(status)) if status.is_server_error() => {
+                    println!("sync_pending_playback: Failed for id {}: HTTP {}, will retry", id, status);
+                    posted
+                }
+                Err(e) => {
+                    println!("sync_pending_playback: Dropping events for id {}: {}", id, e);
+                    events.len()
+                }
+            };
+
+            if let Some(synced_until) = events[..done].last().map(|e| e.timestamp) {
+                // Events recorded while we were syncing stay queued
+                update_playback_store(app, &download_manager, |store| {
+                    if let Some(events) = store.pending.get_mut(&id) {
+                        events.retain(|e| e.timestamp > synced_until);
+                        if events.is_empty() {
+                            store.pending.remove(&id);
+                        }
+                    }
+                })
+                .await
+                .map_err(|e| e.to_string())?;
+            }
+
+            if unreachable {
+                break;
+            }
+        }
+
+        Ok(synced)
+    }
+    .await;
+
+    download_manager.syncing_playback.store(false, Ordering::SeqCst);
+    result
+}
+
+/// Replays the events of one item. `posted` counts the leading events the server accepted, also when a later one
+/// fails.
+async fn sync_item(
+    client: &JellyfinClient,
+    id: &str,
+    events: &[PlaybackEvent],
+    posted: &mut usize,
+) -> Result<(), JellyfinError> {
+    let item = match client.get_json(&format!("Items/{}", id), &[("userId", client.user_id())]).await {
+        Ok(item) => item,
+        Err(JellyfinError::Status(status)) if status == reqwest::StatusCode::NOT_FOUND => {
+            println!("sync_item: Item {} no longer exists on the server", id);
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+
+    // Latest timestamp wins, if the item was played elsewhere after our last event we drop ours
+    let server_last_played = item
+        .pointer("/UserData/LastPlayedDate")
+        .and_then(|d| d.as_str())
+        .and_then(parse_date);
+    let local_last_played = events.last().map(|e| e.timestamp).unwrap_or_default();
+
+    if server_last_played.is_some_and(|server| server >= local_last_played) {
+        println!("sync_item: Server state for {} is newer, skipping offline events", id);
+        return Ok(());
+    }
+
+    let mut in_session = false;
+    let mut last_event: Option<&PlaybackEvent> = None;
+
+    for (index, event) in events.iter().enumerate() {
+        if !in_session && event.kind != PlaybackEventKind::Start {
+            client.post_json("Sessions/Playing", &session_body(id, event)).await?;
+        }
+
+        match event.kind {
+            PlaybackEventKind::Start => {
+                client.post_json("Sessions/Playing", &session_body(id, event)).await?;
+                in_session = true;
+            }
+            PlaybackEventKind::Progress => {
+                client.post_json("Sessions/Playing/Progress", &session_body(id, event)).await?;
+                in_session = true;
+            }
+            PlaybackEventKind::Stop => {
+                client.post_json("Sessions/Playing/Stopped", &session_body(id, event)).await?;
+                in_session = false;
+
+                if event.played {
+                    let date_played = format_date(event.timestamp);
+                    client
+                        .post(
+                            &format!("UserPlayedItems/{}", id),
+                            &[("userId", client.user_id()), ("datePlayed", &date_played)],
+                        )
+                        .await?;
+                }
+            }
+        }
+
+        last_event = Some(event);
+        // The last one also needs its session closed below
+        if index + 1 < events.len() {
+            *posted = index + 1;
+        }
+    }
+
+    // The app was closed without a stop event, close the session at the last known position
+    if let (true, Some(event)) = (in_session, last_event) {
+        client.post_json("Sessions/Playing/Stopped", &session_body(id, event)).await?;
+    }
+
+    *posted = events.len();
+    Ok(())
+}
+
+fn session_body(id: &str, event: &PlaybackEvent) -> serde_json::Value {
+    serde_json::json!({
+        "ItemId": id,
+        "MediaSourceId": event.media_source_id.as_deref().unwrap_or(id),
+        "PositionTicks": event.position_ticks,
+        "IsPaused": event.is_paused,
+        "PlayMethod": "DirectPlay",
+        "CanSeek": true
+    })
+}
+
+/// Periodically retries the sync so events recorded offline reach the server once it's reachable again
+pub fn spawn_playback_sync(app: AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        loop {
+            tokio::time::sleep(SYNC_INTERVAL).await;
+
+            let download_manager = app.state::<DownloadManager>();
+            if download_manager.jellyfin_auth.lock().unwrap().is_none() {
+                continue;
+            }
+
+            let has_pending = get_pending_snapshot(&app, &download_manager)
+                .await
+                .map(|pending| !pending.is_empty())
+                .unwrap_or(false);
+
+            if has_pending {
+                if let Err(e) = sync_pending_playback(&app).await {
+                    println!("spawn_playback_sync: {}", e);
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,234 @@
+//! Download progress. Each `storage_save_track` call streams its own progress through the channel it was
+//! given, every window gets a throttled summary of the whole queue as `download-queue-progress`.
+
+use super::DownloadManager;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+use tauri::ipc::Channel;
+use tauri::{AppHandle, Emitter, Manager};
+
+pub const QUEUE_PROGRESS: &str = "download-queue-progress";
+
+/// How often a running download reports, state changes are reported right away
+const REPORT_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum DownloadState {
+    /// Between two downloads of the queue
+    Queued,
+    Connecting,
+    Downloading,
+    /// Downloaded, the thumbnail and catalog entry are being saved
+    Verifying,
+    /// The connection dropped, waiting before the next attempt
+    Retrying,
+    #[default]
+    Done,
+    Failed,
+}
+
+/// Sent through the channel of a `storage_save_track` call
+#[derive(Debug, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadProgress {
+    pub id: String,
+    pub state: DownloadState,
+    pub downloaded: u64,
+    /// `None` when the server didn't send a `Content-Length`
+    pub total: Option<u64>,
+    /// Percentage with its fraction, `None` while the size is unknown
+    pub progress: Option<f64>,
+    /// Bytes per second since the previous report
+    pub speed: f64,
+    /// Seconds, `None` while the size or speed is unknown
+    pub time_remaining: Option<f64>,
+    pub attempt: u32,
+    pub error: Option<String>,
+}
+
+/// Turns the byte counts of one download attempt into throttled reports
+pub struct ProgressMeter {
+    id: String,
+    attempt: u32,
+    state: DownloadState,
+    last_report: Instant,
+    last_downloaded: u64,
+    downloaded: u64,
+    total: Option<u64>,
+    speed: f64,
+}
+
+impl ProgressMeter {
+    pub fn new(id: &str, attempt: u32, now: Instant) -> Self {
+        Self {
+            id: id.to_string(),
+            attempt,
+            state: DownloadState::Connecting,
+            last_report: now,
+            last_downloaded: 0,
+            downloaded: 0,
+            total: None,
+            speed: 0.0,
+        }
+    }
+
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded
+    }
+
+    /// Starts over for the next attempt, the partial file of the last one is gone
+    pub fn retry(&mut self, now: Instant) {
+        *self = Self::new(&self.id, self.attempt + 1, now);
+    }
+
+    /// A report after `downloaded` of `total` bytes, `total` is 0 when unknown.
+    /// `None` when the previous one was less than `REPORT_INTERVAL` ago, except for the first bytes.
+    pub fn sample(&mut self, downloaded: u64, total: u64, now: Instant) -> Option<DownloadProgress> {
+        self.downloaded = downloaded;
+        self.total = (total > 0).then_some(total);
+
+        let elapsed = now.duration_since(self.last_report);
+        if self.state == DownloadState::Downloading && elapsed < REPORT_INTERVAL {
+            return None;
+        }
+
+        if !elapsed.is_zero() {
+            self.speed = downloaded.saturating_sub(self.last_downloaded) as f64 / elapsed.as_secs_f64();
+        }
+        self.state = DownloadState::Downloading;
+        self.last_report = now;
+        self.last_downloaded = downloaded;
+
+        Some(self.report(DownloadState::Downloading, None))
+    }
+
+    /// A report of `state` with the counts of the last sample
+    pub fn report(&self, state: DownloadState, error: Option<String>) -> DownloadProgress {
+        let progress = self.total.map(|total| (self.downloaded as f64 / total as f64 * 100.0).min(100.0));
+        let time_remaining = self
+            .total
+            .filter(|_| state == DownloadState::Downloading && self.speed > 0.0)
+            .map(|total| total.saturating_sub(self.downloaded) as f64 / self.speed);
+
+        DownloadProgress {
+            id: self.id.clone(),
+            state,
+            downloaded: self.downloaded,
+            total: self.total,
+            progress,
+            speed: if state == DownloadState::Downloading { self.speed } else { 0.0 },
+            time_remaining,
+            attempt: self.attempt,
+            error,
+        }
+    }
+}
+
+/// Payload of `download-queue-progress`
+#[derive(Debug, Serialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueProgress {
+    /// Of the current download, `queued` between downloads and `done` once the queue is empty
+    pub state: DownloadState,
+    pub current_id: Option<String>,
+    /// Downloads waiting behind the current one, as counted by the frontend
+    pub queued: usize,
+    pub done: usize,
+    pub failed: usize,
+    /// Bytes downloaded since the queue last ran empty, the current download included
+    pub downloaded: u64,
+    /// Of the current download
+    pub progress: Option<f64>,
+    pub speed: f64,
+}
+
+/// Summary of the downloads since the queue last ran empty
+#[derive(Debug, Default)]
+pub struct DownloadQueue {
+    summary: QueueProgress,
+    finished_bytes: u64,
+}
+
+impl DownloadQueue {
+    pub fn summary(&self) -> &QueueProgress {
+        &self.summary
+    }
+
+    /// A download starts with `queued` more waiting behind it
+    pub fn start(&mut self, id: &str, queued: usize) {
+        // A new batch once the previous one ran empty
+        if self.summary.current_id.is_none() && self.summary.queued == 0 {
+            *self = Self::default();
+        }
+
+        self.summary.state = DownloadState::Connecting;
+        self.summary.current_id = Some(id.to_string());
+        self.summary.queued = queued;
+        self.summary.progress = None;
+        self.summary.speed = 0.0;
+    }
+
+    pub fn update(&mut self, progress: &DownloadProgress) {
+        self.summary.state = progress.state;
+        self.summary.downloaded = self.finished_bytes + progress.downloaded;
+        self.summary.progress = progress.progress;
+        self.summary.speed = progress.speed;
+    }
+
+    /// The current download ended with `state`, `None` when it was cancelled. Cancelled downloads were taken
+    /// off the queue and aren't counted.
+    pub fn finish(&mut self, state: Option<DownloadState>, downloaded: u64) {
+        match state {
+            Some(DownloadState::Done) => self.summary.done += 1,
+            Some(DownloadState::Failed) => self.summary.failed += 1,
+            _ => {}
+        }
+
+        self.finished_bytes += downloaded;
+        self.summary.downloaded = self.finished_bytes;
+        self.summary.state = if self.summary.queued > 0 { DownloadState::Queued } else { DownloadState::Done };
+        self.summary.current_id = None;
+        self.summary.progress = None;
+        self.summary.speed = 0.0;
+    }
+}
+
+/// Sends `progress` to the caller and updates the queue summary every window gets
+pub fn report(app: &AppHandle, channel: Option<&Channel<DownloadProgress>>, progress: DownloadProgress) {
+    let download_manager = app.state::<DownloadManager>();
+    let summary = {
+        let mut queue = download_manager.download_queue.lock().unwrap();
+        queue.update(&progress);
+        queue.summary().clone()
+    };
+
+    if let Some(channel) = channel {
+        let _ = channel.send(progress);
+    }
+    let _ = app.emit(QUEUE_PROGRESS, summary);
+}
+
+pub fn start(app: &AppHandle, id: &str, queued: usize) {
+    let download_manager = app.state::<DownloadManager>();
+    let summary = {
+        let mut queue = download_manager.download_queue.lock().unwrap();
+        queue.start(id, queued);
+        queue.summary().clone()
+    };
+    let _ = app.emit(QUEUE_PROGRESS, summary);
+}
+
+pub fn finish(app: &AppHandle, state: Option<DownloadState>, downloaded: u64) {
+    let download_manager = app.state::<DownloadManager>();
+    let summary = {
+        let mut queue = download_manager.download_queue.lock().unwrap();
+        queue.finish(state, downloaded);
+        queue.summary().clone()
+    };
+    let _ = app.emit(QUEUE_PROGRESS, summary);
+}
@@ -0,0 +1,404 @@
+use super::playback::{self, OfflineUserData};
+use super::{tree, versions, StorageMetadata, StorageTrack};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+/// Sort fields for offline pages, named like Jellyfin's `ItemSortBy` so the frontend can pass those through
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum SortBy {
+    /// When the item was downloaded
+    #[default]
+    DateCreated,
+    #[serde(alias = "Name")]
+    SortName,
+    PremiereDate,
+    /// Series, then season and episode number
+    #[serde(alias = "ParentIndexNumber")]
+    IndexNumber,
+    Runtime,
+    CommunityRating,
+    /// Size of all downloaded versions
+    Size,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum SortOrder {
+    Ascending,
+    Descending,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Sort {
+    #[serde(default)]
+    pub sort_by: SortBy,
+    /// Defaults to the natural order of `sort_by`
+    pub sort_order: Option<SortOrder>,
+}
+
+impl SortBy {
+    /// Newest, biggest and best rated first, names and episodes in reading order
+    pub fn default_order(self) -> SortOrder {
+        match self {
+            SortBy::SortName | SortBy::IndexNumber | SortBy::Runtime => SortOrder::Ascending,
+            _ => SortOrder::Descending,
+        }
+    }
+}
+
+/// Value an item is sorted on. All items of one sort share the same variant.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SortValue {
+    Number(i64),
+    Text(String),
+    Episode(String, i64, i64),
+}
+
+fn item_str<'a>(item: &'a serde_json::Value, field: &str) -> Option<&'a str> {
+    item.get(field).and_then(|v| v.as_str()).filter(|s| !s.is_empty())
+}
+
+fn sort_name(item: &serde_json::Value) -> Option<String> {
+    item_str(item, "SortName")
+        .or_else(|| item_str(item, "Name"))
+        .map(str::to_lowercase)
+}
+
+/// `None` when the item lacks the field, those items always go last
+pub fn sort_value(track: &StorageTrack, sort_by: SortBy) -> Option<SortValue> {
+    let item = &track.media_item;
+
+    match sort_by {
+        SortBy::DateCreated => Some(SortValue::Number(track.timestamp)),
+        SortBy::SortName => sort_name(item).map(SortValue::Text),
+        SortBy::PremiereDate => item_str(item, "PremiereDate").map(|date| SortValue::Text(date.to_string())),
+        SortBy::IndexNumber => {
+            let series = item_str(item, "SeriesName").map(str::to_lowercase).or_else(|| sort_name(item))?;
+            let season = item.get("ParentIndexNumber").and_then(|n| n.as_i64()).unwrap_or(i64::MAX);
+            let episode = item.get("IndexNumber").and_then(|n| n.as_i64()).unwrap_or(i64::MAX);
+            Some(SortValue::Episode(series, season, episode))
+        }
+        SortBy::Runtime => item.get("RunTimeTicks").and_then(|t| t.as_i64()).map(SortValue::Number),
+        // Hundredths are as precise as Jellyfin's ratings get
+        SortBy::CommunityRating => item
+            .get("CommunityRating")
+            .and_then(|r| r.as_f64())
+            .map(|rating| SortValue::Number((rating * 100.0).round() as i64)),
+        SortBy::Size => track.size.map(|size| {
+            let versions: u64 = track.versions.iter().filter_map(|version| version.size).sum();
+            SortValue::Number((size + versions) as i64)
+        }),
+    }
+}
+
+/// Where an item sits in a sorted list, handed to the frontend as the cursor of the next page.
+/// It holds the sort key rather than an offset so items added or removed mid-scroll don't shift later pages.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SortPosition {
+    sort_by: SortBy,
+    value: Option<SortValue>,
+    timestamp: i64,
+    id: String,
+}
+
+impl SortPosition {
+    pub fn of(id: &str, track: &StorageTrack, sort_by: SortBy) -> Self {
+        SortPosition {
+            sort_by,
+            value: sort_value(track, sort_by),
+            timestamp: track.timestamp,
+            id: id.to_string(),
+        }
+    }
+
+    /// Orders by `value` in `order` with missing values last, ties broken by download time and id
+    /// so the order is the same on every call and pages never overlap
+    fn compare(&self, other: &SortPosition, order: SortOrder) -> Ordering {
+        let by_value = match (&self.value, &other.value) {
+            (Some(a), Some(b)) if order == SortOrder::Ascending => a.cmp(b),
+            (Some(a), Some(b)) => b.cmp(a),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        };
+
+        by_value
+            .then_with(|| other.timestamp.cmp(&self.timestamp))
+            .then_with(|| self.id.cmp(&other.id))
+    }
+
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    pub fn decode(cursor: &str) -> Result<Self, String> {
+        serde_json::from_str(cursor).map_err(|_| "Invalid page cursor".to_string())
+    }
+}
+
+fn sorted_positions<'a>(
+    tracks: Vec<(&'a String, &'a StorageTrack)>,
+    sort_by: SortBy,
+    order: SortOrder,
+) -> Vec<(SortPosition, &'a String, &'a StorageTrack)> {
+    let mut keyed: Vec<_> = tracks
+        .into_iter()
+        .map(|(id, track)| (SortPosition::of(id, track, sort_by), id, track))
+        .collect();
+    keyed.sort_by(|a, b| a.0.compare(&b.0, order));
+    keyed
+}
+
+/// Page returned to the frontend, `next_cursor` is passed back to get the page after it
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Page {
+    pub items: Vec<serde_json::Value>,
+    pub total_count: usize,
+    pub next_cursor: Option<String>,
+}
+
+/// One page of a sorted list, `next_cursor` is `None` on the last page
+pub struct TrackPage<'a> {
+    pub tracks: Vec<(&'a String, &'a StorageTrack)>,
+    pub total_count: usize,
+    pub next_cursor: Option<String>,
+}
+
+/// Sorts `tracks` and returns the `limit` items following `cursor`, the first page without one
+pub fn page_tracks<'a>(
+    tracks: Vec<(&'a String, &'a StorageTrack)>,
+    sort: Sort,
+    cursor: Option<&str>,
+    limit: usize,
+) -> Result<TrackPage<'a>, String> {
+    let order = sort.sort_order.unwrap_or(sort.sort_by.default_order());
+    let total_count = tracks.len();
+    let keyed = sorted_positions(tracks, sort.sort_by, order);
+
+    let start = match cursor.map(SortPosition::decode).transpose()? {
+        Some(cursor) if cursor.sort_by != sort.sort_by => {
+            return Err("Page cursor belongs to a different sort".to_string());
+        }
+        Some(cursor) => keyed.partition_point(|(position, _, _)| position.compare(&cursor, order) != Ordering::Greater),
+        None => 0,
+    };
+    let end = (start + limit).min(keyed.len());
+
+    let next_cursor = (end < keyed.len() && end > start).then(|| keyed[end - 1].0.encode());
+    let tracks = keyed
+        .into_iter()
+        .skip(start)
+        .take(end - start)
+        .map(|(_, id, track)| (id, track))
+        .collect();
+
+    Ok(TrackPage {
+        tracks,
+        total_count,
+        next_cursor,
+    })
+}
+
+/// Resolution classes as Jellyfin's `IsHD` and `Is4K` filters define them
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum Resolution {
+    Sd,
+    Hd,
+    Uhd,
+}
+
+impl Resolution {
+    fn of(width: i64, height: i64) -> Self {
+        if width >= 3800 || height >= 2000 {
+            Resolution::Uhd
+        } else if width >= 1260 || height >= 700 {
+            Resolution::Hd
+        } else {
+            Resolution::Sd
+        }
+    }
+}
+
+/// Filter for offline pages, every field that is set has to match. Lists match when any of their values does.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ItemFilter {
+    #[serde(default)]
+    pub genres: Vec<String>,
+    pub min_year: Option<i64>,
+    pub max_year: Option<i64>,
+    #[serde(default)]
+    pub official_ratings: Vec<String>,
+    pub is_played: Option<bool>,
+    pub is_favorite: Option<bool>,
+    /// Only items stored below this container, at any depth
+    pub container_id: Option<String>,
+    /// Language code of a subtitle stream in one of the downloaded versions, e.g. `eng`
+    pub subtitle_language: Option<String>,
+    #[serde(default)]
+    pub resolutions: Vec<Resolution>,
+}
+
+fn year(item: &serde_json::Value) -> Option<i64> {
+    item.get("ProductionYear").and_then(|y| y.as_i64()).or_else(|| {
+        item_str(item, "PremiereDate")
+            .and_then(|date| date.get(..4))
+            .and_then(|year| year.parse().ok())
+    })
+}
+
+fn contains_ignore_case(values: &[String], value: &str) -> bool {
+    values.iter().any(|v| v.eq_ignore_ascii_case(value))
+}
+
+/// Streams of every downloaded version, the item's own streams when no sources were stored
+fn downloaded_streams<'a>(id: &'a str, track: &'a StorageTrack) -> Vec<&'a serde_json::Value> {
+    let sources = track.media_sources.as_ref().and_then(|sources| sources.as_array());
+
+    let streams: Vec<&serde_json::Value> = match sources {
+        Some(sources) => sources
+            .iter()
+            .filter(|source| {
+                source
+                    .get("Id")
+                    .and_then(|s| s.as_str())
+                    .is_some_and(|source_id| versions::has_version(id, track, source_id))
+            })
+            .filter_map(|source| source.get("MediaStreams").and_then(|s| s.as_array()))
+            .flatten()
+            .collect(),
+        None => Vec::new(),
+    };
+
+    if !streams.is_empty() {
+        return streams;
+    }
+
+    track
+        .media_item
+        .get("MediaStreams")
+        .and_then(|s| s.as_array())
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+fn stream_type(stream: &serde_json::Value) -> Option<&str> {
+    stream.get("Type").and_then(|t| t.as_str())
+}
+
+impl ItemFilter {
+    fn matches(
+        &self,
+        id: &str,
+        track: &StorageTrack,
+        user_data: &HashMap<String, OfflineUserData>,
+        in_container: Option<&HashSet<String>>,
+    ) -> bool {
+        let item = &track.media_item;
+
+        if in_container.is_some_and(|ids| !ids.contains(id)) {
+            return false;
+        }
+
+        if !self.genres.is_empty() {
+            let genres = item.get("Genres").and_then(|g| g.as_array());
+            let any = genres
+                .into_iter()
+                .flatten()
+                .filter_map(|genre| genre.as_str())
+                .any(|genre| contains_ignore_case(&self.genres, genre));
+            if !any {
+                return false;
+            }
+        }
+
+        if self.min_year.is_some() || self.max_year.is_some() {
+            let Some(year) = year(item) else {
+                return false;
+            };
+            if self.min_year.is_some_and(|min| year < min) || self.max_year.is_some_and(|max| year > max) {
+                return false;
+            }
+        }
+
+        if !self.official_ratings.is_empty()
+            && !item_str(item, "OfficialRating").is_some_and(|rating| contains_ignore_case(&self.official_ratings, rating))
+        {
+            return false;
+        }
+
+        if let Some(is_played) = self.is_played {
+            if playback::is_played(item, user_data.get(id)) != is_played {
+                return false;
+            }
+        }
+
+        if let Some(is_favorite) = self.is_favorite {
+            let favorite = item.pointer("/UserData/IsFavorite").and_then(|f| f.as_bool()).unwrap_or(false);
+            if favorite != is_favorite {
+                return false;
+            }
+        }
+
+        if self.subtitle_language.is_none() && self.resolutions.is_empty() {
+            return true;
+        }
+
+        let streams = downloaded_streams(id, track);
+
+        if let Some(language) = &self.subtitle_language {
+            let has_subtitle = streams.iter().any(|stream| {
+                stream_type(stream) == Some("Subtitle")
+                    && stream
+                        .get("Language")
+                        .and_then(|l| l.as_str())
+                        .is_some_and(|l| l.eq_ignore_ascii_case(language))
+            });
+            if !has_subtitle {
+                return false;
+            }
+        }
+
+        if !self.resolutions.is_empty() {
+            let matches_resolution = streams
+                .iter()
+                .filter(|stream| stream_type(stream) == Some("Video"))
+                .map(|stream| {
+                    Resolution::of(
+                        stream.get("Width").and_then(|w| w.as_i64()).unwrap_or(0),
+                        stream.get("Height").and_then(|h| h.as_i64()).unwrap_or(0),
+                    )
+                })
+                .any(|resolution| self.resolutions.contains(&resolution));
+            if !matches_resolution {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// Items of `item_kind` matching `filter`, in no particular order
+pub fn filter_tracks<'a>(
+    metadata: &'a StorageMetadata,
+    item_kind: &str,
+    filter: &ItemFilter,
+    user_data: &HashMap<String, OfflineUserData>,
+) -> Vec<(&'a String, &'a StorageTrack)> {
+    let in_container: Option<HashSet<String>> = filter
+        .container_id
+        .as_ref()
+        .map(|container_id| tree::descendants(metadata, container_id).into_iter().collect());
+
+    metadata
+        .tracks
+        .iter()
+        .filter(|(_, track)| track.media_item.get("Type").and_then(|t| t.as_str()) == Some(item_kind))
+        .filter(|(id, track)| filter.matches(id, track, user_data, in_container.as_ref()))
+        .collect()
+}
@@ -0,0 +1,57 @@
+use super::versions::preferred_source_id;
+use super::{crypto, ServerStatus, StorageTrack};
+use std::path::Path;
+
+/// Size of a downloaded file as the server reports it, without the header of an encrypted blob.
+/// Only for tracks saved before the catalog recorded sizes, reads the file so it runs on the blocking pool.
+pub fn blob_size(path: &Path) -> Option<u64> {
+    let len = std::fs::metadata(path).ok()?.len();
+
+    if crypto::is_encrypted_file(path) {
+        return len.checked_sub(crypto::BLOB_HEADER_LEN as u64);
+    }
+    Some(len)
+}
+
+/// Compares every stored version of a track with the server's current copy of the item.
+/// `fresh` is `None` when the item no longer exists on the server.
+/// `local_size` is the recorded size of the preferred download, or `blob_size` of its file for older tracks.
+pub fn reconcile_track(
+    id: &str,
+    track: &StorageTrack,
+    fresh: Option<&serde_json::Value>,
+    local_size: Option<u64>,
+) -> Option<ServerStatus> {
+    let Some(fresh) = fresh else {
+        return Some(ServerStatus::OrphanedOnServer);
+    };
+
+    if track.track_type == "container" {
+        return None;
+    }
+
+    let sources = fresh
+        .get("MediaSources")
+        .and_then(|sources| sources.as_array())
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    let versions = track.versions.iter().map(|version| (version.media_source_id.as_str(), version.size));
+
+    std::iter::once((preferred_source_id(id, track), local_size))
+        .chain(versions)
+        .any(|(source_id, local_size)| source_changed(sources, source_id, local_size))
+        .then_some(ServerStatus::NewerVersionAvailable)
+}
+
+/// Whether the version we downloaded of `source_id` was removed or replaced by a different file
+fn source_changed(sources: &[serde_json::Value], source_id: &str, local_size: Option<u64>) -> bool {
+    let Some(server_source) = sources
+        .iter()
+        .find(|source| source.get("Id").and_then(|s| s.as_str()) == Some(source_id))
+    else {
+        return true;
+    };
+
+    let server_size = server_source.get("Size").and_then(|s| s.as_u64());
+    matches!((server_size, local_size), (Some(server_size), Some(local_size)) if server_size != local_size)
+}
@@ -0,0 +1,214 @@
+use super::crypto;
+use super::jellyfin::JellyfinClient;
+use super::reconcile::{blob_size, reconcile_track};
+use super::upgrade::{find_better_source, spawn_upgrades};
+use super::{
+    get_cached_metadata, get_storage_dir, mutate_metadata, run_blocking, settings, DownloadManager, ServerStatus,
+};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Manager};
+
+const REFRESH_BATCH_SIZE: usize = 50;
+const REFRESH_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Serialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct RefreshReport {
+    pub updated: usize,
+    /// Downloaded items that no longer exist on the server
+    pub deleted: Vec<String>,
+    /// Downloaded items whose file was replaced or removed on the server
+    pub newer_version_available: Vec<String>,
+}
+
+#[tauri::command]
+pub async fn storage_refresh_metadata(app: AppHandle) -> Result<RefreshReport, String> {
+    refresh_metadata(&app).await
+}
+
+/// Re-fetches every stored item from the server and updates `media_item`/`media_sources` in the catalog
+pub async fn refresh_metadata(app: &AppHandle) -> Result<RefreshReport, String> {
+    let download_manager = app.state::<DownloadManager>();
+
+    let Some(auth) = download_manager.jellyfin_auth.lock().unwrap().clone() else {
+        return Err("Not connected to a server".to_string());
+    };
+
+    if download_manager.refreshing_metadata.swap(true, Ordering::SeqCst) {
+        return Err("Metadata refresh already in progress".to_string());
+    }
+
+    let result = async {
+        let client = JellyfinClient::new(auth);
+        let storage_dir = get_storage_dir(app).map_err(|e| e.to_string())?;
+        let metadata = get_cached_metadata(app, &download_manager).await.map_err(|e| e.to_string())?;
+        let ids: Vec<String> = metadata.tracks.keys().cloned().collect();
+
+        let mut report = RefreshReport::default();
+        let mut fresh_items = HashMap::new();
+
+        for batch in ids.chunks(REFRESH_BATCH_SIZE) {
+            let found = client.get_items_by_ids(batch).await.map_err(|e| e.to_string())?;
+
+            report
+                .deleted
+                .extend(batch.iter().filter(|id| !found.contains_key(*id)).cloned());
+            fresh_items.extend(found);
+        }
+
+        // Artwork only needs downloading again when the image tag changed
+        let mut thumbnails_saved = HashSet::new();
+        for (id, fresh) in &fresh_items {
+            let old_tag = metadata
+                .tracks
+                .get(id)
+                .and_then(|track| track.media_item.pointer("/ImageTags/Primary"));
+            let new_tag = fresh.pointer("/ImageTags/Primary");
+
+            if new_tag.is_none() || new_tag == old_tag {
+                continue;
+            }
+
+            match client.get_thumbnail(id).await {
+                Ok(data) => {
+                    let (app, path) = (app.clone(), storage_dir.join(format!("{}.thumb", id)));
+                    run_blocking(move || crypto::write_file(&app, &path, &data))
+                        .await
+                        .map_err(|e| e.to_string())?;
+                    thumbnails_saved.insert(id.clone());
+                }
+                Err(e) => println!("refresh_metadata: Failed to fetch artwork for id {}: {}", id, e),
+            }
+        }
+
+        // Stat the files before taking the catalog again, older tracks don't record their size
+        let unsized_ids: Vec<String> = metadata
+            .tracks
+            .iter()
+            .filter(|(_, track)| track.size.is_none() && track.track_type != "container")
+            .map(|(id, _)| id.clone())
+            .collect();
+        let local_sizes: HashMap<String, Option<u64>> = run_blocking(move || {
+            Ok(unsized_ids
+                .into_iter()
+                .map(|id| {
+                    let size = blob_size(&storage_dir.join(format!("{}.blob", id)));
+                    (id, size)
+                })
+                .collect())
+        })
+        .await
+        .map_err(|e| e.to_string())?;
+
+        let auto_upgrade = settings::get_settings(app, &download_manager)
+            .map(|settings| settings.auto_upgrade_downloads)
+            .unwrap_or(false);
+        let refreshed: HashSet<&String> = ids.iter().collect();
+
+        // Apply to the current catalog, downloads may have finished while we were fetching
+        let upgrades = mutate_metadata(app, &download_manager, |metadata| {
+            let mut upgrades = Vec::new();
+
+            for (id, track) in metadata.tracks.iter_mut() {
+                // Added after we fetched, nothing to compare against yet
+                if !refreshed.contains(id) {
+                    continue;
+                }
+
+                // Compare before `media_sources` is overwritten with the server's current sources
+                let local_size = track.size.or_else(|| local_sizes.get(id).copied().flatten());
+                track.server_status = reconcile_track(id, track, fresh_items.get(id), local_size);
+
+                if track.server_status == Some(ServerStatus::NewerVersionAvailable) {
+                    report.newer_version_available.push(id.clone());
+                }
+
+                if let (true, Some(fresh)) = (auto_upgrade, fresh_items.get(id)) {
+                    upgrades.extend(find_better_source(id, track, fresh));
+                }
+            }
+
+            for (id, fresh) in fresh_items {
+                if let Some(track) = metadata.tracks.get_mut(&id) {
+                    if let Some(media_sources) = fresh.get("MediaSources").filter(|s| s.is_array()) {
+                        track.media_sources = Some(media_sources.clone());
+                    }
+                    merge_item(&mut track.media_item, fresh);
+                    if thumbnails_saved.contains(&id) {
+                        track.has_thumbnail = Some(true);
+                    }
+                    report.updated += 1;
+                }
+            }
+
+            upgrades
+        })
+        .await
+        .map_err(|e| e.to_string())?;
+        spawn_upgrades(app.clone(), client, upgrades);
+
+        println!(
+            "refresh_metadata: Updated {} items, {} deleted on server, {} with a newer version",
+            report.updated,
+            report.deleted.len(),
+            report.newer_version_available.len()
+        );
+
+        Ok(report)
+    }
+    .await;
+
+    download_manager.refreshing_metadata.store(false, Ordering::SeqCst);
+    result
+}
+
+/// Overlays the server's fields on the snapshot, keeping frontend-only fields like `offlineState`
+fn merge_item(media_item: &mut serde_json::Value, fresh: serde_json::Value) {
+    let (Some(target), serde_json::Value::Object(fresh)) = (media_item.as_object_mut(), fresh) else {
+        return;
+    };
+
+    for (key, value) in fresh {
+        // Stored separately in `media_sources`
+        if key == "MediaSources" {
+            continue;
+        }
+        target.insert(key, value);
+    }
+}
+
+/// Runs the refresh on the interval configured in the storage settings
+pub fn spawn_metadata_refresh(app: AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        let mut last_refresh: Option<Instant> = None;
+
+        loop {
+            tokio::time::sleep(REFRESH_CHECK_INTERVAL).await;
+
+            let download_manager = app.state::<DownloadManager>();
+            let interval = settings::get_settings(&app, &download_manager)
+                .ok()
+                .and_then(|settings| settings.refresh_interval_minutes);
+
+            let Some(interval) = interval else {
+                continue;
+            };
+
+            if download_manager.jellyfin_auth.lock().unwrap().is_none() {
+                continue;
+            }
+
+            if last_refresh.is_some_and(|t| t.elapsed() < Duration::from_secs(interval * 60)) {
+                continue;
+            }
+
+            match refresh_metadata(&app).await {
+                Ok(_) => last_refresh = Some(Instant::now()),
+                Err(e) => println!("spawn_metadata_refresh: {}", e),
+            }
+        }
+    });
+}
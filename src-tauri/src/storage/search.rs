@@ -0,0 +1,420 @@
+//! Ranked search over the offline catalog.
+//!
+//! Every item is indexed by the folded words of its name, original title, series name, genres, people and
+//! overview. A query word matches an indexed word exactly, as a prefix or within a small edit distance, and
+//! the item must match every query word. Matches in the name weigh most, matches in the overview least.
+
+use super::catalog::offline_media_item;
+use super::{get_cached_metadata, load_metadata_blocking, playback, DownloadManager, StorageMetadata};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tauri::{AppHandle, State};
+
+/// Share of an episode's score its season and series get, enough to list them without outranking direct hits
+const PARENT_SCORE: f64 = 0.5;
+
+#[derive(Debug, Clone, Copy)]
+enum Field {
+    Name,
+    OriginalTitle,
+    SeriesName,
+    Genre,
+    Person,
+    Overview,
+}
+
+impl Field {
+    fn weight(self) -> f64 {
+        match self {
+            Field::Name => 10.0,
+            Field::OriginalTitle => 8.0,
+            Field::SeriesName => 5.0,
+            Field::Genre | Field::Person => 3.0,
+            Field::Overview => 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Posting {
+    entry: usize,
+    field: Field,
+}
+
+#[derive(Debug)]
+struct Entry {
+    id: String,
+    /// Folded name, for phrase bonuses and ordering equal scores
+    name: String,
+}
+
+#[derive(Debug, Default)]
+pub struct SearchIndex {
+    entries: Vec<Entry>,
+    words: HashMap<String, Vec<Posting>>,
+}
+
+/// Lowercase ASCII form of a Latin character, accents and ligatures folded away
+fn fold_char(c: char, out: &mut String) {
+    let folded = match c {
+        'à'..='å' | 'ā' | 'ă' | 'ą' => "a",
+        'æ' => "ae",
+        'ç' | 'ć' | 'ĉ' | 'ċ' | 'č' => "c",
+        'ď' | 'đ' | 'ð' => "d",
+        'è'..='ë' | 'ē' | 'ĕ' | 'ė' | 'ę' | 'ě' => "e",
+        'ĝ' | 'ğ' | 'ġ' | 'ģ' => "g",
+        'ĥ' | 'ħ' => "h",
+        'ì'..='ï' | 'ĩ' | 'ī' | 'ĭ' | 'į' | 'ı' => "i",
+        'ĵ' => "j",
+        'ķ' => "k",
+        'ĺ' | 'ļ' | 'ľ' | 'ŀ' | 'ł' => "l",
+        'ñ' | 'ń' | 'ņ' | 'ň' => "n",
+        'ò'..='ö' | 'ø' | 'ō' | 'ŏ' | 'ő' => "o",
+        'œ' => "oe",
+        'ŕ' | 'ŗ' | 'ř' => "r",
+        'ś' | 'ŝ' | 'ş' | 'š' => "s",
+        'ß' => "ss",
+        'ţ' | 'ť' | 'ŧ' => "t",
+        'þ' => "th",
+        'ù'..='ü' | 'ũ' | 'ū' | 'ŭ' | 'ů' | 'ű' | 'ų' => "u",
+        'ŵ' => "w",
+        'ý' | 'ÿ' | 'ŷ' => "y",
+        'ź' | 'ż' | 'ž' => "z",
+        _ => {
+            out.push(c);
+            return;
+        }
+    };
+    out.push_str(folded);
+}
+
+/// Lowercases and strips diacritics, "Amélie" and "amelie" fold to the same text
+pub fn fold(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars().flat_map(char::to_lowercase) {
+        fold_char(c, &mut out);
+    }
+    out
+}
+
+fn words(folded: &str) -> impl Iterator<Item = &str> {
+    folded.split(|c: char| !c.is_alphanumeric()).filter(|word| !word.is_empty())
+}
+
+/// Edit distance with transpositions, `None` once it's certain to exceed `max`
+fn edit_distance(a: &[char], b: &[char], max: usize) -> Option<usize> {
+    if a.len().abs_diff(b.len()) > max {
+        return None;
+    }
+
+    let mut before: Vec<usize> = Vec::new();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+
+    for i in 1..=a.len() {
+        current[0] = i;
+        let mut row_min = current[0];
+
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            current[j] = (previous[j] + 1).min(current[j - 1] + 1).min(previous[j - 1] + cost);
+
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                current[j] = current[j].min(before[j - 2] + 1);
+            }
+            row_min = row_min.min(current[j]);
+        }
+
+        if row_min > max {
+            return None;
+        }
+        before = std::mem::replace(&mut previous, std::mem::take(&mut current));
+        current = vec![0; b.len() + 1];
+    }
+
+    Some(previous[b.len()]).filter(|distance| *distance <= max)
+}
+
+/// How well `word` from the index matches the query word, 0 when it doesn't
+fn match_quality(query: &[char], query_text: &str, word: &str) -> f64 {
+    if word == query_text {
+        return 1.0;
+    }
+    if query.len() >= 2 && word.starts_with(query_text) {
+        return 0.8;
+    }
+
+    // Short words need to be exact, a single typo in a three letter word matches half the library
+    let max_typos = match query.len() {
+        0..=3 => return 0.0,
+        4..=7 => 1,
+        _ => 2,
+    };
+    let word: Vec<char> = word.chars().collect();
+
+    match edit_distance(query, &word, max_typos) {
+        Some(1) => 0.6,
+        Some(_) => 0.4,
+        None => {
+            // A typo in a word that isn't finished yet, "avneg" for "avengers"
+            let prefix = &word[..word.len().min(query.len())];
+            if query.len() >= 4 && edit_distance(query, prefix, 1).is_some() {
+                0.5
+            } else {
+                0.0
+            }
+        }
+    }
+}
+
+impl SearchIndex {
+    pub fn build(metadata: &StorageMetadata) -> Self {
+        let mut index = SearchIndex::default();
+
+        for (id, track) in &metadata.tracks {
+            let item = &track.media_item;
+            let entry = index.entries.len();
+            let text = |field: &str| item.get(field).and_then(|v| v.as_str()).unwrap_or_default();
+
+            let mut fields: Vec<(Field, &str)> = vec![
+                (Field::Name, text("Name")),
+                (Field::OriginalTitle, text("OriginalTitle")),
+                (Field::SeriesName, text("SeriesName")),
+                (Field::Overview, text("Overview")),
+            ];
+            let genres = item.get("Genres").and_then(|g| g.as_array()).into_iter().flatten();
+            fields.extend(genres.filter_map(|genre| genre.as_str()).map(|genre| (Field::Genre, genre)));
+            let people = item.get("People").and_then(|p| p.as_array()).into_iter().flatten();
+            fields.extend(
+                people
+                    .filter_map(|person| person.get("Name").and_then(|n| n.as_str()))
+                    .map(|name| (Field::Person, name)),
+            );
+
+            for (field, value) in fields {
+                for word in words(&fold(value)) {
+                    let postings = index.words.entry(word.to_string()).or_default();
+                    // One posting per entry and word, keeping the heaviest field
+                    match postings.last_mut() {
+                        Some(last) if last.entry == entry => {
+                            if field.weight() > last.field.weight() {
+                                last.field = field;
+                            }
+                        }
+                        _ => postings.push(Posting { entry, field }),
+                    }
+                }
+            }
+
+            index.entries.push(Entry {
+                id: id.clone(),
+                name: fold(text("Name")),
+            });
+        }
+
+        index
+    }
+
+    /// Ids of the best matches for `query`, best first
+    pub fn search(&self, query: &str, limit: usize) -> Vec<(String, f64)> {
+        let folded_query = fold(query);
+        let query_words: Vec<&str> = words(&folded_query).collect();
+        if query_words.is_empty() {
+            return Vec::new();
+        }
+
+        let mut scores: HashMap<usize, f64> = HashMap::new();
+
+        for (position, query_word) in query_words.iter().enumerate() {
+            let query_chars: Vec<char> = query_word.chars().collect();
+            let mut word_scores: HashMap<usize, f64> = HashMap::new();
+
+            for (word, postings) in &self.words {
+                let quality = match_quality(&query_chars, query_word, word);
+                if quality == 0.0 {
+                    continue;
+                }
+                for posting in postings {
+                    let score = word_scores.entry(posting.entry).or_default();
+                    *score = score.max(quality * posting.field.weight());
+                }
+            }
+
+            // Every query word has to match somewhere
+            if position == 0 {
+                scores = word_scores;
+            } else {
+                scores.retain(|entry, _| word_scores.contains_key(entry));
+                for (entry, score) in scores.iter_mut() {
+                    *score += word_scores[entry];
+                }
+            }
+        }
+
+        let phrase = query_words.join(" ");
+        let mut results: Vec<(usize, f64)> = scores
+            .into_iter()
+            .map(|(entry, score)| {
+                let name = words(&self.entries[entry].name).collect::<Vec<_>>().join(" ");
+                let bonus = if name == phrase {
+                    20.0
+                } else if name.starts_with(&phrase) {
+                    10.0
+                } else if name.contains(&phrase) {
+                    5.0
+                } else {
+                    0.0
+                };
+                (entry, score + bonus)
+            })
+            .collect();
+
+        results.sort_by(|a, b| {
+            b.1.total_cmp(&a.1)
+                .then_with(|| self.entries[a.0].name.cmp(&self.entries[b.0].name))
+                .then_with(|| self.entries[a.0].id.cmp(&self.entries[b.0].id))
+        });
+
+        results
+            .into_iter()
+            .take(limit)
+            .map(|(entry, score)| (self.entries[entry].id.clone(), score))
+            .collect()
+    }
+}
+
+/// Searches the cached index, building it from the catalog first if it was invalidated
+pub async fn search(
+    app: &AppHandle,
+    download_manager: &State<'_, DownloadManager>,
+    query: &str,
+    limit: usize,
+) -> tauri::Result<Vec<(String, f64)>> {
+    loop {
+        {
+            // Held while building so a change in the meantime can't be overwritten by an index of the old catalog
+            let mut cache = download_manager.cached_search_index.lock().unwrap();
+            if cache.is_none() {
+                let metadata = download_manager.cached_metadata.lock().unwrap().clone();
+                *cache = metadata.map(|metadata| SearchIndex::build(&metadata));
+            }
+            if let Some(index) = cache.as_ref() {
+                return Ok(index.search(query, limit));
+            }
+        }
+        let metadata = load_metadata_blocking(app).await?;
+        download_manager.cached_metadata.lock().unwrap().get_or_insert(metadata);
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchGroupRequest {
+    /// Jellyfin item type of the bucket, e.g. `Movie` or `Series`
+    pub item_type: String,
+    pub limit: usize,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchGroup {
+    pub item_type: String,
+    /// All matches of this type, not only the returned ones
+    pub total_count: usize,
+    pub items: Vec<serde_json::Value>,
+}
+
+/// Searches once and splits the matches into one bucket per requested type, each with its own limit.
+/// Seasons and series of matching episodes are added to their buckets, so a search for an episode title
+/// still shows the show it belongs to.
+#[tauri::command]
+pub async fn storage_search_grouped(
+    app: AppHandle,
+    download_manager: State<'_, DownloadManager>,
+    search_term: String,
+    groups: Vec<SearchGroupRequest>,
+) -> Result<Vec<SearchGroup>, String> {
+    if search_term.trim().is_empty() || groups.is_empty() {
+        return Ok(groups
+            .into_iter()
+            .map(|group| SearchGroup {
+                item_type: group.item_type,
+                total_count: 0,
+                items: Vec::new(),
+            })
+            .collect());
+    }
+
+    let matches = search(&app, &download_manager, &search_term, usize::MAX).await.map_err(|e| e.to_string())?;
+    let metadata = get_cached_metadata(&app, &download_manager).await.map_err(|e| e.to_string())?;
+    let user_data = playback::get_user_data_snapshot(&app, &download_manager).await.map_err(|e| e.to_string())?;
+
+    let mut scores: HashMap<&str, f64> = matches.iter().map(|(id, score)| (id.as_str(), *score)).collect();
+
+    for (id, score) in &matches {
+        let Some(track) = metadata.tracks.get(id) else {
+            continue;
+        };
+        let parents = ["SeasonId", "SeriesId"]
+            .iter()
+            .filter_map(|field| track.media_item.get(*field).and_then(|p| p.as_str()));
+
+        for parent in parents {
+            let Some((parent_id, _)) = metadata.tracks.get_key_value(parent) else {
+                continue;
+            };
+            let parent_score = scores.entry(parent_id.as_str()).or_default();
+            *parent_score = parent_score.max(score * PARENT_SCORE);
+        }
+    }
+
+    let type_of = |id: &str| {
+        metadata
+            .tracks
+            .get(id)
+            .and_then(|track| track.media_item.get("Type"))
+            .and_then(|t| t.as_str())
+    };
+    let name_of = |id: &str| {
+        metadata
+            .tracks
+            .get(id)
+            .and_then(|track| track.media_item.get("Name"))
+            .and_then(|n| n.as_str())
+            .map(fold)
+            .unwrap_or_default()
+    };
+
+    let results = groups
+        .into_iter()
+        .map(|group| {
+            let mut bucket: Vec<(&str, f64, String)> = scores
+                .iter()
+                .filter(|(id, _)| type_of(id) == Some(group.item_type.as_str()))
+                .map(|(id, score)| (*id, *score, name_of(id)))
+                .collect();
+            bucket.sort_by(|a, b| {
+                b.1.total_cmp(&a.1)
+                    .then_with(|| a.2.cmp(&b.2))
+                    .then_with(|| a.0.cmp(b.0))
+            });
+
+            let items = bucket
+                .iter()
+                .take(group.limit)
+                .filter_map(|(id, _, _)| {
+                    let track = metadata.tracks.get(*id)?;
+                    Some(offline_media_item(track, user_data.get(*id)))
+                })
+                .collect();
+
+            SearchGroup {
+                item_type: group.item_type,
+                total_count: bucket.len(),
+                items,
+            }
+        })
+        .collect();
+
+    Ok(results)
+}
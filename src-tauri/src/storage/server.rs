@@ -0,0 +1,379 @@
+use super::crypto::{self, BLOB_HEADER_LEN};
+use super::{get_cached_metadata, get_storage_dir, run_blocking, versions, DownloadManager};
+use chacha20::cipher::{StreamCipher, StreamCipherSeek};
+use chacha20::XChaCha20;
+use http_body_util::{combinators::BoxBody, BodyExt, Empty, StreamBody};
+use hyper::body::{Bytes, Frame, Incoming};
+use hyper::header::{ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, RANGE};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use futures_util::{StreamExt, TryStreamExt};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use std::io::SeekFrom;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tauri::{AppHandle, Manager, State};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::net::TcpListener;
+use tokio_util::io::ReaderStream;
+
+type ResponseBody = BoxBody<Bytes, std::io::Error>;
+
+#[derive(Clone)]
+struct ServerInfo {
+    port: u16,
+    token: String,
+}
+
+/// Loopback-only HTTP server exposing downloaded blobs with `Range` support.
+///
+/// The server is started lazily the first time a stream URL is requested and
+/// keeps running for the rest of the session. Every URL carries a random token
+/// generated at startup so other local processes can't enumerate the library.
+/// Encrypted blobs are decrypted on the fly, ranges refer to the plaintext.
+#[derive(Default)]
+pub struct StreamServer {
+    info: Arc<Mutex<Option<ServerInfo>>>,
+}
+
+impl StreamServer {
+    fn ensure_started(&self, storage_dir: PathBuf, app: AppHandle) -> std::io::Result<ServerInfo> {
+        let mut info = self.info.lock().unwrap();
+
+        if let Some(info) = info.as_ref() {
+            return Ok(info.clone());
+        }
+
+        let std_listener = std::net::TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))?;
+        std_listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(std_listener)?;
+        let port = listener.local_addr()?.port();
+
+        let token: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+
+        let server_info = ServerInfo { port, token };
+        let ctx = Arc::new((storage_dir, server_info.token.clone(), app));
+
+        tauri::async_runtime::spawn(async move {
+            loop {
+                let (stream, _) = match listener.accept().await {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        println!("stream_server: Accept failed: {}", e);
+                        continue;
+                    }
+                };
+
+                let ctx = ctx.clone();
+                tauri::async_runtime::spawn(async move {
+                    let service = service_fn(move |req| {
+                        let ctx = ctx.clone();
+                        async move { handle_request(req, &ctx.0, &ctx.1, &ctx.2).await }
+                    });
+
+                    if let Err(e) = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await
+                    {
+                        // Players routinely drop the connection mid-body when seeking
+                        if !e.is_incomplete_message() {
+                            println!("stream_server: Connection error: {}", e);
+                        }
+                    }
+                });
+            }
+        });
+
+        println!("stream_server: Listening on 127.0.0.1:{}", port);
+        *info = Some(server_info.clone());
+
+        Ok(server_info)
+    }
+}
+
+/// Marks an item as served while a response streams its video, see `item_in_use`
+struct ServingGuard {
+    serving: Arc<Mutex<HashMap<String, usize>>>,
+    id: String,
+}
+
+impl ServingGuard {
+    fn new(download_manager: &DownloadManager, id: &str) -> Self {
+        *download_manager.serving.lock().unwrap().entry(id.to_string()).or_default() += 1;
+        Self {
+            serving: download_manager.serving.clone(),
+            id: id.to_string(),
+        }
+    }
+}
+
+impl Drop for ServingGuard {
+    fn drop(&mut self) {
+        let mut serving = self.serving.lock().unwrap();
+        if let Some(count) = serving.get_mut(&self.id) {
+            *count -= 1;
+            if *count == 0 {
+                serving.remove(&self.id);
+            }
+        }
+    }
+}
+
+/// URL of the requested version, or of the preferred one when `media_source_id` is `None`
+#[tauri::command]
+pub async fn storage_get_stream_url(
+    app: AppHandle,
+    download_manager: State<'_, DownloadManager>,
+    stream_server: State<'_, StreamServer>,
+    id: String,
+    media_source_id: Option<String>,
+) -> Result<Option<String>, String> {
+    let storage_dir = get_storage_dir(&app).map_err(|e| e.to_string())?;
+    let metadata = get_cached_metadata(&app, &download_manager).await.map_err(|e| e.to_string())?;
+
+    if !versions::is_valid_id(&id) {
+        return Ok(None);
+    }
+
+    // The preferred version is served as `/{token}/{id}`, others as `/{token}/{id}/{media_source_id}`
+    let path = match metadata.tracks.get(&id) {
+        Some(track) => match media_source_id.as_deref() {
+            Some(source) if source != versions::preferred_source_id(&id, track) && versions::is_valid_id(source) => {
+                versions::blob_path(&storage_dir, &id, track, Some(source)).map(|blob| (blob, format!("{}/{}", id, source)))
+            }
+            _ => Some((storage_dir.join(format!("{}.blob", id)), id.clone())),
+        },
+        None => None,
+    };
+
+    let Some((blob_path, path)) = path else {
+        return Ok(None);
+    };
+    if !tokio::fs::try_exists(&blob_path).await.unwrap_or(false) {
+        return Ok(None);
+    }
+
+    // Loads the key into the cache off the async runtime, requests only read it from there
+    let key_app = app.clone();
+    run_blocking(move || crypto::get_key(&key_app)).await.map_err(|e| e.to_string())?;
+
+    let info = stream_server
+        .ensure_started(storage_dir, app)
+        .map_err(|e| e.to_string())?;
+    Ok(Some(format!("http://127.0.0.1:{}/{}/{}", info.port, info.token, path)))
+}
+
+async fn handle_request(
+    req: Request<Incoming>,
+    storage_dir: &Path,
+    token: &str,
+    app: &AppHandle,
+) -> Result<Response<ResponseBody>, hyper::Error> {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return Ok(empty_response(StatusCode::METHOD_NOT_ALLOWED));
+    }
+
+    let mut segments = req.uri().path().trim_start_matches('/').splitn(2, '/');
+    let (Some(req_token), Some(id)) = (segments.next(), segments.next()) else {
+        return Ok(empty_response(StatusCode::NOT_FOUND));
+    };
+
+    if req_token != token {
+        return Ok(empty_response(StatusCode::FORBIDDEN));
+    }
+
+    let (item_id, blob_path) = match id.split_once('/') {
+        Some((id, source)) => match versions::version_path(storage_dir, id, source) {
+            Ok(path) => (id, path),
+            Err(_) => return Ok(empty_response(StatusCode::NOT_FOUND)),
+        },
+        None if versions::is_valid_id(id) => (id, storage_dir.join(format!("{}.blob", id))),
+        None => return Ok(empty_response(StatusCode::NOT_FOUND)),
+    };
+    // Taken before opening so encrypting existing files can't swap the file underneath the response
+    let serving = ServingGuard::new(&app.state::<DownloadManager>(), item_id);
+    let mut file = match tokio::fs::File::open(&blob_path).await {
+        Ok(file) => file,
+        Err(_) => return Ok(empty_response(StatusCode::NOT_FOUND)),
+    };
+
+    let result = async {
+        let (offset, mut cipher) = open_blob(&mut file, app).await?;
+        let file_size = file.metadata().await?.len() - offset;
+        let mime = sniff_mime(&mut file, offset, cipher.as_mut()).await?;
+
+        let range = req.headers().get(RANGE).and_then(|value| value.to_str().ok());
+        let (start, end, status) = match range {
+            Some(range) => match parse_range(range, file_size) {
+                Some((start, end)) => (start, end, StatusCode::PARTIAL_CONTENT),
+                None => {
+                    let mut response = empty_response(StatusCode::RANGE_NOT_SATISFIABLE);
+                    response
+                        .headers_mut()
+                        .insert(CONTENT_RANGE, format!("bytes */{}", file_size).parse().unwrap());
+                    return Ok(response);
+                }
+            },
+            None => (0, file_size.saturating_sub(1), StatusCode::OK),
+        };
+
+        let length = if file_size == 0 { 0 } else { end - start + 1 };
+
+        let body = if req.method() == Method::HEAD || length == 0 {
+            Empty::<Bytes>::new().map_err(|never| match never {}).boxed()
+        } else {
+            file.seek(SeekFrom::Start(offset + start)).await?;
+            let stream = ReaderStream::new(file.take(length));
+
+            match cipher {
+                Some(mut cipher) => {
+                    // Also rewinds what sniffing the header consumed
+                    cipher.seek(start);
+                    let stream = stream.map(move |chunk| {
+                        // The body owns the guard, the item counts as served until it's dropped
+                        let _serving = &serving;
+                        chunk.map(|chunk| {
+                            let mut chunk = chunk.to_vec();
+                            cipher.apply_keystream(&mut chunk);
+                            Frame::data(Bytes::from(chunk))
+                        })
+                    });
+                    BodyExt::boxed(StreamBody::new(stream))
+                }
+                None => BodyExt::boxed(StreamBody::new(stream.map_ok(move |chunk| {
+                    let _serving = &serving;
+                    Frame::data(chunk)
+                }))),
+            }
+        };
+
+        let mut builder = Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, mime)
+            .header(ACCEPT_RANGES, "bytes")
+            .header(CONTENT_LENGTH, length);
+
+        if status == StatusCode::PARTIAL_CONTENT {
+            builder = builder.header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, file_size));
+        }
+
+        Ok::<_, std::io::Error>(builder.body(body).unwrap())
+    }
+    .await;
+
+    Ok(result.unwrap_or_else(|e| {
+        println!("stream_server: Failed to serve {}: {}", id, e);
+        empty_response(StatusCode::INTERNAL_SERVER_ERROR)
+    }))
+}
+
+fn empty_response(status: StatusCode) -> Response<ResponseBody> {
+    let mut response = Response::new(Empty::<Bytes>::new().map_err(|never| match never {}).boxed());
+    *response.status_mut() = status;
+    response
+}
+
+/// Parses a single `bytes=` range into inclusive offsets, `None` when unsatisfiable.
+/// Multi-range requests are answered with the first range only, which players accept.
+pub fn parse_range(header: &str, file_size: u64) -> Option<(u64, u64)> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    let first = spec.split(',').next()?.trim();
+    let (start, end) = first.split_once('-')?;
+
+    if file_size == 0 {
+        return None;
+    }
+
+    let last = file_size - 1;
+
+    let (start, end) = if start.is_empty() {
+        // Suffix range: the last N bytes
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 {
+            return None;
+        }
+        (file_size.saturating_sub(suffix), last)
+    } else {
+        let start: u64 = start.parse().ok()?;
+        let end = if end.is_empty() { last } else { end.parse::<u64>().ok()?.min(last) };
+        (start, end)
+    };
+
+    if start > end || start > last {
+        return None;
+    }
+
+    Some((start, end))
+}
+
+/// Offset of the content in the file and the cipher to decrypt it with, `None` for plain blobs
+async fn open_blob(file: &mut tokio::fs::File, app: &AppHandle) -> std::io::Result<(u64, Option<XChaCha20>)> {
+    let mut header = [0u8; BLOB_HEADER_LEN];
+    let is_encrypted = match file.read_exact(&mut header).await {
+        Ok(_) => crypto::is_encrypted_blob(&header),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => false,
+        Err(e) => return Err(e),
+    };
+
+    if !is_encrypted {
+        file.seek(SeekFrom::Start(0)).await?;
+        return Ok((0, None));
+    }
+
+    let key = crypto::get_key(app).map_err(std::io::Error::other)?;
+    Ok((BLOB_HEADER_LEN as u64, crypto::blob_cipher(&header, key.as_ref())?))
+}
+
+/// Detects the container from the file header since blobs are stored without an extension
+async fn sniff_mime(
+    file: &mut tokio::fs::File,
+    offset: u64,
+    cipher: Option<&mut XChaCha20>,
+) -> std::io::Result<&'static str> {
+    let mut header = [0u8; 16];
+    let mut read = 0;
+
+    while read < header.len() {
+        let n = file.read(&mut header[read..]).await?;
+        if n == 0 {
+            break;
+        }
+        read += n;
+    }
+
+    file.seek(SeekFrom::Start(offset)).await?;
+
+    if let Some(cipher) = cipher {
+        cipher.apply_keystream(&mut header[..read]);
+    }
+
+    Ok(mime_from_header(&header[..read]))
+}
+
+fn mime_from_header(header: &[u8]) -> &'static str {
+    if header.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+        // EBML, used by both Matroska and WebM
+        "video/x-matroska"
+    } else if header.len() >= 8 && &header[4..8] == b"ftyp" {
+        "video/mp4"
+    } else if header.len() >= 12 && &header[0..4] == b"RIFF" && &header[8..12] == b"AVI " {
+        "video/x-msvideo"
+    } else if header.starts_with(&[0x47]) {
+        "video/mp2t"
+    } else if header.starts_with(b"OggS") {
+        "video/ogg"
+    } else if header.starts_with(&[0x00, 0x00, 0x01, 0xBA]) {
+        "video/mpeg"
+    } else {
+        "application/octet-stream"
+    }
+}
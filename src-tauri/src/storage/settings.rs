@@ -0,0 +1,80 @@
+use super::{crypto, DownloadManager};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use tauri::{AppHandle, Manager, State};
+
+/// User configurable behaviour of the offline storage.
+/// Kept next to `offline_storage` rather than inside it so clearing downloads doesn't reset it.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct StorageSettings {
+    /// Re-fetch metadata of downloaded items from the server every N minutes, disabled when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_interval_minutes: Option<u64>,
+    /// Re-download items in the background when the server gets a higher quality source
+    pub auto_upgrade_downloads: bool,
+    /// Encrypt downloads, thumbnails and the catalog on disk. Turning it on encrypts what's already stored,
+    /// turning it off only affects new files, encrypted ones stay readable.
+    pub encrypt_storage: bool,
+    /// Space in bytes the user wants downloads to stay under, reported next to the usage
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub storage_quota_bytes: Option<u64>,
+}
+
+fn get_settings_path(app: &AppHandle) -> tauri::Result<PathBuf> {
+    let app_data_dir = app.path().app_data_dir()?;
+    fs::create_dir_all(&app_data_dir)?;
+    Ok(app_data_dir.join("storage_settings.json"))
+}
+
+fn load_settings(app: &AppHandle) -> tauri::Result<StorageSettings> {
+    let path = get_settings_path(app)?;
+
+    if !path.exists() {
+        return Ok(StorageSettings::default());
+    }
+
+    let content = fs::read_to_string(&path)?;
+    serde_json::from_str(&content)
+        .map_err(|e| tauri::Error::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, e)))
+}
+
+pub(super) fn get_settings(app: &AppHandle, download_manager: &DownloadManager) -> tauri::Result<StorageSettings> {
+    let mut cache = download_manager.cached_settings.lock().unwrap();
+
+    if cache.is_none() {
+        *cache = Some(load_settings(app)?);
+    }
+
+    Ok(cache.as_ref().unwrap().clone())
+}
+
+#[tauri::command]
+pub async fn storage_get_settings(
+    app: AppHandle,
+    download_manager: State<'_, DownloadManager>,
+) -> Result<StorageSettings, String> {
+    get_settings(&app, &download_manager).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn storage_set_settings(
+    app: AppHandle,
+    download_manager: State<'_, DownloadManager>,
+    settings: StorageSettings,
+) -> Result<(), String> {
+    let path = get_settings_path(&app).map_err(|e| e.to_string())?;
+    let content = serde_json::to_string(&settings).map_err(|e| e.to_string())?;
+    tokio::fs::write(path, content).await.map_err(|e| e.to_string())?;
+
+    let was_encrypted = get_settings(&app, &download_manager).map_err(|e| e.to_string())?.encrypt_storage;
+    let encrypt = settings.encrypt_storage;
+    *download_manager.cached_settings.lock().unwrap() = Some(settings);
+
+    if encrypt && !was_encrypted {
+        crypto::spawn_encrypt_existing(app);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,167 @@
+use super::{tree, StorageMetadata, StorageTrack};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Serialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct TypeUsage {
+    pub item_type: String,
+    pub count: usize,
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ItemUsage {
+    pub id: String,
+    pub name: String,
+    pub item_type: String,
+    /// Downloaded items below it, 1 for an item that isn't a container
+    pub count: usize,
+    pub size: u64,
+}
+
+/// Bytes on disk per kind of file
+#[derive(Debug, Serialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct FileKindUsage {
+    pub video: u64,
+    pub images: u64,
+    /// External subtitle files
+    pub subtitles: u64,
+    /// Downloads in progress, interrupted upgrades, restores and encryption, and blobs nothing in the catalog points to
+    pub temp: u64,
+    /// The catalog and playback state
+    pub other: u64,
+}
+
+#[derive(Debug, Serialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageStats {
+    /// Size of every file in the storage directory
+    pub usage: u64,
+    pub track_count: usize,
+    pub by_type: Vec<TypeUsage>,
+    /// Top level containers such as series, collections and playlists, an item shared by two counts in both
+    pub by_container: Vec<ItemUsage>,
+    pub by_file_kind: FileKindUsage,
+    pub largest: Vec<ItemUsage>,
+    /// Space left on the volume holding the storage directory, `None` when the platform can't tell
+    pub free_space: Option<u64>,
+    pub quota: Option<u64>,
+}
+
+/// Sidecar subtitles are stored as `{id}.{media_source_id}.{index}.{extension}`
+pub const SUBTITLE_EXTENSIONS: [&str; 5] = ["srt", "vtt", "ass", "ssa", "sub"];
+
+/// Adds up the files in `storage_dir` and attributes them to the catalog entries they belong to
+pub fn collect_stats(
+    storage_dir: &Path,
+    metadata: &StorageMetadata,
+    quota: Option<u64>,
+    largest_count: usize,
+) -> std::io::Result<StorageStats> {
+    let mut stats = StorageStats {
+        track_count: metadata.tracks.len(),
+        free_space: fs2::available_space(storage_dir).ok(),
+        quota,
+        ..Default::default()
+    };
+
+    // Files are named after the item, `{id}.blob`, `{id}.{media_source_id}.blob` or `{id}.thumb`
+    let mut item_sizes: HashMap<&str, u64> = HashMap::new();
+
+    for entry in fs::read_dir(storage_dir)?.flatten() {
+        let Ok(file_metadata) = entry.metadata() else {
+            continue;
+        };
+        if !file_metadata.is_file() {
+            continue;
+        }
+
+        let size = file_metadata.len();
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let id = file_name.split('.').next().unwrap_or_default();
+        let extension = file_name.rsplit('.').next().unwrap_or_default();
+        let tracked = metadata.tracks.get_key_value(id).map(|(id, _)| id.as_str());
+
+        stats.usage += size;
+
+        match (extension, tracked) {
+            ("blob", Some(id)) => {
+                stats.by_file_kind.video += size;
+                *item_sizes.entry(id).or_default() += size;
+            }
+            ("thumb", Some(id)) => {
+                stats.by_file_kind.images += size;
+                *item_sizes.entry(id).or_default() += size;
+            }
+            ("thumb", None) => stats.by_file_kind.images += size,
+            (extension, _) if SUBTITLE_EXTENSIONS.contains(&extension) => stats.by_file_kind.subtitles += size,
+            ("json", _) => stats.by_file_kind.other += size,
+            _ => stats.by_file_kind.temp += size,
+        }
+    }
+
+    let index = tree::children_index(metadata);
+    let mut by_type: HashMap<&str, TypeUsage> = HashMap::new();
+    let mut items = Vec::new();
+
+    for (id, track) in &metadata.tracks {
+        let item_type = track.media_item.get("Type").and_then(|t| t.as_str()).unwrap_or_default();
+        let size = item_sizes.get(id.as_str()).copied().unwrap_or(0);
+
+        let usage = by_type.entry(item_type).or_insert_with(|| TypeUsage {
+            item_type: item_type.to_string(),
+            ..Default::default()
+        });
+        usage.count += 1;
+        usage.size += size;
+
+        if track.track_type == "container" {
+            if track.container_ids().next().is_none() {
+                let descendants: HashSet<String> = tree::descendants_in(&index, id).into_iter().collect();
+                let videos = descendants
+                    .iter()
+                    .filter(|child| metadata.tracks.get(*child).is_some_and(|t| t.track_type != "container"))
+                    .count();
+
+                stats.by_container.push(ItemUsage {
+                    count: videos,
+                    size: size + descendants.iter().filter_map(|child| item_sizes.get(child.as_str())).sum::<u64>(),
+                    ..item_usage(id, track, item_type, size)
+                });
+            }
+        } else {
+            items.push(item_usage(id, track, item_type, size));
+        }
+    }
+
+    stats.by_type = by_type.into_values().collect();
+    stats.by_type.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.item_type.cmp(&b.item_type)));
+
+    stats.by_container.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.id.cmp(&b.id)));
+
+    items.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.id.cmp(&b.id)));
+    items.truncate(largest_count);
+    stats.largest = items;
+
+    Ok(stats)
+}
+
+fn item_usage(id: &str, track: &StorageTrack, item_type: &str, size: u64) -> ItemUsage {
+    ItemUsage {
+        id: id.to_string(),
+        name: track
+            .media_item
+            .get("Name")
+            .and_then(|n| n.as_str())
+            .unwrap_or_default()
+            .to_string(),
+        item_type: item_type.to_string(),
+        count: 1,
+        size,
+    }
+}
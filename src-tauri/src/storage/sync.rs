@@ -0,0 +1,671 @@
+use super::catalog::remove_track_files;
+use super::crypto;
+use super::download::download_to_file;
+use super::jellyfin::{JellyfinClient, JellyfinError, ITEM_FIELDS};
+use super::playback::{get_user_data_snapshot, is_played, OfflineUserData};
+use super::tree::release;
+use super::{
+    claim_download, get_cached_metadata, get_storage_dir, mutate_metadata, now_millis, run_blocking,
+    DownloadManager, StorageMetadata, StorageTrack,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tauri::{AppHandle, Manager, State};
+use tokio_util::sync::CancellationToken;
+
+const SYNC_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// A container kept in sync with the server: the next unwatched episodes of a series or season,
+/// or every member of a playlist or collection
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncSubscription {
+    /// Ignored for playlists and collections, all of their members are kept downloaded
+    pub unwatched_count: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_synced: Option<i64>,
+    /// References the sync added to items. Only these are released when items leave the sync,
+    /// downloads the user made themselves stay.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub added: Vec<SyncReference>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncReference {
+    pub container_id: String,
+    pub item_id: String,
+}
+
+#[tauri::command]
+pub async fn storage_add_sync(
+    app: AppHandle,
+    download_manager: State<'_, DownloadManager>,
+    id: String,
+    unwatched_count: usize,
+) -> Result<(), String> {
+    let Some(auth) = download_manager.jellyfin_auth.lock().unwrap().clone() else {
+        return Err("Not connected to a server".to_string());
+    };
+
+    let client = JellyfinClient::new(auth);
+    let metadata = get_cached_metadata(&app, &download_manager).await.map_err(|e| e.to_string())?;
+
+    // Register the container itself the same way a season download does
+    let container = if metadata.tracks.contains_key(&id) {
+        None
+    } else {
+        let mut item = client
+            .get_items_by_ids(std::slice::from_ref(&id))
+            .await
+            .map_err(|e| e.to_string())?
+            .remove(&id)
+            .ok_or("Item not found on server")?;
+
+        let item_type = item.get("Type").and_then(|t| t.as_str());
+        if !matches!(item_type, Some("Series") | Some("Season") | Some("Playlist") | Some("BoxSet")) {
+            return Err("Only series, seasons, playlists and collections can be synced".to_string());
+        }
+
+        if let Some(obj) = item.as_object_mut() {
+            obj.insert("offlineState".to_string(), "downloaded".into());
+        }
+
+        let has_thumbnail = save_thumbnail(&app, &client, &id).await;
+
+        Some(StorageTrack {
+            track_type: "container".to_string(),
+            timestamp: now_millis(),
+            media_item: item,
+            bitrate: 0,
+            container_id: None,
+            media_sources: None,
+            media_source_id: None,
+            size: None,
+            server_status: None,
+            references: Vec::new(),
+            direct: false,
+            versions: Vec::new(),
+            has_thumbnail: Some(has_thumbnail),
+        })
+    };
+
+    mutate_metadata(&app, &download_manager, |metadata| {
+        if let Some(container) = container {
+            metadata.tracks.insert(id.clone(), container);
+        }
+        metadata.subscriptions.insert(
+            id,
+            SyncSubscription {
+                unwatched_count,
+                last_synced: None,
+                added: Vec::new(),
+            },
+        );
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) = run_series_sync(&app).await {
+            println!("storage_add_sync: {}", e);
+        }
+    });
+
+    Ok(())
+}
+
+/// Stops syncing, items that were already downloaded are kept
+#[tauri::command]
+pub async fn storage_remove_sync(
+    app: AppHandle,
+    download_manager: State<'_, DownloadManager>,
+    id: String,
+) -> Result<(), String> {
+    mutate_metadata(&app, &download_manager, |metadata| {
+        metadata.subscriptions.remove(&id);
+    })
+    .await
+    .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn storage_get_syncs(
+    app: AppHandle,
+    download_manager: State<'_, DownloadManager>,
+) -> Result<HashMap<String, SyncSubscription>, String> {
+    let metadata = get_cached_metadata(&app, &download_manager).await.map_err(|e| e.to_string())?;
+    Ok(metadata.subscriptions)
+}
+
+#[tauri::command]
+pub async fn storage_run_sync(app: AppHandle) -> Result<(), String> {
+    run_series_sync(&app).await
+}
+
+pub async fn run_series_sync(app: &AppHandle) -> Result<(), String> {
+    let download_manager = app.state::<DownloadManager>();
+
+    let Some(auth) = download_manager.jellyfin_auth.lock().unwrap().clone() else {
+        return Err("Not connected to a server".to_string());
+    };
+
+    if download_manager.syncing_series.swap(true, Ordering::SeqCst) {
+        return Ok(());
+    }
+
+    let cancel_token = CancellationToken::new();
+    *download_manager.sync_cancellation_token.lock().unwrap() = Some(cancel_token.clone());
+
+    let result = async {
+        let client = JellyfinClient::new(auth);
+        let subscriptions = get_cached_metadata(app, &download_manager)
+            .await
+            .map_err(|e| e.to_string())?
+            .subscriptions;
+
+        for (id, subscription) in subscriptions {
+            if cancel_token.is_cancelled() {
+                break;
+            }
+
+            match sync_subscription(app, &client, &id, &subscription, &cancel_token).await {
+                Ok(()) => {}
+                Err(JellyfinError::Unreachable(e)) => return Err(format!("Server unreachable: {}", e)),
+                Err(e) => println!("run_series_sync: Failed to sync id {}: {}", id, e),
+            }
+        }
+
+        Ok(())
+    }
+    .await;
+
+    *download_manager.sync_cancellation_token.lock().unwrap() = None;
+    download_manager.syncing_series.store(false, Ordering::SeqCst);
+    download_manager.sync_finished.notify_waiters();
+    result
+}
+
+async fn sync_subscription(
+    app: &AppHandle,
+    client: &JellyfinClient,
+    id: &str,
+    subscription: &SyncSubscription,
+    cancel_token: &CancellationToken,
+) -> Result<(), JellyfinError> {
+    let download_manager = app.state::<DownloadManager>();
+    let metadata = get_cached_metadata(app, &download_manager).await?;
+
+    let Some(container) = metadata.tracks.get(id) else {
+        return Ok(());
+    };
+
+    let complete = match container.media_item.get("Type").and_then(|t| t.as_str()) {
+        Some("Series") => sync_episodes(app, client, id, None, subscription, cancel_token).await?,
+        Some("Season") => match container.media_item.get("SeriesId").and_then(|s| s.as_str()) {
+            Some(series_id) => sync_episodes(app, client, series_id, Some(id), subscription, cancel_token).await?,
+            None => return Err(JellyfinError::Invalid("Season without a series".to_string())),
+        },
+        Some(item_type @ ("Playlist" | "BoxSet")) => sync_members(app, client, id, item_type, cancel_token).await?,
+        _ => return Err(JellyfinError::Invalid("Not a series, season, playlist or collection".to_string())),
+    };
+
+    // Only synced once everything wanted is on disk, the next run retries what failed
+    if !complete {
+        return Ok(());
+    }
+
+    mutate_metadata(app, &download_manager, |metadata| {
+        if let Some(subscription) = metadata.subscriptions.get_mut(id) {
+            subscription.last_synced = Some(now_millis());
+        }
+    })
+    .await?;
+
+    Ok(())
+}
+
+/// Returns whether every wanted episode is on disk
+async fn sync_episodes(
+    app: &AppHandle,
+    client: &JellyfinClient,
+    series_id: &str,
+    season_id: Option<&str>,
+    subscription: &SyncSubscription,
+    cancel_token: &CancellationToken,
+) -> Result<bool, JellyfinError> {
+    let id = season_id.unwrap_or(series_id);
+    let download_manager = app.state::<DownloadManager>();
+
+    let mut query = vec![
+        ("userId", client.user_id()),
+        ("fields", ITEM_FIELDS),
+        ("isMissing", "false"),
+    ];
+    if let Some(season_id) = season_id {
+        query.push(("seasonId", season_id));
+    }
+
+    let response = client.get_json(&format!("Shows/{}/Episodes", series_id), &query).await?;
+    let episodes = items_of(&response);
+
+    let user_data = get_user_data_snapshot(app, &download_manager).await?;
+    let wanted = sync_window(&episodes, &user_data, subscription.unwatched_count);
+
+    let wanted_ids: HashSet<&str> = wanted
+        .iter()
+        .filter_map(|episode| episode.get("Id")?.as_str())
+        .collect();
+
+    // Episodes the sync downloaded that were watched or fell out of the window, e.g. after the count was
+    // lowered, make room for the next ones
+    let outside = references_outside(&get_cached_metadata(app, &download_manager).await?, id, &wanted_ids);
+
+    if !outside.is_empty() {
+        remove_references(app, id, &outside).await?;
+        println!(
+            "sync_episodes: Released {} episodes outside the next {} of {}",
+            outside.len(),
+            subscription.unwatched_count,
+            id
+        );
+    }
+
+    let mut complete = true;
+    for episode in wanted {
+        if cancel_token.is_cancelled() {
+            return Ok(false);
+        }
+
+        // Episodes of a synced series go into a season container below it
+        let container_id = match (season_id, episode.get("SeasonId").and_then(|s| s.as_str())) {
+            (None, Some(episode_season_id)) => {
+                ensure_season_container(app, client, episode_season_id, id).await?;
+                episode_season_id
+            }
+            _ => id,
+        };
+
+        complete &= download_item(app, client, episode, container_id, id, cancel_token).await?;
+    }
+
+    Ok(complete)
+}
+
+/// Mirrors the member list of a playlist or collection, only items the server can stream directly are downloaded.
+/// Returns whether every member is on disk.
+async fn sync_members(
+    app: &AppHandle,
+    client: &JellyfinClient,
+    id: &str,
+    item_type: &str,
+    cancel_token: &CancellationToken,
+) -> Result<bool, JellyfinError> {
+    let download_manager = app.state::<DownloadManager>();
+
+    let response = match item_type {
+        "Playlist" => {
+            client
+                .get_json(&format!("Playlists/{}/Items", id), &[("userId", client.user_id()), ("fields", ITEM_FIELDS)])
+                .await?
+        }
+        // Collections aren't playlists, their members are plain children
+        _ => {
+            client
+                .get_json("Items", &[("userId", client.user_id()), ("parentId", id), ("fields", ITEM_FIELDS)])
+                .await?
+        }
+    };
+
+    let items = items_of(&response);
+    let members: Vec<&serde_json::Value> = items
+        .iter()
+        .filter(|item| has_media_sources(item))
+        .collect();
+    let member_ids: HashSet<&str> = members
+        .iter()
+        .filter_map(|item| item.get("Id")?.as_str())
+        .collect();
+
+    // Items the sync downloaded that were taken out of the container on the server
+    let removed = references_outside(&get_cached_metadata(app, &download_manager).await?, id, &member_ids);
+
+    if !removed.is_empty() {
+        remove_references(app, id, &removed).await?;
+        println!("sync_members: Released {} items no longer in {}", removed.len(), id);
+    }
+
+    let mut complete = true;
+    for member in members {
+        if cancel_token.is_cancelled() {
+            return Ok(false);
+        }
+
+        complete &= download_item(app, client, member, id, id, cancel_token).await?;
+    }
+
+    Ok(complete)
+}
+
+fn items_of(response: &serde_json::Value) -> Vec<serde_json::Value> {
+    response
+        .get("Items")
+        .and_then(|items| items.as_array())
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// The next `count` unwatched episodes with media to download, in order. "Next" means after the last episode that
+/// was watched, skipped earlier episodes don't count.
+pub fn sync_window<'a>(
+    episodes: &'a [serde_json::Value],
+    user_data: &HashMap<String, OfflineUserData>,
+    count: usize,
+) -> Vec<&'a serde_json::Value> {
+    let played = |episode: &serde_json::Value| {
+        let user_data = episode.get("Id").and_then(|id| id.as_str()).and_then(|id| user_data.get(id));
+        is_played(episode, user_data)
+    };
+    let start = episodes
+        .iter()
+        .rposition(&played)
+        .map_or(0, |index| index + 1);
+
+    episodes[start..]
+        .iter()
+        .filter(|episode| !played(episode) && has_media_sources(episode))
+        .take(count)
+        .collect()
+}
+
+fn has_media_sources(item: &serde_json::Value) -> bool {
+    item.get("MediaSources")
+        .and_then(|s| s.as_array())
+        .is_some_and(|s| !s.is_empty())
+}
+
+/// References the sync of `subscription_id` added to items that aren't in `keep`. Containers stay, an empty
+/// season is kept like one emptied by hand.
+pub fn references_outside(
+    metadata: &StorageMetadata,
+    subscription_id: &str,
+    keep: &HashSet<&str>,
+) -> Vec<SyncReference> {
+    let Some(subscription) = metadata.subscriptions.get(subscription_id) else {
+        return Vec::new();
+    };
+
+    subscription
+        .added
+        .iter()
+        .filter(|reference| !keep.contains(reference.item_id.as_str()))
+        .filter(|reference| {
+            metadata
+                .tracks
+                .get(&reference.item_id)
+                .is_some_and(|track| track.track_type != "container")
+        })
+        .cloned()
+        .collect()
+}
+
+/// Drops `references` if the sync of `subscription_id` still holds them and returns the items nothing
+/// references anymore
+pub fn release_references(
+    metadata: &mut StorageMetadata,
+    subscription_id: &str,
+    references: &[SyncReference],
+) -> Vec<String> {
+    let mut removed = Vec::new();
+
+    for reference in references {
+        let Some(subscription) = metadata.subscriptions.get_mut(subscription_id) else {
+            break;
+        };
+        let Some(index) = subscription.added.iter().position(|added| added == reference) else {
+            continue;
+        };
+        subscription.added.remove(index);
+        removed.extend(release(metadata, &reference.item_id, Some(&reference.container_id)));
+    }
+
+    removed
+}
+
+/// Records a reference the sync of `subscription_id` added
+fn record_reference(metadata: &mut StorageMetadata, subscription_id: &str, container_id: &str, item_id: &str) {
+    let Some(subscription) = metadata.subscriptions.get_mut(subscription_id) else {
+        return;
+    };
+    let reference = SyncReference {
+        container_id: container_id.to_string(),
+        item_id: item_id.to_string(),
+    };
+    if !subscription.added.contains(&reference) {
+        subscription.added.push(reference);
+    }
+}
+
+/// Releases references the sync added, files are only deleted for items nothing else references
+async fn remove_references(
+    app: &AppHandle,
+    subscription_id: &str,
+    references: &[SyncReference],
+) -> Result<(), JellyfinError> {
+    let download_manager = app.state::<DownloadManager>();
+    let storage_dir = get_storage_dir(app)?;
+
+    // Out of the catalog first, nothing can pick up the files while they're deleted
+    let removed = mutate_metadata(app, &download_manager, |metadata| {
+        release_references(metadata, subscription_id, references)
+    })
+    .await?;
+
+    run_blocking(move || {
+        if let Err(e) = remove_track_files(&storage_dir, &removed) {
+            println!("remove_references: Failed to remove files of {} items: {}", removed.len(), e);
+        }
+        Ok(())
+    })
+    .await?;
+
+    Ok(())
+}
+
+/// Downloads a single item into `container_id` for the sync of `subscription_id`. Items that are already stored,
+/// e.g. through another container, only get a reference so the blob is shared.
+/// Returns whether the item is on disk, a failed download is logged and left for the next sync.
+async fn download_item(
+    app: &AppHandle,
+    client: &JellyfinClient,
+    item: &serde_json::Value,
+    container_id: &str,
+    subscription_id: &str,
+    cancel_token: &CancellationToken,
+) -> Result<bool, JellyfinError> {
+    let Some(item_id) = item.get("Id").and_then(|i| i.as_str()) else {
+        return Ok(true);
+    };
+
+    let download_manager = app.state::<DownloadManager>();
+    let claim = mutate_metadata(app, &download_manager, |metadata| {
+        if add_sync_reference(metadata, subscription_id, container_id, item_id) {
+            return Ok(None);
+        }
+        claim_download(&download_manager, item_id).map(Some).ok_or(())
+    })
+    .await?;
+    let _claim = match claim {
+        Ok(Some(claim)) => claim,
+        Ok(None) => return Ok(true),
+        Err(()) => {
+            println!("download_item: {} is already being downloaded, left for the next sync", item_id);
+            return Ok(false);
+        }
+    };
+
+    println!("download_item: Downloading {} for {}", item_id, container_id);
+
+    // Only renamed into place once complete, a partial file never sits at the blob path
+    let storage_dir = get_storage_dir(app)?;
+    let part_path = storage_dir.join(format!("{}.blob.part", item_id));
+    let url = client.stream_url(item_id, item_id);
+    let key = crypto::encryption_key(app)?;
+    let downloaded = match download_to_file(&reqwest::Client::new(), &url, &part_path, key.as_ref(), cancel_token, |_, _| {}).await {
+        Ok(downloaded) => downloaded,
+        Err(e) => {
+            println!("download_item: Failed to download {}: {}", item_id, e);
+            return Ok(false);
+        }
+    };
+    if let Err(e) = tokio::fs::rename(&part_path, storage_dir.join(format!("{}.blob", item_id))).await {
+        println!("download_item: Failed to move {} into place: {}", item_id, e);
+        let _ = tokio::fs::remove_file(&part_path).await;
+        return Ok(false);
+    }
+
+    let has_thumbnail = save_thumbnail(app, client, item_id).await;
+
+    let mut media_item = item.clone();
+    let media_sources = media_item.as_object_mut().and_then(|obj| {
+        obj.insert("offlineState".to_string(), "downloaded".into());
+        obj.remove("MediaSources")
+    });
+    let bitrate = media_sources
+        .as_ref()
+        .and_then(|sources| sources.pointer("/0/Bitrate"))
+        .and_then(|b| b.as_i64())
+        .unwrap_or(0) as i32;
+
+    let track = StorageTrack {
+        track_type: "video".to_string(),
+        timestamp: now_millis(),
+        media_item,
+        bitrate,
+        container_id: Some(container_id.to_string()),
+        media_sources,
+        media_source_id: None,
+        size: Some(downloaded),
+        server_status: None,
+        references: Vec::new(),
+        direct: false,
+        versions: Vec::new(),
+        has_thumbnail: Some(has_thumbnail),
+    };
+    // The claim kept other writers away, the item can't have been stored in the meantime
+    mutate_metadata(app, &download_manager, |metadata| {
+        metadata.tracks.insert(item_id.to_string(), track);
+        record_reference(metadata, subscription_id, container_id, item_id);
+    })
+    .await?;
+
+    Ok(true)
+}
+
+/// Gives a stored item `container_id`'s reference, recorded for the sync unless the container already held it.
+/// Returns false when the item isn't stored.
+fn add_sync_reference(metadata: &mut StorageMetadata, subscription_id: &str, container_id: &str, item_id: &str) -> bool {
+    let Some(track) = metadata.tracks.get_mut(item_id) else {
+        return false;
+    };
+
+    if !track.container_ids().any(|holder| holder == container_id) {
+        track.add_reference(Some(container_id));
+        record_reference(metadata, subscription_id, container_id, item_id);
+    }
+    true
+}
+
+async fn ensure_season_container(
+    app: &AppHandle,
+    client: &JellyfinClient,
+    season_id: &str,
+    series_id: &str,
+) -> Result<(), JellyfinError> {
+    let download_manager = app.state::<DownloadManager>();
+
+    let stored = mutate_metadata(app, &download_manager, |metadata| match metadata.tracks.get_mut(season_id) {
+        Some(season) => {
+            if !season.container_ids().any(|holder| holder == series_id) {
+                season.add_reference(Some(series_id));
+            }
+            true
+        }
+        None => false,
+    })
+    .await?;
+    if stored {
+        return Ok(());
+    }
+
+    let Some(mut season) = client.get_items_by_ids(&[season_id.to_string()]).await?.remove(season_id) else {
+        return Err(JellyfinError::Invalid(format!("Season {} not found", season_id)));
+    };
+
+    if let Some(obj) = season.as_object_mut() {
+        obj.insert("offlineState".to_string(), "downloaded".into());
+    }
+
+    let has_thumbnail = save_thumbnail(app, client, season_id).await;
+
+    let container = StorageTrack {
+        track_type: "container".to_string(),
+        timestamp: now_millis(),
+        media_item: season,
+        bitrate: 0,
+        container_id: Some(series_id.to_string()),
+        media_sources: None,
+        media_source_id: None,
+        size: None,
+        server_status: None,
+        references: Vec::new(),
+        direct: false,
+        versions: Vec::new(),
+        has_thumbnail: Some(has_thumbnail),
+    };
+    mutate_metadata(app, &download_manager, |metadata| {
+        metadata.tracks.entry(season_id.to_string()).or_insert(container);
+    })
+    .await?;
+
+    Ok(())
+}
+
+/// Returns whether the artwork was saved
+pub(super) async fn save_thumbnail(app: &AppHandle, client: &JellyfinClient, id: &str) -> bool {
+    let result = match client.get_thumbnail(id).await {
+        Ok(data) => {
+            let app = app.clone();
+            let id = id.to_string();
+            run_blocking(move || crypto::write_file(&app, &get_storage_dir(&app)?.join(format!("{}.thumb", id)), &data))
+                .await
+                .map_err(|e| e.to_string())
+        }
+        Err(e) => Err(e.to_string()),
+    };
+
+    if let Err(e) = &result {
+        println!("save_thumbnail: Failed for id {}: {}", id, e);
+    }
+    result.is_ok()
+}
+
+/// Keeps subscriptions up to date as episodes are watched, aired or added to the server
+pub fn spawn_series_sync(app: AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        loop {
+            tokio::time::sleep(SYNC_INTERVAL).await;
+
+            let download_manager = app.state::<DownloadManager>();
+            if download_manager.jellyfin_auth.lock().unwrap().is_none() {
+                continue;
+            }
+
+            if let Err(e) = run_series_sync(&app).await {
+                println!("spawn_series_sync: {}", e);
+            }
+        }
+    });
+}
@@ -0,0 +1,187 @@
+use super::reconcile::blob_size;
+use super::{get_cached_metadata, get_storage_dir, run_blocking, DownloadManager, StorageMetadata, StorageTrack};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use tauri::{AppHandle, State};
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ContainerNode {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub track_type: String,
+    pub media_item: serde_json::Value,
+    /// Downloaded videos in this node and everything below it
+    pub download_count: usize,
+    /// Size in bytes of those downloaded videos
+    pub total_size: u64,
+    pub children: Vec<ContainerNode>,
+}
+
+/// Maps every container id to the ids of its direct children
+pub fn children_index(metadata: &StorageMetadata) -> HashMap<&str, Vec<&str>> {
+    let mut index: HashMap<&str, Vec<&str>> = HashMap::new();
+
+    for (id, track) in &metadata.tracks {
+        for container_id in track.container_ids() {
+            index.entry(container_id).or_default().push(id);
+        }
+    }
+
+    index
+}
+
+/// All ids below `id` at any depth, not including `id` itself
+pub fn descendants(metadata: &StorageMetadata, id: &str) -> Vec<String> {
+    descendants_in(&children_index(metadata), id)
+}
+
+/// `descendants` with a prebuilt index, for callers walking many containers
+pub fn descendants_in(index: &HashMap<&str, Vec<&str>>, id: &str) -> Vec<String> {
+    let mut visited: HashSet<&str> = HashSet::from([id]);
+    let mut stack = vec![id];
+    let mut result = Vec::new();
+
+    while let Some(current) = stack.pop() {
+        for child in index.get(current).into_iter().flatten() {
+            // A broken catalog could contain a cycle, don't loop forever on it
+            if visited.insert(child) {
+                result.push(child.to_string());
+                stack.push(child);
+            }
+        }
+    }
+
+    result
+}
+
+/// True when `container_id` is `id` or one of its descendants, storing it would create a cycle
+pub fn creates_cycle(metadata: &StorageMetadata, id: &str, container_id: &str) -> bool {
+    let mut visited = HashSet::new();
+    let mut stack = vec![container_id];
+
+    while let Some(parent) = stack.pop() {
+        if parent == id {
+            return true;
+        }
+        if visited.insert(parent) {
+            stack.extend(metadata.tracks.get(parent).into_iter().flat_map(|t| t.container_ids()));
+        }
+    }
+
+    false
+}
+
+/// Drops the reference `container_id` (or the direct download when `None`) holds on `id`.
+/// Entries nothing references anymore are removed from the catalog and a removed container
+/// releases its own children in turn. Returns the ids whose files can be deleted.
+pub fn release(metadata: &mut StorageMetadata, id: &str, container_id: Option<&str>) -> Vec<String> {
+    let mut removed = Vec::new();
+    let mut stack = vec![(id.to_string(), container_id.map(str::to_string))];
+
+    while let Some((id, container_id)) = stack.pop() {
+        let Some(track) = metadata.tracks.get_mut(&id) else {
+            continue;
+        };
+        if !track.release(container_id.as_deref()) {
+            continue;
+        }
+
+        metadata.tracks.remove(&id);
+        metadata.subscriptions.remove(&id);
+
+        let children: Vec<String> = metadata
+            .tracks
+            .iter()
+            .filter(|(_, track)| track.container_ids().any(|holder| holder == id))
+            .map(|(child, _)| child.clone())
+            .collect();
+        stack.extend(children.into_iter().map(|child| (child, Some(id.clone()))));
+        removed.push(id);
+    }
+
+    removed
+}
+
+/// Size of every stored version of the item
+fn track_size(storage_dir: &Path, id: &str, track: &StorageTrack) -> u64 {
+    let preferred = track
+        .size
+        .or_else(|| blob_size(&storage_dir.join(format!("{}.blob", id))))
+        .unwrap_or(0);
+
+    preferred + track.versions.iter().filter_map(|version| version.size).sum::<u64>()
+}
+
+/// Season/episode order, falling back to the name for items without an index
+fn sort_key(track: &StorageTrack) -> (i64, i64, String) {
+    let item = &track.media_item;
+    (
+        item.get("ParentIndexNumber").and_then(|n| n.as_i64()).unwrap_or(i64::MAX),
+        item.get("IndexNumber").and_then(|n| n.as_i64()).unwrap_or(i64::MAX),
+        item.get("SortName")
+            .or_else(|| item.get("Name"))
+            .and_then(|n| n.as_str())
+            .unwrap_or_default()
+            .to_string(),
+    )
+}
+
+fn build_node(
+    metadata: &StorageMetadata,
+    index: &HashMap<&str, Vec<&str>>,
+    storage_dir: &Path,
+    id: &str,
+    visited: &mut HashSet<String>,
+) -> Option<ContainerNode> {
+    let track = metadata.tracks.get(id)?;
+
+    if !visited.insert(id.to_string()) {
+        return None;
+    }
+
+    let mut children_ids: Vec<&str> = index.get(id).cloned().unwrap_or_default();
+    children_ids.sort_by_key(|child| metadata.tracks.get(*child).map(sort_key));
+
+    let children: Vec<ContainerNode> = children_ids
+        .into_iter()
+        .filter_map(|child| build_node(metadata, index, storage_dir, child, visited))
+        .collect();
+
+    // Only the current path counts, an item shared by two containers shows up below both
+    visited.remove(id);
+
+    let (own_count, own_size) = if track.track_type == "container" {
+        (0, 0)
+    } else {
+        (1, track_size(storage_dir, id, track))
+    };
+
+    Some(ContainerNode {
+        id: id.to_string(),
+        track_type: track.track_type.clone(),
+        media_item: track.media_item.clone(),
+        download_count: own_count + children.iter().map(|c| c.download_count).sum::<usize>(),
+        total_size: own_size + children.iter().map(|c| c.total_size).sum::<u64>(),
+        children,
+    })
+}
+
+#[tauri::command]
+pub async fn storage_get_container_tree(
+    app: AppHandle,
+    download_manager: State<'_, DownloadManager>,
+    id: String,
+) -> Result<Option<ContainerNode>, String> {
+    let storage_dir = get_storage_dir(&app).map_err(|e| e.to_string())?;
+    let metadata = get_cached_metadata(&app, &download_manager).await.map_err(|e| e.to_string())?;
+
+    // Items without a recorded size are measured on disk
+    run_blocking(move || {
+        let index = children_index(&metadata);
+        Ok(build_node(&metadata, &index, &storage_dir, &id, &mut HashSet::new()))
+    })
+    .await
+    .map_err(|e| e.to_string())
+}
@@ -0,0 +1,163 @@
+use super::crypto;
+use super::download::download_to_file;
+use super::jellyfin::JellyfinClient;
+use super::{claim_download, get_storage_dir, mutate_metadata, DownloadManager, StorageTrack};
+use std::sync::atomic::Ordering;
+use tauri::{AppHandle, Manager};
+use tokio_util::sync::CancellationToken;
+
+/// A better media source found for a downloaded item
+#[derive(Debug, Clone)]
+pub struct UpgradeCandidate {
+    pub id: String,
+    pub media_source_id: String,
+    pub size: Option<u64>,
+}
+
+/// Height of the video stream and bitrate of a media source, compared in that order
+fn source_quality(source: &serde_json::Value) -> (i64, i64) {
+    let height = source
+        .get("MediaStreams")
+        .and_then(|streams| streams.as_array())
+        .and_then(|streams| {
+            streams
+                .iter()
+                .find(|stream| stream.get("Type").and_then(|t| t.as_str()) == Some("Video"))
+        })
+        .and_then(|stream| stream.get("Height"))
+        .and_then(|h| h.as_i64())
+        .unwrap_or(0);
+    let bitrate = source.get("Bitrate").and_then(|b| b.as_i64()).unwrap_or(0);
+
+    (height, bitrate)
+}
+
+/// Looks for a server source with a higher resolution, or the same resolution at a higher bitrate,
+/// than what we downloaded. Must run before `media_sources` is replaced with the server's sources.
+pub fn find_better_source(id: &str, track: &StorageTrack, fresh: &serde_json::Value) -> Option<UpgradeCandidate> {
+    // Items with several versions were picked deliberately, leave them alone
+    if track.track_type == "container" || !track.versions.is_empty() {
+        return None;
+    }
+
+    let source_id = track.media_source_id.as_deref().unwrap_or(id);
+    let stored_source = track
+        .media_sources
+        .as_ref()
+        .and_then(|sources| sources.as_array())
+        .and_then(|sources| {
+            sources
+                .iter()
+                .find(|source| source.get("Id").and_then(|s| s.as_str()) == Some(source_id))
+        });
+
+    let (stored_height, stored_bitrate) = stored_source.map(source_quality).unwrap_or_default();
+    let stored_bitrate = if stored_bitrate > 0 { stored_bitrate } else { track.bitrate as i64 };
+
+    fresh
+        .get("MediaSources")
+        .and_then(|sources| sources.as_array())?
+        .iter()
+        .filter(|source| source_quality(source) > (stored_height, stored_bitrate))
+        .max_by_key(|source| source_quality(source))
+        .and_then(|source| {
+            Some(UpgradeCandidate {
+                id: id.to_string(),
+                media_source_id: source.get("Id")?.as_str()?.to_string(),
+                size: source.get("Size").and_then(|s| s.as_u64()),
+            })
+        })
+}
+
+/// Downloads the better sources one by one in the background.
+/// The new file is written next to the old blob and only renamed over it once complete and verified.
+pub fn spawn_upgrades(app: AppHandle, client: JellyfinClient, candidates: Vec<UpgradeCandidate>) {
+    let cancel_token = {
+        let download_manager = app.state::<DownloadManager>();
+
+        if candidates.is_empty() || download_manager.upgrading.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let cancel_token = CancellationToken::new();
+        *download_manager.upgrade_cancellation_token.lock().unwrap() = Some(cancel_token.clone());
+        cancel_token
+    };
+
+    tauri::async_runtime::spawn(async move {
+        for candidate in candidates {
+            if cancel_token.is_cancelled() {
+                break;
+            }
+
+            match upgrade_item(&app, &client, &candidate, &cancel_token).await {
+                Ok(()) => println!(
+                    "spawn_upgrades: Upgraded id {} to media source {}",
+                    candidate.id, candidate.media_source_id
+                ),
+                Err(e) => println!("spawn_upgrades: Failed to upgrade id {}: {}", candidate.id, e),
+            }
+        }
+
+        let download_manager = app.state::<DownloadManager>();
+        *download_manager.upgrade_cancellation_token.lock().unwrap() = None;
+        download_manager.upgrading.store(false, Ordering::SeqCst);
+    });
+}
+
+async fn upgrade_item(
+    app: &AppHandle,
+    client: &JellyfinClient,
+    candidate: &UpgradeCandidate,
+    cancel_token: &CancellationToken,
+) -> Result<(), String> {
+    let storage_dir = get_storage_dir(app).map_err(|e| e.to_string())?;
+    let blob_path = storage_dir.join(format!("{}.blob", candidate.id));
+    let upgrade_path = storage_dir.join(format!("{}.blob.upgrade", candidate.id));
+
+    // Without a size from the server there is nothing to verify the new file against
+    let Some(expected_size) = candidate.size else {
+        return Err(format!("Server doesn't report a size for media source {}", candidate.media_source_id));
+    };
+
+    let url = client.stream_url(&candidate.id, &candidate.media_source_id);
+    let key = crypto::encryption_key(app).map_err(|e| e.to_string())?;
+    let downloaded = download_to_file(&reqwest::Client::new(), &url, &upgrade_path, key.as_ref(), cancel_token, |_, _| {}).await?;
+
+    if downloaded != expected_size {
+        let _ = tokio::fs::remove_file(&upgrade_path).await;
+        return Err(format!("Size mismatch: server reported {} bytes, got {}", expected_size, downloaded));
+    }
+
+    // A save or import of the item can't write the blob while it's swapped
+    let download_manager = app.state::<DownloadManager>();
+    let Some(_claim) = claim_download(&download_manager, &candidate.id) else {
+        let _ = tokio::fs::remove_file(&upgrade_path).await;
+        return Err("Item is being downloaded".to_string());
+    };
+
+    // The catalog keeps describing the old file until the new one is in place
+    if let Err(e) = tokio::fs::rename(&upgrade_path, &blob_path).await {
+        let _ = tokio::fs::remove_file(&upgrade_path).await;
+        return Err(format!("Failed to move the new file into place: {}", e));
+    }
+
+    let upgraded = mutate_metadata(app, &download_manager, |metadata| match metadata.tracks.get_mut(&candidate.id) {
+        Some(track) => {
+            track.media_source_id = Some(candidate.media_source_id.clone());
+            track.size = Some(downloaded);
+            track.server_status = None;
+            true
+        }
+        None => false,
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+
+    // Removed while we were downloading, its files were deleted before the new one moved in
+    if !upgraded {
+        let _ = tokio::fs::remove_file(&blob_path).await;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,150 @@
+use super::{claim_download, get_cached_metadata, get_storage_dir, mutate_metadata, DownloadManager, StorageTrack};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+use tauri::{AppHandle, State};
+
+/// Another version of an item, stored next to the preferred one as `{id}.{media_source_id}.blob`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageVersion {
+    pub media_source_id: String,
+    pub timestamp: i64,
+    pub bitrate: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+}
+
+/// Media source of the preferred version, the one stored as `{id}.blob`
+pub fn preferred_source_id<'a>(id: &'a str, track: &'a StorageTrack) -> &'a str {
+    track.media_source_id.as_deref().unwrap_or(id)
+}
+
+pub fn has_version(id: &str, track: &StorageTrack, media_source_id: &str) -> bool {
+    preferred_source_id(id, track) == media_source_id
+        || track.versions.iter().any(|version| version.media_source_id == media_source_id)
+}
+
+/// Item and media source ids are Jellyfin GUIDs, anything else could be used to escape the storage directory
+pub fn is_valid_id(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+/// Both ids end up in the file name, so they're checked here rather than trusted from the caller
+pub fn version_path(storage_dir: &Path, id: &str, media_source_id: &str) -> io::Result<PathBuf> {
+    if !is_valid_id(id) || !is_valid_id(media_source_id) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid media source {} of {}", media_source_id, id),
+        ));
+    }
+    Ok(storage_dir.join(format!("{}.{}.blob", id, media_source_id)))
+}
+
+/// File of the requested version, the preferred one when `media_source_id` is `None`
+pub fn blob_path(storage_dir: &Path, id: &str, track: &StorageTrack, media_source_id: Option<&str>) -> Option<PathBuf> {
+    match media_source_id {
+        Some(source) if source != preferred_source_id(id, track) => track
+            .versions
+            .iter()
+            .any(|version| version.media_source_id == source)
+            .then(|| version_path(storage_dir, id, source).ok())
+            .flatten(),
+        _ => Some(storage_dir.join(format!("{}.blob", id))),
+    }
+}
+
+/// All stored versions of an item, the preferred one first
+#[tauri::command]
+pub async fn storage_get_versions(
+    app: AppHandle,
+    download_manager: State<'_, DownloadManager>,
+    id: String,
+) -> Result<Vec<StorageVersion>, String> {
+    let metadata = get_cached_metadata(&app, &download_manager).await.map_err(|e| e.to_string())?;
+
+    let Some(track) = metadata.tracks.get(&id).filter(|track| track.track_type != "container") else {
+        return Ok(Vec::new());
+    };
+
+    let preferred = StorageVersion {
+        media_source_id: preferred_source_id(&id, track).to_string(),
+        timestamp: track.timestamp,
+        bitrate: track.bitrate,
+        size: track.size,
+    };
+
+    Ok(std::iter::once(preferred).chain(track.versions.iter().cloned()).collect())
+}
+
+/// Deletes one version of an item. When the preferred version goes the oldest remaining one takes its place,
+/// the last version can only go by removing the item.
+#[tauri::command]
+pub async fn storage_remove_version(
+    app: AppHandle,
+    download_manager: State<'_, DownloadManager>,
+    id: String,
+    media_source_id: String,
+) -> Result<(), String> {
+    let storage_dir = get_storage_dir(&app).map_err(|e| e.to_string())?;
+    let path = version_path(&storage_dir, &id, &media_source_id).map_err(|e| e.to_string())?;
+
+    // A save or import of the item can't write its files while versions move around
+    let _claim = claim_download(&download_manager, &id).ok_or(format!("{} is being downloaded", id))?;
+
+    let metadata = get_cached_metadata(&app, &download_manager).await.map_err(|e| e.to_string())?;
+    let Some(track) = metadata.tracks.get(&id) else {
+        return Ok(());
+    };
+
+    if track.versions.iter().any(|version| version.media_source_id == media_source_id) {
+        // Out of the catalog first, nothing can stream the file while it's deleted
+        mutate_metadata(&app, &download_manager, |metadata| {
+            if let Some(track) = metadata.tracks.get_mut(&id) {
+                track.versions.retain(|version| version.media_source_id != media_source_id);
+            }
+        })
+        .await
+        .map_err(|e| e.to_string())?;
+
+        if tokio::fs::try_exists(&path).await.unwrap_or(false) {
+            tokio::fs::remove_file(path).await.map_err(|e| e.to_string())?;
+        }
+        return Ok(());
+    }
+
+    if preferred_source_id(&id, track) != media_source_id {
+        return Ok(());
+    }
+    let Some(next) = track.versions.first().map(|version| version.media_source_id.clone()) else {
+        return Err(format!("{} is the only version of {}, remove the item instead", media_source_id, id));
+    };
+
+    // The file moves first, the catalog keeps describing the old one until the next version is in place
+    let blob_path = storage_dir.join(format!("{}.blob", id));
+    let next_path = version_path(&storage_dir, &id, &next).map_err(|e| e.to_string())?;
+    tokio::fs::rename(next_path, &blob_path).await.map_err(|e| e.to_string())?;
+
+    let promoted = mutate_metadata(&app, &download_manager, |metadata| {
+        let Some(track) = metadata.tracks.get_mut(&id) else {
+            return false;
+        };
+        if let Some(index) = track.versions.iter().position(|version| version.media_source_id == next) {
+            let next = track.versions.remove(index);
+            track.media_source_id = Some(next.media_source_id);
+            track.bitrate = next.bitrate;
+            track.size = next.size;
+            track.server_status = None;
+        }
+        true
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+
+    // Removed meanwhile, its files were deleted before the version moved
+    if !promoted {
+        let _ = tokio::fs::remove_file(&blob_path).await;
+    }
+
+    Ok(())
+}
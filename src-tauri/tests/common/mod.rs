@@ -0,0 +1,253 @@
+//! Fixture libraries for the storage tests and benchmarks. Everything is generated from a fixed seed so
+//! runs are comparable.
+
+#![allow(dead_code)]
+
+use jelly_video_app_lib::storage::playback::OfflineUserData;
+use jelly_video_app_lib::storage::{StorageMetadata, StorageTrack};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const ADJECTIVES: [&str; 16] = [
+    "Silent", "Golden", "Broken", "Hidden", "Crimson", "Distant", "Frozen", "Burning", "Lonely", "Savage", "Electric",
+    "Ancient", "Midnight", "Wandering", "Hollow", "Velvet",
+];
+const NOUNS: [&str; 16] = [
+    "River", "Empire", "Garden", "Harbor", "Signal", "Kingdom", "Mirror", "Frontier", "Orchard", "Station", "Lantern",
+    "Voyage", "Citadel", "Meadow", "Engine", "Tide",
+];
+const GENRES: [&str; 8] = ["Action", "Comedy", "Drama", "Horror", "Science Fiction", "Thriller", "Animation", "Documentary"];
+const RATINGS: [&str; 5] = ["G", "PG", "PG-13", "R", "TV-MA"];
+const PEOPLE: [&str; 8] = [
+    "Ada Lindqvist", "Bruno Okafor", "Chloé Martín", "Dmitri Sokolov", "Elif Yıldız", "Farah Haddad", "Goran Petrović",
+    "Hana Sato",
+];
+const RESOLUTIONS: [(i64, i64); 3] = [(720, 480), (1920, 1080), (3840, 2160)];
+
+/// Shape of a generated library
+#[derive(Debug, Clone, Copy)]
+pub struct LibrarySpec {
+    pub series: usize,
+    pub seasons_per_series: usize,
+    pub episodes_per_season: usize,
+    pub movies: usize,
+}
+
+impl LibrarySpec {
+    /// About 20k items: 16000 episodes in 200 series of 4 seasons, 4000 movies and the 1000 containers
+    pub fn large() -> Self {
+        Self {
+            series: 200,
+            seasons_per_series: 4,
+            episodes_per_season: 20,
+            movies: 4000,
+        }
+    }
+
+    pub fn small() -> Self {
+        Self {
+            series: 5,
+            seasons_per_series: 2,
+            episodes_per_season: 5,
+            movies: 20,
+        }
+    }
+
+    pub fn item_count(&self) -> usize {
+        let seasons = self.series * self.seasons_per_series;
+        self.series + seasons + seasons * self.episodes_per_season + self.movies
+    }
+}
+
+/// A catalog shaped like `spec`, with series, seasons and episodes nested through `containerId`
+pub fn library(spec: LibrarySpec) -> StorageMetadata {
+    let mut rng = StdRng::seed_from_u64(7);
+    let mut metadata = StorageMetadata::default();
+    let mut timestamp = 1_600_000_000_000;
+
+    for series in 0..spec.series {
+        let series_id = format!("series{:04}", series);
+        let series_name = title(&mut rng, series);
+        timestamp += 1000;
+        metadata.tracks.insert(
+            series_id.clone(),
+            container(timestamp, None, json!({ "Id": series_id, "Type": "Series", "Name": series_name })),
+        );
+
+        for season in 0..spec.seasons_per_series {
+            let season_id = format!("{}s{:02}", series_id, season + 1);
+            timestamp += 1000;
+            metadata.tracks.insert(
+                season_id.clone(),
+                container(
+                    timestamp,
+                    Some(&series_id),
+                    json!({
+                        "Id": season_id,
+                        "Type": "Season",
+                        "Name": format!("Season {}", season + 1),
+                        "IndexNumber": season + 1,
+                        "SeriesId": series_id,
+                        "SeriesName": series_name,
+                    }),
+                ),
+            );
+
+            for episode in 0..spec.episodes_per_season {
+                let id = format!("{}e{:03}", season_id, episode + 1);
+                timestamp += 1000;
+                let name = format!("{} Part {}", title(&mut rng, episode), episode + 1);
+                let mut item = media_item(&mut rng, &id, "Episode", name);
+                item["SeriesId"] = json!(series_id);
+                item["SeriesName"] = json!(series_name);
+                item["SeasonId"] = json!(season_id);
+                item["IndexNumber"] = json!(episode + 1);
+                item["ParentIndexNumber"] = json!(season + 1);
+                metadata.tracks.insert(id.clone(), video(&mut rng, &id, timestamp, Some(&season_id), item));
+            }
+        }
+    }
+
+    for movie in 0..spec.movies {
+        let id = format!("movie{:05}", movie);
+        timestamp += 1000;
+        let name = format!("{} {}", title(&mut rng, movie), movie);
+        let item = media_item(&mut rng, &id, "Movie", name);
+        metadata.tracks.insert(id.clone(), video(&mut rng, &id, timestamp, None, item));
+    }
+
+    metadata
+}
+
+/// Offline user data for roughly `played_ratio` of the videos
+pub fn user_data(metadata: &StorageMetadata, played_ratio: f64) -> HashMap<String, OfflineUserData> {
+    let mut rng = StdRng::seed_from_u64(11);
+    // Sorted first, map order differs between runs
+    let mut videos: Vec<(&String, &StorageTrack)> =
+        metadata.tracks.iter().filter(|(_, track)| track.track_type == "video").collect();
+    videos.sort_by_key(|(id, _)| *id);
+
+    videos
+        .into_iter()
+        .filter(|_| rng.gen_bool(played_ratio))
+        .map(|(id, track)| {
+            let user_data = OfflineUserData {
+                playback_position_ticks: 0,
+                played: true,
+                play_count: 1,
+                last_played_date: Some(track.timestamp + 1),
+            };
+            (id.clone(), user_data)
+        })
+        .collect()
+}
+
+/// Writes a small blob for every video and a thumbnail for every item, the way a real library lays them out
+pub fn write_files(storage_dir: &Path, metadata: &StorageMetadata) {
+    fs::create_dir_all(storage_dir).unwrap();
+    for (id, track) in &metadata.tracks {
+        if track.track_type == "video" {
+            fs::write(storage_dir.join(format!("{}.blob", id)), [0u8; 64]).unwrap();
+        }
+        fs::write(storage_dir.join(format!("{}.thumb", id)), [0u8; 16]).unwrap();
+    }
+}
+
+/// Empty directory under the system temp dir, unique per process and `name`
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("jelly-storage-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn title(rng: &mut StdRng, n: usize) -> String {
+    format!("{} {}", ADJECTIVES[n % ADJECTIVES.len()], NOUNS.choose(rng).unwrap())
+}
+
+fn media_item(rng: &mut StdRng, id: &str, item_type: &str, name: String) -> serde_json::Value {
+    let genre_count = rng.gen_range(1..=3);
+    let genres: Vec<&str> = GENRES.choose_multiple(rng, genre_count).copied().collect();
+    let people: Vec<serde_json::Value> = PEOPLE
+        .choose_multiple(rng, 3)
+        .map(|person| json!({ "Name": person, "Type": "Actor" }))
+        .collect();
+
+    json!({
+        "Id": id,
+        "Type": item_type,
+        "Name": name,
+        "ProductionYear": rng.gen_range(1950..=2025),
+        "OfficialRating": RATINGS.choose(rng).unwrap(),
+        "CommunityRating": rng.gen_range(10..=100) as f64 / 10.0,
+        "RunTimeTicks": rng.gen_range(20..=180) * 600_000_000i64,
+        "Genres": genres,
+        "People": people,
+        "Overview": format!(
+            "A {} story about the {} {}.",
+            ADJECTIVES.choose(rng).unwrap().to_lowercase(),
+            ADJECTIVES.choose(rng).unwrap().to_lowercase(),
+            NOUNS.choose(rng).unwrap().to_lowercase()
+        ),
+        "UserData": { "Played": false, "IsFavorite": rng.gen_bool(0.1) },
+    })
+}
+
+fn container(timestamp: i64, container_id: Option<&str>, media_item: serde_json::Value) -> StorageTrack {
+    StorageTrack {
+        track_type: "container".to_string(),
+        timestamp,
+        media_item,
+        bitrate: 0,
+        container_id: container_id.map(str::to_string),
+        media_sources: None,
+        media_source_id: None,
+        size: None,
+        server_status: None,
+        references: Vec::new(),
+        direct: false,
+        versions: Vec::new(),
+        has_thumbnail: Some(true),
+    }
+}
+
+fn video(
+    rng: &mut StdRng,
+    id: &str,
+    timestamp: i64,
+    container_id: Option<&str>,
+    media_item: serde_json::Value,
+) -> StorageTrack {
+    let (width, height) = *RESOLUTIONS.choose(rng).unwrap();
+    let size = rng.gen_range(100_000_000..4_000_000_000u64);
+    let bitrate = rng.gen_range(1_000_000..40_000_000);
+
+    StorageTrack {
+        track_type: "video".to_string(),
+        timestamp,
+        media_item,
+        bitrate,
+        container_id: container_id.map(str::to_string),
+        media_sources: Some(json!([{
+            "Id": id,
+            "Size": size,
+            "Bitrate": bitrate,
+            "MediaStreams": [
+                { "Type": "Video", "Width": width, "Height": height },
+                { "Type": "Subtitle", "Language": "eng" },
+            ],
+        }])),
+        media_source_id: None,
+        size: Some(size),
+        server_status: None,
+        references: Vec::new(),
+        direct: false,
+        versions: Vec::new(),
+        has_thumbnail: Some(true),
+    }
+}
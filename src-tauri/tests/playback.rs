@@ -0,0 +1,76 @@
+//! Offline playback state and how it's merged into item snapshots
+
+use jelly_video_app_lib::storage::playback::{
+    apply_user_data, is_played, record_event, OfflineUserData, PlaybackEvent, PlaybackEventKind, PlaybackStore,
+};
+use serde_json::json;
+
+fn event(kind: PlaybackEventKind, position_ticks: i64, played: bool, timestamp: i64) -> PlaybackEvent {
+    PlaybackEvent {
+        kind,
+        position_ticks,
+        is_paused: false,
+        played,
+        media_source_id: None,
+        timestamp,
+    }
+}
+
+#[test]
+fn progress_reports_collapse() {
+    let mut store = PlaybackStore::default();
+    record_event(&mut store, "a", event(PlaybackEventKind::Start, 0, false, 1));
+    record_event(&mut store, "a", event(PlaybackEventKind::Progress, 10, false, 2));
+    record_event(&mut store, "a", event(PlaybackEventKind::Progress, 20, false, 3));
+
+    let kinds: Vec<_> = store.pending["a"].iter().map(|e| (e.kind, e.position_ticks)).collect();
+    assert_eq!(kinds, vec![(PlaybackEventKind::Start, 0), (PlaybackEventKind::Progress, 20)]);
+    assert_eq!(store.user_data["a"].playback_position_ticks, 20);
+    assert_eq!(store.user_data["a"].last_played_date, Some(3));
+}
+
+#[test]
+fn an_unplayed_stop_clears_played() {
+    let mut store = PlaybackStore::default();
+    record_event(&mut store, "a", event(PlaybackEventKind::Progress, 50, false, 1));
+    record_event(&mut store, "a", event(PlaybackEventKind::Stop, 100, true, 2));
+
+    let user_data = &store.user_data["a"];
+    assert!(user_data.played);
+    assert_eq!((user_data.play_count, user_data.playback_position_ticks), (1, 0));
+
+    // Rewatching and stopping part way through
+    record_event(&mut store, "a", event(PlaybackEventKind::Stop, 30, false, 3));
+    let user_data = &store.user_data["a"];
+    assert!(!user_data.played);
+    assert_eq!((user_data.play_count, user_data.playback_position_ticks), (1, 30));
+    assert_eq!(store.pending["a"].len(), 3);
+}
+
+#[test]
+fn newer_snapshots_win() {
+    let user_data = OfflineUserData {
+        playback_position_ticks: 25,
+        played: false,
+        play_count: 2,
+        last_played_date: Some(1_700_000_000_000),
+    };
+
+    let newer = json!({"RunTimeTicks": 100, "UserData": {"Played": true, "LastPlayedDate": "2030-01-01T00:00:00.000Z"}});
+    let mut item = newer.clone();
+    apply_user_data(&mut item, &user_data);
+    assert_eq!(item, newer);
+    assert!(is_played(&item, Some(&user_data)));
+
+    let mut item = json!({"RunTimeTicks": 100, "UserData": {"Played": true, "LastPlayedDate": "2020-01-01T00:00:00.000Z"}});
+    apply_user_data(&mut item, &user_data);
+    assert_eq!(item["UserData"]["Played"], false);
+    assert_eq!(item["UserData"]["PlayCount"], 2);
+    assert_eq!(item["UserData"]["PlayedPercentage"], 25.0);
+    assert!(!is_played(&item, Some(&user_data)));
+
+    // Nothing played locally leaves the snapshot alone
+    let mut item = newer.clone();
+    apply_user_data(&mut item, &OfflineUserData::default());
+    assert_eq!(item, newer);
+}
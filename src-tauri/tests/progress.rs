@@ -0,0 +1,63 @@
+//! Download progress reports and the queue summary built from them
+
+use jelly_video_app_lib::storage::progress::{DownloadQueue, DownloadState, ProgressMeter};
+use std::time::{Duration, Instant};
+
+#[test]
+fn meter_reports_fractions_and_throttles() {
+    let start = Instant::now();
+    let mut meter = ProgressMeter::new("movie", 1, start);
+
+    // The first bytes are reported right away
+    let first = meter.sample(1_000, 3_000, start + Duration::from_millis(100)).unwrap();
+    assert_eq!(first.state, DownloadState::Downloading);
+    assert_eq!(first.speed, 10_000.0);
+    assert!(meter.sample(1_500, 3_000, start + Duration::from_millis(300)).is_none());
+
+    let second = meter.sample(2_000, 3_000, start + Duration::from_millis(600)).unwrap();
+    assert_eq!(second.progress, Some(2_000.0 / 3_000.0 * 100.0));
+    assert_eq!(second.speed, 2_000.0);
+    assert_eq!(second.time_remaining, Some(0.5));
+
+    // Without a `Content-Length` neither the percentage nor the time left are known
+    let mut meter = ProgressMeter::new("movie", 1, start);
+    let unknown = meter.sample(1_000, 0, start + Duration::from_secs(1)).unwrap();
+    assert_eq!(unknown.total, None);
+    assert_eq!(unknown.progress, None);
+    assert_eq!(unknown.time_remaining, None);
+
+    meter.retry(start + Duration::from_secs(2));
+    let retried = meter.report(DownloadState::Connecting, None);
+    assert_eq!((retried.attempt, retried.downloaded), (2, 0));
+}
+
+#[test]
+fn queue_summary_counts_the_batch() {
+    let start = Instant::now();
+    let mut queue = DownloadQueue::default();
+
+    queue.start("first", 1);
+    let mut meter = ProgressMeter::new("first", 1, start);
+    queue.update(&meter.sample(500, 1_000, start + Duration::from_secs(1)).unwrap());
+    assert_eq!(queue.summary().state, DownloadState::Downloading);
+    assert_eq!(queue.summary().progress, Some(50.0));
+    queue.finish(Some(DownloadState::Done), 1_000);
+    assert_eq!(queue.summary().state, DownloadState::Queued);
+
+    // Cancelled downloads aren't counted
+    queue.start("cancelled", 1);
+    queue.finish(None, 0);
+
+    queue.start("second", 0);
+    queue.update(&ProgressMeter::new("second", 1, start).sample(200, 0, start + Duration::from_secs(1)).unwrap());
+    assert_eq!(queue.summary().downloaded, 1_200);
+    queue.finish(Some(DownloadState::Failed), 0);
+
+    let summary = queue.summary();
+    assert_eq!((summary.state, summary.done, summary.failed, summary.downloaded), (DownloadState::Done, 1, 1, 1_000));
+    assert_eq!(summary.current_id, None);
+
+    // The next download starts a new batch
+    queue.start("third", 0);
+    assert_eq!((queue.summary().done, queue.summary().failed, queue.summary().downloaded), (0, 0, 0));
+}
@@ -0,0 +1,352 @@
+//! Stress tests for the storage catalog against generated libraries of about 20k items
+
+mod common;
+
+use common::LibrarySpec;
+use jelly_video_app_lib::storage::catalog::{self, SavePlan};
+use jelly_video_app_lib::storage::events::{self, StorageTotals};
+use jelly_video_app_lib::storage::crypto;
+use jelly_video_app_lib::storage::playback::OfflineUserData;
+use jelly_video_app_lib::storage::query::{ItemFilter, Resolution, Sort, SortBy, SortOrder};
+use jelly_video_app_lib::storage::reconcile::{blob_size, reconcile_track};
+use jelly_video_app_lib::storage::search::SearchIndex;
+use jelly_video_app_lib::storage::versions;
+use jelly_video_app_lib::storage::{ServerStatus, StorageTrack};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+fn sort(sort_by: SortBy, sort_order: Option<SortOrder>) -> Sort {
+    Sort { sort_by, sort_order }
+}
+
+#[test]
+fn large_library_has_expected_shape() {
+    let spec = LibrarySpec::large();
+    let metadata = common::library(spec);
+
+    assert_eq!(metadata.tracks.len(), spec.item_count());
+    assert!(metadata.tracks.len() >= 20_000);
+}
+
+#[test]
+fn paging_visits_every_item_once() {
+    let spec = LibrarySpec::large();
+    let metadata = common::library(spec);
+    let user_data = common::user_data(&metadata, 0.3);
+    let episodes = spec.series * spec.seasons_per_series * spec.episodes_per_season;
+
+    for sort in [
+        sort(SortBy::DateCreated, None),
+        sort(SortBy::SortName, None),
+        sort(SortBy::CommunityRating, Some(SortOrder::Ascending)),
+        sort(SortBy::IndexNumber, None),
+    ] {
+        let mut seen = HashSet::new();
+        let mut cursor = None;
+
+        loop {
+            let page = catalog::page(
+                &metadata,
+                &user_data,
+                "Episode",
+                2000,
+                cursor.as_deref(),
+                sort,
+                &ItemFilter::default(),
+            )
+            .unwrap();
+            assert_eq!(page.total_count, episodes);
+
+            for item in &page.items {
+                let id = item["Id"].as_str().unwrap().to_string();
+                assert!(seen.insert(id), "{:?} returned an item twice", sort.sort_by);
+            }
+
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+
+        assert_eq!(seen.len(), episodes, "{:?} skipped items", sort.sort_by);
+    }
+}
+
+#[test]
+fn pages_follow_the_sort() {
+    let metadata = common::library(LibrarySpec::large());
+    let user_data = common::user_data(&metadata, 0.3);
+
+    let page = catalog::page(
+        &metadata,
+        &user_data,
+        "Movie",
+        1000,
+        None,
+        sort(SortBy::SortName, None),
+        &ItemFilter::default(),
+    )
+    .unwrap();
+    let names: Vec<String> = page
+        .items
+        .iter()
+        .map(|item| item["Name"].as_str().unwrap().to_lowercase())
+        .collect();
+    assert!(names.windows(2).all(|pair| pair[0] <= pair[1]));
+
+    let page = catalog::page(
+        &metadata,
+        &user_data,
+        "Movie",
+        1000,
+        None,
+        sort(SortBy::DateCreated, None),
+        &ItemFilter::default(),
+    )
+    .unwrap();
+    let ids: Vec<&str> = page.items.iter().map(|item| item["Id"].as_str().unwrap()).collect();
+    let timestamps: Vec<i64> = ids.iter().map(|id| metadata.tracks[*id].timestamp).collect();
+    assert!(timestamps.windows(2).all(|pair| pair[0] >= pair[1]));
+}
+
+#[test]
+fn filtered_counts_match_the_catalog() {
+    let metadata = common::library(LibrarySpec::large());
+    let user_data = common::user_data(&metadata, 0.3);
+
+    let filter = ItemFilter {
+        genres: vec!["Drama".to_string()],
+        min_year: Some(1990),
+        is_played: Some(false),
+        resolutions: vec![Resolution::Uhd],
+        ..Default::default()
+    };
+    let page = catalog::page(&metadata, &user_data, "Movie", 50, None, Sort::default(), &filter).unwrap();
+
+    let expected = metadata
+        .tracks
+        .iter()
+        .filter(|(_, track)| track.media_item["Type"] == "Movie")
+        .filter(|(_, track)| track.media_item["Genres"].as_array().unwrap().iter().any(|g| *g == "Drama"))
+        .filter(|(_, track)| track.media_item["ProductionYear"].as_i64().unwrap() >= 1990)
+        .filter(|(id, _)| !user_data.contains_key(*id))
+        .filter(|(_, track)| track.media_sources.as_ref().unwrap()[0]["MediaStreams"][0]["Width"] == 3840)
+        .count();
+
+    assert!(expected > 0);
+    assert_eq!(page.total_count, expected);
+    assert_eq!(page.items.len(), expected.min(50));
+}
+
+#[test]
+fn search_finds_items_in_a_large_library() {
+    let metadata = common::library(LibrarySpec::large());
+    let user_data = common::user_data(&metadata, 0.3);
+    let index = SearchIndex::build(&metadata);
+
+    let name = metadata.tracks["movie01234"].media_item["Name"].as_str().unwrap();
+    let matches = index.search(name, 10);
+    assert_eq!(matches[0].0, "movie01234");
+
+    // Two swapped letters in the longest word still find it
+    let mut words: Vec<String> = name.split(' ').map(str::to_string).collect();
+    let longest = words.iter().enumerate().max_by_key(|(_, word)| word.len()).unwrap().0;
+    let mut letters: Vec<char> = words[longest].chars().collect();
+    letters.swap(1, 2);
+    words[longest] = letters.into_iter().collect();
+    let matches = index.search(&words.join(" "), 10);
+    assert!(matches.iter().any(|(id, _)| id == "movie01234"));
+
+    let items = catalog::media_items(&metadata, &user_data, matches.iter().map(|(id, _)| id.as_str()));
+    assert_eq!(items.len(), matches.len());
+    assert_eq!(items[0]["hasThumbnail"], true);
+}
+
+#[test]
+fn saving_shares_stored_items() {
+    let mut metadata = common::library(LibrarySpec::small());
+    let id = "series0000s01e001";
+    let mut data = metadata.tracks[id].clone();
+
+    // Already stored through its season, a playlist only adds a reference
+    data.container_id = Some("playlist".to_string());
+    assert_eq!(catalog::plan_save(&mut metadata, id, &data, true).unwrap(), SavePlan::Referenced);
+    assert!(metadata.tracks[id].container_ids().any(|holder| holder == "playlist"));
+
+    // Another media source becomes a version
+    data.media_source_id = Some("other-source".to_string());
+    let plan = catalog::plan_save(&mut metadata, id, &data, true).unwrap();
+    assert_eq!(plan, SavePlan::NewVersion("other-source".to_string()));
+    assert!(!catalog::finish_save(&mut metadata, id, data.clone(), Some("other-source".to_string())));
+    assert_eq!(metadata.tracks[id].versions.len(), 1);
+
+    // Removed while the version was downloading, the version has to take the blob's place
+    metadata.tracks.remove(id);
+    assert!(catalog::finish_save(&mut metadata, id, data, Some("other-source".to_string())));
+
+    // A series can't be saved into one of its own seasons
+    let mut series = metadata.tracks["series0001"].clone();
+    series.container_id = Some("series0001s01".to_string());
+    assert!(catalog::plan_save(&mut metadata, "series0001", &series, false).is_err());
+
+    let fresh = metadata.tracks["movie00000"].clone();
+    assert_eq!(catalog::plan_save(&mut metadata, "movie99999", &fresh, true).unwrap(), SavePlan::Download);
+    // A playlist stored it while it was downloading, the finished download keeps that reference
+    let mut referenced = fresh.clone();
+    referenced.container_id = Some("playlist".to_string());
+    metadata.tracks.insert("movie99999".to_string(), referenced);
+    assert!(!catalog::finish_save(&mut metadata, "movie99999", fresh, None));
+    assert!(metadata.tracks["movie99999"].container_ids().any(|holder| holder == "playlist"));
+    assert!(metadata.tracks["movie99999"].is_direct());
+
+    // Ids end up in file names, anything that could leave the storage directory is refused
+    let mut escaping = metadata.tracks["movie00001"].clone();
+    assert!(catalog::plan_save(&mut metadata, "../movie00001", &escaping, true).is_err());
+    escaping.media_source_id = Some("../../escape".to_string());
+    assert!(catalog::plan_save(&mut metadata, "movie00001", &escaping, true).is_err());
+    assert!(versions::version_path(Path::new("storage"), "movie00001", "a/b").is_err());
+    assert!(versions::version_path(Path::new("storage"), "movie00001", "other-source").is_ok());
+}
+
+#[test]
+fn removing_a_series_keeps_shared_episodes() {
+    let spec = LibrarySpec::small();
+    let mut metadata = common::library(spec);
+    let storage_dir = common::temp_dir("remove");
+    common::write_files(&storage_dir, &metadata);
+
+    let shared = "series0000s02e003";
+    metadata.tracks.get_mut(shared).unwrap().add_reference(Some("movie00000"));
+
+    let removed = catalog::remove(&mut metadata, "series0000", None);
+    let below = 1 + spec.seasons_per_series * (1 + spec.episodes_per_season);
+    assert_eq!(removed.len(), below - 1);
+    assert!(!removed.iter().any(|id| id == shared));
+
+    catalog::remove_track_files(&storage_dir, &removed).unwrap();
+    for id in &removed {
+        assert!(!metadata.tracks.contains_key(id));
+        assert!(!storage_dir.join(format!("{}.blob", id)).exists());
+        assert!(!storage_dir.join(format!("{}.thumb", id)).exists());
+    }
+    assert!(storage_dir.join(format!("{}.blob", shared)).exists());
+    assert_eq!(metadata.tracks.len(), spec.item_count() - removed.len());
+
+    // Removing the episode on its own also drops it from the container still holding it
+    let removed = catalog::remove(&mut metadata, shared, None);
+    assert_eq!(removed, vec![shared.to_string()]);
+
+    let _ = std::fs::remove_dir_all(storage_dir);
+}
+
+#[test]
+fn catalog_survives_a_round_trip() {
+    let metadata = common::library(LibrarySpec::large());
+    let storage_dir = common::temp_dir("roundtrip");
+    let path = storage_dir.join("metadata.json");
+
+    assert!(catalog::load(&path, None).unwrap().tracks.is_empty());
+
+    catalog::save(&path, &metadata, None).unwrap();
+    let loaded = catalog::load(&path, None).unwrap();
+    assert_eq!(loaded.tracks.len(), metadata.tracks.len());
+    assert_eq!(loaded.tracks["movie00042"].media_item, metadata.tracks["movie00042"].media_item);
+
+    // Sealing is slow in debug builds, a small library covers it
+    let metadata = common::library(LibrarySpec::small());
+    let key = [7u8; 32];
+    catalog::save(&path, &metadata, Some(&key)).unwrap();
+    let sealed = std::fs::read(&path).unwrap();
+    assert!(!sealed.windows(10).any(|window| window == b"movie00012"));
+    assert!(catalog::load(&path, None).is_err());
+    assert_eq!(catalog::load(&path, Some(&key)).unwrap().tracks.len(), metadata.tracks.len());
+
+    let _ = std::fs::remove_dir_all(storage_dir);
+}
+
+#[test]
+fn blob_size_leaves_out_the_encryption_header() {
+    let storage_dir = common::temp_dir("blobsize");
+    let (plain, sealed) = (storage_dir.join("plain.blob"), storage_dir.join("sealed.blob"));
+    let data = vec![1u8; 1_000];
+
+    crypto::copy_blob(data.as_slice(), &plain, None).unwrap();
+    crypto::copy_blob(data.as_slice(), &sealed, Some(&[7u8; 32])).unwrap();
+
+    assert_eq!(blob_size(&plain), Some(1_000));
+    assert_eq!(blob_size(&sealed), Some(1_000));
+    assert_eq!(blob_size(&storage_dir.join("missing.blob")), None);
+
+    let _ = std::fs::remove_dir_all(storage_dir);
+}
+
+#[test]
+fn reconcile_compares_every_version() {
+    let mut track: StorageTrack = serde_json::from_value(json!({
+        "type": "video",
+        "timestamp": 0,
+        "mediaItem": {},
+        "bitrate": 0,
+        "mediaSourceId": "main",
+        "size": 100,
+        "versions": [{ "mediaSourceId": "extra", "timestamp": 0, "bitrate": 0, "size": 50 }],
+    }))
+    .unwrap();
+    let fresh = |extra: serde_json::Value| json!({ "MediaSources": [{ "Id": "main", "Size": 100 }, extra] });
+    let newer = Some(ServerStatus::NewerVersionAvailable);
+
+    let unchanged = fresh(json!({ "Id": "extra", "Size": 50 }));
+    assert_eq!(reconcile_track("item", &track, Some(&unchanged), track.size), None);
+    // Only the other version changed or went away
+    let replaced = fresh(json!({ "Id": "extra", "Size": 60 }));
+    assert_eq!(reconcile_track("item", &track, Some(&replaced), track.size), newer);
+    let removed = fresh(json!({ "Id": "other", "Size": 50 }));
+    assert_eq!(reconcile_track("item", &track, Some(&removed), track.size), newer);
+
+    track.versions.clear();
+    assert_eq!(reconcile_track("item", &track, Some(&removed), track.size), None);
+    assert_eq!(reconcile_track("item", &track, Some(&removed), Some(90)), newer);
+    assert_eq!(reconcile_track("item", &track, None, track.size), Some(ServerStatus::OrphanedOnServer));
+}
+
+#[test]
+fn catalog_changes_list_added_and_removed_items() {
+    let previous = common::library(LibrarySpec::large());
+    let mut current = previous.clone();
+
+    let removed = catalog::remove(&mut current, "series0007", None);
+    let mut added = current.tracks["movie00000"].clone();
+    added.size = Some(1000);
+    current.tracks.insert("movie99999".to_string(), added);
+
+    // Watched offline before the catalog event went out
+    let user_data = HashMap::from([(
+        "movie99999".to_string(),
+        OfflineUserData {
+            playback_position_ticks: 0,
+            played: true,
+            play_count: 1,
+            last_played_date: Some(4_102_444_800_000),
+        },
+    )]);
+
+    let changes = events::diff(&previous, &current, &user_data);
+    let mut expected = removed.clone();
+    expected.sort();
+    assert_eq!(changes.removed.ids, expected);
+    assert_eq!(changes.removed.items.len(), removed.len());
+    assert_eq!(changes.added.ids, vec!["movie99999".to_string()]);
+    assert_eq!(changes.added.items[0]["Id"], "movie00000");
+    assert_eq!(changes.added.items[0]["UserData"]["Played"], true);
+    assert!(changes.totals_changed);
+    assert_eq!(changes.added.totals, StorageTotals::of(&current));
+    assert_eq!(changes.added.totals.track_count, previous.tracks.len() - removed.len() + 1);
+
+    // Only a size change, e.g. after an upgrade
+    let mut upgraded = previous.clone();
+    upgraded.tracks.get_mut("movie00001").unwrap().size = Some(1);
+    let changes = events::diff(&previous, &upgraded, &HashMap::new());
+    assert!(changes.added.ids.is_empty() && changes.removed.ids.is_empty());
+    assert!(changes.totals_changed);
+}
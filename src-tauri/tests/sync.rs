@@ -0,0 +1,127 @@
+//! Which episodes a series sync wants and which items it may release again
+
+use jelly_video_app_lib::storage::playback::OfflineUserData;
+use jelly_video_app_lib::storage::sync::{
+    references_outside, release_references, sync_window, SyncReference, SyncSubscription,
+};
+use jelly_video_app_lib::storage::{StorageMetadata, StorageTrack};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+
+fn track(track_type: &str, container_id: Option<&str>) -> StorageTrack {
+    serde_json::from_value(json!({
+        "type": track_type,
+        "timestamp": 0,
+        "mediaItem": {},
+        "bitrate": 0,
+        "containerId": container_id,
+        "size": 100,
+    }))
+    .unwrap()
+}
+
+fn reference(container_id: &str, item_id: &str) -> SyncReference {
+    SyncReference {
+        container_id: container_id.to_string(),
+        item_id: item_id.to_string(),
+    }
+}
+
+/// A synced series with one season the sync downloaded and one the user downloaded by hand before syncing
+fn library() -> StorageMetadata {
+    let mut metadata = StorageMetadata::default();
+    metadata.tracks.insert("series".to_string(), track("container", None));
+    metadata.tracks.insert("synced-season".to_string(), track("container", Some("series")));
+    metadata.tracks.insert("synced-episode".to_string(), track("video", Some("synced-season")));
+
+    let mut manual_season = track("container", None);
+    manual_season.add_reference(Some("series"));
+    metadata.tracks.insert("manual-season".to_string(), manual_season);
+    metadata.tracks.insert("manual-episode".to_string(), track("video", Some("manual-season")));
+
+    metadata.subscriptions.insert(
+        "series".to_string(),
+        SyncSubscription {
+            unwatched_count: 1,
+            last_synced: None,
+            added: vec![reference("synced-season", "synced-episode")],
+        },
+    );
+    metadata
+}
+
+#[test]
+fn manual_downloads_survive_a_sync() {
+    let mut metadata = library();
+
+    // Everything was watched, nothing is wanted anymore
+    let outside = references_outside(&metadata, "series", &HashSet::new());
+    assert_eq!(outside, vec![reference("synced-season", "synced-episode")]);
+
+    let removed = release_references(&mut metadata, "series", &outside);
+    assert_eq!(removed, vec!["synced-episode".to_string()]);
+    assert!(metadata.tracks.contains_key("manual-season"));
+    assert!(metadata.tracks.contains_key("manual-episode"));
+    assert!(metadata.subscriptions["series"].added.is_empty());
+}
+
+#[test]
+fn wanted_items_and_released_references_stay() {
+    let mut metadata = library();
+
+    let wanted = HashSet::from(["synced-episode"]);
+    assert!(references_outside(&metadata, "series", &wanted).is_empty());
+
+    // Released twice, e.g. by two overlapping syncs, the second one is a no-op
+    let outside = references_outside(&metadata, "series", &HashSet::new());
+    release_references(&mut metadata, "series", &outside);
+    metadata.tracks.insert("synced-episode".to_string(), track("video", Some("other")));
+    assert!(release_references(&mut metadata, "series", &outside).is_empty());
+    assert!(metadata.tracks.contains_key("synced-episode"));
+}
+
+fn episode(id: &str, played: bool, has_sources: bool) -> serde_json::Value {
+    let sources = if has_sources { json!([{"Id": id}]) } else { json!([]) };
+    json!({"Id": id, "UserData": {"Played": played}, "MediaSources": sources})
+}
+
+fn ids(window: Vec<&serde_json::Value>) -> Vec<&str> {
+    window.iter().map(|e| e["Id"].as_str().unwrap()).collect()
+}
+
+#[test]
+fn window_starts_after_the_last_watched_episode() {
+    let episodes = vec![
+        episode("1", false, true),
+        episode("2", true, true),
+        episode("3", false, false),
+        episode("4", true, true),
+        episode("5", false, true),
+        episode("6", false, false),
+        episode("7", false, true),
+        episode("8", false, true),
+    ];
+    let no_user_data = HashMap::new();
+
+    // Skipped episode 1 and the watched ones don't count, 6 has nothing to download
+    assert_eq!(ids(sync_window(&episodes, &no_user_data, 2)), vec!["5", "7"]);
+    assert_eq!(ids(sync_window(&episodes, &no_user_data, 10)), vec!["5", "7", "8"]);
+    assert!(sync_window(&episodes, &no_user_data, 0).is_empty());
+
+    // Watched offline since the snapshot was taken
+    let user_data = HashMap::from([(
+        "7".to_string(),
+        OfflineUserData {
+            played: true,
+            last_played_date: Some(0),
+            ..Default::default()
+        },
+    )]);
+    assert_eq!(ids(sync_window(&episodes, &user_data, 2)), vec!["8"]);
+}
+
+#[test]
+fn window_starts_at_the_beginning_when_nothing_was_watched() {
+    let episodes = vec![episode("1", false, true), episode("2", false, true)];
+    assert_eq!(ids(sync_window(&episodes, &HashMap::new(), 5)), vec!["1", "2"]);
+}
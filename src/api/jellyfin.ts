@@ -58,6 +58,7 @@ export type MediaItem = BaseItemDto & {
     Id: string
     Name: string
     offlineState?: 'downloading' | 'downloaded' | 'deleting'
+    offlineServerStatus?: 'orphanedOnServer' | 'newerVersionAvailable'
     downloadedImageUrl?: string
 }
 
@@ -1,5 +1,6 @@
 import { XCircleIcon } from '@primer/octicons-react'
 import { MediaList } from '../components/MediaList'
+import { DownloadProgress } from '../context/AudioStorageContext/AudioStorageContextProvider'
 import { useDownloadContext } from '../context/DownloadContext/DownloadContext'
 import { useFilterContext } from '../context/FilterContext/FilterContext'
 import { useIndexedDbDownloadsData } from '../hooks/useIndexedDbDownloadsData'
@@ -7,6 +8,29 @@ import { formatFileSize } from '../utils/formatFileSize'
 import { formatTimeRemaining } from '../utils/formatTimeRemaining'
 import './Downloads.css'
 
+const formatDownloadState = ({ state, total, timeRemaining, attempt, error }: DownloadProgress) => {
+    switch (state) {
+        case 'queued':
+            return 'Queued'
+        case 'connecting':
+            return attempt > 1 ? `Connecting, attempt ${attempt}...` : 'Connecting...'
+        case 'retrying':
+            return 'Connection lost, retrying...'
+        case 'verifying':
+            return 'Finishing...'
+        case 'done':
+            return 'Done'
+        case 'failed':
+            return error ? `Failed: ${error}` : 'Failed'
+        case 'downloading':
+            if (total === null) {
+                return 'Size unknown'
+            }
+            // Known size but no speed measured yet
+            return timeRemaining === null ? 'Estimating...' : `${formatTimeRemaining(timeRemaining)} left`
+    }
+}
+
 export const Downloads = () => {
     const { items, isLoading, error, loadMore } = useIndexedDbDownloadsData()
     const { queue, removeFromQueue, progressBarRef, currentDownloadingId, downloadProgress } = useDownloadContext()
@@ -25,12 +49,16 @@ export const Downloads = () => {
                 <div className="queue-list">
                     {downloadProgress && currentDownloadingId && (
                         <div className="download-info">
-                            <div className="time">{formatTimeRemaining(downloadProgress.timeRemaining)} left</div>
+                            <div className="time">{formatDownloadState(downloadProgress)}</div>
                             <div className="stats">
                                 <div className="speed">{formatFileSize(downloadProgress.speed)}/s</div>-
                                 <div className="progress">{formatFileSize(downloadProgress.downloaded)}</div>
-                                of
-                                <div className="total">{formatFileSize(downloadProgress.total)}</div>
+                                {downloadProgress.total !== null && (
+                                    <>
+                                        of
+                                        <div className="total">{formatFileSize(downloadProgress.total)}</div>
+                                    </>
+                                )}
                             </div>
                         </div>
                     )}
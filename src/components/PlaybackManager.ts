@@ -220,6 +220,8 @@ export const usePlaybackManager = ({ initialVolume, clearOnLogout }: PlaybackMan
     const previousTrackRef = useRef<MediaItem | undefined>(undefined)
     const lastStoppedTrackIdRef = useRef<string | undefined>(undefined)
     const isPlayingTrackRef = useRef<string | undefined>(undefined)
+    // Id of the track playing from a download, its playback is also recorded in the offline store
+    const offlineTrackIdRef = useRef<string | undefined>(undefined)
 
     const [currentTrack, setCurrentTrack] = useState<MediaItem | undefined>(undefined)
     const [currentMediaSourceId, setCurrentMediaSourceId] = useState<string | undefined>(undefined)
@@ -233,17 +235,28 @@ export const usePlaybackManager = ({ initialVolume, clearOnLogout }: PlaybackMan
             }
 
             lastStoppedTrackIdRef.current = track.Id
+
+            const positionTicks = Math.floor(currentTime * 10000000)
+            const playedPercentage = track.RunTimeTicks ? (positionTicks / track.RunTimeTicks) * 100 : 0
+
+            // Calculate Played status based on min/max resume percentages
+            const isPlayedComplete = playedPercentage > maxResumePercentage
+            const isPlayedStart = playedPercentage < minResumePercentage
+
+            if (track.Id === offlineTrackIdRef.current) {
+                offlineTrackIdRef.current = undefined
+                audioStorage.reportPlayback(track.Id, {
+                    kind: 'stop',
+                    positionTicks,
+                    played: Boolean(duration) && isPlayedComplete,
+                    mediaSourceId,
+                })
+            }
+
             await api.reportPlaybackStopped(track.Id, currentTime, signal, mediaSourceId)
 
             if (duration) {
                 // Update cached item with new progress percentage
-                const positionTicks = currentTime * 10000000
-                const playedPercentage = track.RunTimeTicks ? (positionTicks / track.RunTimeTicks) * 100 : 0
-
-                // Calculate Played status based on min/max resume percentages
-                const isPlayedComplete = playedPercentage > maxResumePercentage
-                const isPlayedStart = playedPercentage < minResumePercentage
-
                 if (isPlayedComplete) {
                     await markAsPlayed(track, playParentId)
                 } else if (isPlayedStart) {
@@ -255,6 +268,7 @@ export const usePlaybackManager = ({ initialVolume, clearOnLogout }: PlaybackMan
         },
         [
             api,
+            audioStorage,
             duration,
             markAsPlayed,
             markAsProgress,
@@ -665,10 +679,18 @@ export const usePlaybackManager = ({ initialVolume, clearOnLogout }: PlaybackMan
 
         const interval = setInterval(() => {
             api.reportPlaybackProgress(currentTrack.Id, timePos, false, currentMediaSourceId)
+
+            if (currentTrack.Id === offlineTrackIdRef.current) {
+                audioStorage.reportPlayback(currentTrack.Id, {
+                    kind: 'progress',
+                    positionTicks: Math.floor(timePos * 10000000),
+                    mediaSourceId: currentMediaSourceId,
+                })
+            }
         }, 10000)
 
         return () => clearInterval(interval)
-    }, [api, timePos, currentTrack, isPaused, currentMediaSourceId])
+    }, [api, audioStorage, timePos, currentTrack, isPaused, currentMediaSourceId])
 
     // Handle login/logout and sync to localStorage
     useEffect(() => {
@@ -691,14 +713,15 @@ export const usePlaybackManager = ({ initialVolume, clearOnLogout }: PlaybackMan
             if (!isInitialized) return
 
             try {
-                const storedTrack = await audioStorage.getTrack(track.Id)
-                const storedMediaSourceId = storedTrack?.type === 'video' ? storedTrack.mediaSourceId : undefined
-                const isDownloadedVersion =
-                    storedTrack && (!storedMediaSourceId || storedMediaSourceId === mediaSourceId)
-                const offlineFilePath = isDownloadedVersion ? await audioStorage.getFilePath(track.Id) : undefined
+                // Resolves to the preferred downloaded version when no media source is requested.
+                // Encrypted downloads have no usable file path and are played through the local stream server
+                const offlineFilePath =
+                    (await audioStorage.getFilePath(track.Id, mediaSourceId)) ||
+                    (await audioStorage.getStreamUrl(track.Id, mediaSourceId))
                 const streamUrl = api.getStreamUrl(track.Id, bitrate, mediaSourceId)
 
                 const videoUrl = offlineFilePath || streamUrl
+                offlineTrackIdRef.current = offlineFilePath ? track.Id : undefined
 
                 setIsPending(true)
                 await setProperty('save-position-on-quit', false)
@@ -793,11 +816,20 @@ export const usePlaybackManager = ({ initialVolume, clearOnLogout }: PlaybackMan
 
             // Report playback start to Jellyfin
             api.reportPlaybackStart(currentTrack.Id, signal, currentMediaSourceId)
+
+            if (currentTrack.Id === offlineTrackIdRef.current) {
+                audioStorage.reportPlayback(currentTrack.Id, {
+                    kind: 'start',
+                    positionTicks: currentTrack.UserData?.PlaybackPositionTicks || 0,
+                    mediaSourceId: currentMediaSourceId,
+                })
+            }
         } catch (error) {
             console.error('Error playing track:', error)
         }
     }, [
         api,
+        audioStorage,
         currentTrack,
         currentMediaSourceId,
         isPaused,
@@ -828,11 +860,29 @@ export const usePlaybackManager = ({ initialVolume, clearOnLogout }: PlaybackMan
                     api.reportPlaybackProgress(currentTrack.Id, timePos, false, currentMediaSourceId)
                     updateMediaSessionMetadata(currentTrack)
                 }
+
+                if (currentTrack.Id === offlineTrackIdRef.current) {
+                    audioStorage.reportPlayback(currentTrack.Id, {
+                        kind: 'progress',
+                        positionTicks: Math.floor(timePos * 10000000),
+                        isPaused: !isPaused,
+                        mediaSourceId: currentMediaSourceId,
+                    })
+                }
             } catch (error) {
                 console.error('Failed to toggle play/pause:', error)
             }
         }
-    }, [api, currentTrack, isPaused, isInitialized, timePos, updateMediaSessionMetadata, currentMediaSourceId])
+    }, [
+        api,
+        audioStorage,
+        currentTrack,
+        isPaused,
+        isInitialized,
+        timePos,
+        updateMediaSessionMetadata,
+        currentMediaSourceId,
+    ])
 
     const protectedPlay = useCallback(async () => {
         const timeSinceLastPause = Date.now() - lastUserPauseRef.current
@@ -27,7 +27,7 @@ export const Sidenav = (props: { username: string }) => {
     const { disabled, setDisabled } = useScrollContext()
     const [searchQuery, setSearchQuery] = useState(new URLSearchParams(location.search).get('search') || '')
     const { searchResults, searchLoading, searchError, searchAttempted } = useJellyfinSearch(searchQuery)
-    const { storageStats, queueCount } = useDownloadContext()
+    const { storageStats, queueCount, queueProgress } = useDownloadContext()
 
     const handleSearchChange = (e: ChangeEvent<HTMLInputElement>) => {
         setSearchQuery(e.target.value)
@@ -250,6 +250,8 @@ export const Sidenav = (props: { username: string }) => {
                                     queueCount > 0
                                         ? ` (${queueCount} video${queueCount === 1 ? '' : 's'} in queue)`
                                         : ''
+                                }${queueProgress?.progress != null ? ` - ${Math.floor(queueProgress.progress)}%` : ''}${
+                                    queueProgress?.failed ? `, ${queueProgress.failed} failed` : ''
                                 }`}
                             >
                                 <DownloadingIcon width={16} height={16} />
@@ -1,5 +1,5 @@
-import { BaseItemKind, MediaSourceInfo } from '@jellyfin/sdk/lib/generated-client/models'
-import { invoke } from '@tauri-apps/api/core'
+import { BaseItemKind, ItemSortBy, MediaSourceInfo, SortOrder } from '@jellyfin/sdk/lib/generated-client/models'
+import { Channel, invoke } from '@tauri-apps/api/core'
 import { ReactNode, useCallback, useRef } from 'react'
 import { MediaItem } from '../../api/jellyfin'
 import { AudioStorageContext } from './AudioStorageContext'
@@ -19,6 +19,91 @@ export type IStorageTrack =
           thumbnail?: Blob
       }
 
+export type OfflineSort = {
+    sortBy?: ItemSortBy
+    sortOrder?: SortOrder
+}
+
+// Every field that is set has to match, lists match when any of their values does
+export type OfflineItemFilter = {
+    genres?: string[]
+    minYear?: number
+    maxYear?: number
+    officialRatings?: string[]
+    isPlayed?: boolean
+    isFavorite?: boolean
+    containerId?: string
+    subtitleLanguage?: string
+    resolutions?: ('Sd' | 'Hd' | 'Uhd')[]
+}
+
+export type OfflinePage = {
+    items: MediaItem[]
+    totalCount: number
+    // Passed back to get the page after this one, missing on the last page
+    nextCursor?: string
+}
+
+export type OfflineSearchGroup = {
+    itemType: BaseItemKind
+    // All matches of this type, not only the returned ones
+    totalCount: number
+    items: MediaItem[]
+}
+
+// Recorded while playing a downloaded item and replayed to the server once it's reachable
+export type OfflinePlaybackEvent = {
+    kind: 'start' | 'progress' | 'stop'
+    positionTicks: number
+    isPaused?: boolean
+    // Only on stop, marks the item as watched
+    played?: boolean
+    mediaSourceId?: string
+}
+
+// Behaviour of the offline storage, kept when downloads are cleared
+export type StorageSettings = {
+    // Minutes between metadata refreshes of downloaded items, disabled when unset
+    refreshIntervalMinutes?: number
+    autoUpgradeDownloads: boolean
+    encryptStorage: boolean
+    storageQuotaBytes?: number
+}
+
+export type DownloadState = 'queued' | 'connecting' | 'downloading' | 'verifying' | 'retrying' | 'done' | 'failed'
+
+// Sent through the channel of a single save, sizes and times are null while unknown
+export type DownloadProgress = {
+    id: string
+    state: DownloadState
+    downloaded: number
+    total: number | null
+    // Percentage with its fraction
+    progress: number | null
+    speed: number
+    timeRemaining: number | null
+    attempt: number
+    error: string | null
+}
+
+// Load thumbnails for items that have them
+const loadThumbnails = async (items: MediaItem[]) => {
+    for (const item of items) {
+        if ((item as any).hasThumbnail) {
+            try {
+                const thumbnailData = await invoke<number[] | null>('storage_get_thumbnail', { id: item.Id })
+                if (thumbnailData) {
+                    const blob = new Blob([new Uint8Array(thumbnailData)])
+                    item.downloadedImageUrl = URL.createObjectURL(blob)
+                }
+            } catch (error) {
+                console.warn('Failed to load thumbnail for', item.Id, error)
+            }
+            delete (item as any).hasThumbnail
+        }
+    }
+}
+
 const useInitialState = () => {
     const isInitialized = useRef(true) // Tauri is always ready
 
@@ -35,7 +120,9 @@ const useInitialState = () => {
                 mediaSourceId?: string
             },
             videoUrl?: string,
-            thumbnailUrl?: string
+            thumbnailUrl?: string,
+            // `queued` counts the downloads waiting behind this one, for the queue summary every window gets
+            options: { queued?: number; onProgress?: (progress: DownloadProgress) => void } = {}
         ) => {
             try {
                 // Prepare track data without blobs
@@ -49,11 +136,16 @@ const useInitialState = () => {
                     mediaSourceId: data.mediaSourceId,
                 }
 
+                const onProgress = new Channel<DownloadProgress>()
+                if (options.onProgress) onProgress.onmessage = options.onProgress
+
                 await invoke('storage_save_track', {
                     id,
                     data: trackData,
                     videoUrl,
                     thumbnailUrl,
+                    queued: options.queued,
+                    onProgress,
                 })
             } catch (error) {
                 console.error('Failed to download and save track:', error)
@@ -63,9 +155,9 @@ const useInitialState = () => {
         []
     )
 
-    const removeTrack = useCallback(async (id: string) => {
+    const removeTrack = useCallback(async (id: string, containerId?: string) => {
         try {
-            await invoke('storage_remove_track', { id })
+            await invoke('storage_remove_track', { id, containerId })
         } catch (error) {
             console.error('Failed to remove track:', error)
             throw error
@@ -82,9 +174,9 @@ const useInitialState = () => {
         }
     }, [])
 
-    const hasTrack = useCallback(async (id: string) => {
+    const hasTrack = useCallback(async (id: string, mediaSourceId?: string) => {
         try {
-            const exists = await invoke<boolean>('storage_has_track', { id })
+            const exists = await invoke<boolean>('storage_has_track', { id, mediaSourceId })
             return exists
         } catch (error) {
             console.error('Failed to check track:', error)
@@ -92,9 +184,9 @@ const useInitialState = () => {
         }
     }, [])
 
-    const getFilePath = useCallback(async (id: string): Promise<string | undefined> => {
+    const getFilePath = useCallback(async (id: string, mediaSourceId?: string): Promise<string | undefined> => {
         try {
-            const filePath = await invoke<string | null>('storage_get_file_path', { id })
+            const filePath = await invoke<string | null>('storage_get_file_path', { id, mediaSourceId })
             return filePath || undefined
         } catch (error) {
             console.error('Failed to get file path:', error)
@@ -102,9 +194,27 @@ const useInitialState = () => {
         }
     }, [])
 
-    const getTrackCount = useCallback(async () => {
+    const getStreamUrl = useCallback(async (id: string, mediaSourceId?: string): Promise<string | undefined> => {
+        try {
+            const streamUrl = await invoke<string | null>('storage_get_stream_url', { id, mediaSourceId })
+            return streamUrl || undefined
+        } catch (error) {
+            console.error('Failed to get stream url:', error)
+            return undefined
+        }
+    }, [])
+
+    const reportPlayback = useCallback(async (id: string, event: OfflinePlaybackEvent) => {
+        try {
+            await invoke('storage_report_playback', { id, event })
+        } catch (error) {
+            console.error('Failed to record offline playback:', error)
+        }
+    }, [])
+
+    const getTrackCount = useCallback(async (kind: BaseItemKind = BaseItemKind.Audio, filter?: OfflineItemFilter) => {
         try {
-            const count = await invoke<number>('storage_get_track_count', { kind: BaseItemKind.Audio })
+            const count = await invoke<number>('storage_get_track_count', { kind, filter })
             return count
         } catch (error) {
             console.error('Failed to get track count:', error)
@@ -121,38 +231,47 @@ const useInitialState = () => {
         }
     }, [])
 
+    const getSettings = useCallback(async (): Promise<StorageSettings | null> => {
+        try {
+            return await invoke<StorageSettings>('storage_get_settings')
+        } catch (error) {
+            console.error('Failed to get storage settings:', error)
+            return null
+        }
+    }, [])
+
+    const setSettings = useCallback(async (settings: StorageSettings) => {
+        try {
+            await invoke('storage_set_settings', { settings })
+        } catch (error) {
+            console.error('Failed to save storage settings:', error)
+            throw error
+        }
+    }, [])
+
     const getPageFromIndexedDb = async (
-        pageIndex: number,
+        cursor: string | undefined,
         itemKind: BaseItemKind,
-        itemsPerPage: number
-    ): Promise<MediaItem[]> => {
+        itemsPerPage: number,
+        sort?: OfflineSort,
+        filter?: OfflineItemFilter
+    ): Promise<OfflinePage> => {
         try {
-            const items = await invoke<MediaItem[]>('storage_get_page', {
-                pageIndex,
+            const page = await invoke<OfflinePage>('storage_get_page', {
                 itemKind,
                 itemsPerPage,
+                cursor,
+                sort,
+                filter,
             })
+            const items = page.items
 
-            // Load thumbnails for items that have them
-            for (const item of items) {
-                if ((item as any).hasThumbnail) {
-                    try {
-                        const thumbnailData = await invoke<number[] | null>('storage_get_thumbnail', { id: item.Id })
-                        if (thumbnailData) {
-                            const blob = new Blob([new Uint8Array(thumbnailData)])
-                            item.downloadedImageUrl = URL.createObjectURL(blob)
-                        }
-                    } catch (error) {
-                        console.warn('Failed to load thumbnail for', item.Id, error)
-                    }
-                    delete (item as any).hasThumbnail
-                }
-            }
+            await loadThumbnails(items)
 
-            return items
+            return { ...page, nextCursor: page.nextCursor || undefined }
         } catch (error) {
             console.error('Failed to get page:', error)
-            return []
+            return { items: [], totalCount: 0 }
         }
     }
 
@@ -165,21 +284,7 @@ const useInitialState = () => {
                 limit,
             })
 
-            // Load thumbnails for items that have them
-            for (const item of items) {
-                if ((item as any).hasThumbnail) {
-                    try {
-                        const thumbnailData = await invoke<number[] | null>('storage_get_thumbnail', { id: item.Id })
-                        if (thumbnailData) {
-                            const blob = new Blob([new Uint8Array(thumbnailData)])
-                            item.downloadedImageUrl = URL.createObjectURL(blob)
-                        }
-                    } catch (error) {
-                        console.warn('Failed to load thumbnail for', item.Id, error)
-                    }
-                    delete (item as any).hasThumbnail
-                }
-            }
+            await loadThumbnails(items)
 
             return items
         } catch (error) {
@@ -188,16 +293,42 @@ const useInitialState = () => {
         }
     }
 
+    const searchOfflineGrouped = async (
+        searchTerm: string,
+        groups: { itemType: BaseItemKind; limit: number }[]
+    ): Promise<OfflineSearchGroup[]> => {
+        try {
+            if (!searchTerm.trim()) return []
+
+            const results = await invoke<OfflineSearchGroup[]>('storage_search_grouped', {
+                searchTerm,
+                groups,
+            })
+
+            await loadThumbnails(results.flatMap(group => group.items))
+
+            return results
+        } catch (error) {
+            console.error('Failed to search items:', error)
+            return []
+        }
+    }
+
     const audioStorage = {
         saveTrack,
         removeTrack,
         getTrack,
         hasTrack,
         getFilePath,
+        getStreamUrl,
+        reportPlayback,
         getTrackCount,
         clearAllDownloads,
+        getSettings,
+        setSettings,
         getPageFromIndexedDb,
         searchOfflineItems,
+        searchOfflineGrouped,
         isInitialized: () => isInitialized.current,
     }
 
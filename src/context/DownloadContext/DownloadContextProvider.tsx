@@ -5,6 +5,7 @@ import { ReactNode, useCallback, useEffect, useRef, useState } from 'react'
 import { MediaItem } from '../../api/jellyfin'
 import { usePatchQueries } from '../../hooks/usePatchQueries'
 import { useAudioStorageContext } from '../AudioStorageContext/AudioStorageContext'
+import { DownloadProgress, DownloadState } from '../AudioStorageContext/AudioStorageContextProvider'
 import { useJellyfinContext } from '../JellyfinContext/JellyfinContext'
 import { usePlaybackContext } from '../PlaybackContext/PlaybackContext'
 import { DownloadContext } from './DownloadContext'
@@ -15,25 +16,52 @@ type Task = { mediaItem: MediaItem; action: 'download' | 'remove'; containerId?:
 
 export type IDownloadContext = ReturnType<typeof useInitialState>
 
+type StorageUsage = { id: string; name: string; itemType: string; count: number; size: number }
+
+export type StorageStats = {
+    usage: number
+    trackCount: number
+    byType?: { itemType: string; count: number; size: number }[]
+    // Top level series, collections and playlists
+    byContainer?: StorageUsage[]
+    byFileKind?: { video: number; images: number; subtitles: number; temp: number; other: number }
+    largest?: StorageUsage[]
+    freeSpace?: number | null
+    quota?: number | null
+}
+
+// Catalog totals sent with every storage event, without the file system scan `storage_get_stats` does
+type StorageTotals = { trackCount: number; size: number }
+
+type StorageItemsChanged = { ids: string[]; items: MediaItem[]; totals: StorageTotals }
+
+// Summary of the downloads since the queue last ran empty, sent to every window
+export type QueueProgress = {
+    state: DownloadState
+    currentId: string | null
+    queued: number
+    done: number
+    failed: number
+    downloaded: number
+    progress: number | null
+    speed: number
+}
+
 const useInitialState = () => {
     const api = useJellyfinContext()
     const playback = usePlaybackContext()
     const audioStorage = useAudioStorageContext()
     const { patchMediaItem, patchMediaItems, prependItemsToQueryData, removeItemFromQueryData } = usePatchQueries()
-    const [storageStats, setStorageStats] = useState({ usage: 0, trackCount: 0 })
+    const [storageStats, setStorageStats] = useState<StorageStats>({ usage: 0, trackCount: 0 })
     const progressBarRef = useRef<HTMLDivElement | null>(null)
     const [currentDownloadingId, setCurrentDownloadingId] = useState<string | undefined>(undefined)
-    const [downloadProgress, setDownloadProgress] = useState<{
-        speed: number
-        downloaded: number
-        total: number
-        timeRemaining: number
-    } | null>(null)
+    const [downloadProgress, setDownloadProgress] = useState<DownloadProgress | null>(null)
+    const [queueProgress, setQueueProgress] = useState<QueueProgress | null>(null)
 
     const refreshStorageStats = useCallback(async () => {
         if (isTauri()) {
             try {
-                const stats = await invoke<{ usage: number; trackCount: number }>('storage_get_stats')
+                const stats = await invoke<StorageStats>('storage_get_stats')
                 setStorageStats(stats)
             } catch (error) {
                 console.error('Failed to load storage stats:', error)
@@ -45,33 +73,59 @@ const useInitialState = () => {
         refreshStorageStats()
     }, [refreshStorageStats])
 
-    // Listen for download progress events
+    // Downloads of a whole series change the catalog once per episode, refetch the full stats once they settle
+    const statsTimeoutRef = useRef<ReturnType<typeof setTimeout> | undefined>(undefined)
+
+    // The patch helpers change every render, the listeners read them through a ref to stay subscribed
+    const patchQueriesRef = useRef({ patchMediaItems, prependItemsToQueryData, removeItemFromQueryData })
+    patchQueriesRef.current = { patchMediaItems, prependItemsToQueryData, removeItemFromQueryData }
+
+    // Keep queries and stats in sync with the catalog, also when background syncs change it
     useEffect(() => {
         if (!isTauri()) return
 
-        const unlisten = listen<{
-            id: string
-            downloaded: number
-            total: number
-            progress: number
-            speed: number
-            timeRemaining: number
-        }>('download-progress', event => {
-            const { id, downloaded, total, progress, speed, timeRemaining } = event.payload
-            console.log(`Download progress for ${id}: ${progress}% (${downloaded}/${total} bytes)`)
-
-            // Update the progress bar element
-            if (progressBarRef.current) {
-                progressBarRef.current.style.setProperty('--progress-percent', `${progress}%`)
-            }
+        const unlisteners = [
+            listen<StorageItemsChanged>('storage-item-added', event => {
+                const { patchMediaItems, prependItemsToQueryData } = patchQueriesRef.current
+
+                for (const item of event.payload.items) {
+                    prependItemsToQueryData(['downloads', item.Type || ''], [item])
+                }
+
+                patchMediaItems(event.payload.ids, item => ({ ...item, offlineState: 'downloaded' }))
+            }),
+            listen<StorageItemsChanged>('storage-item-removed', event => {
+                const { patchMediaItems, removeItemFromQueryData } = patchQueriesRef.current
+
+                for (const item of event.payload.items) {
+                    removeItemFromQueryData(['downloads', item.Type || ''], item.Id)
+                }
+
+                patchMediaItems(event.payload.ids, item => ({ ...item, offlineState: undefined }))
+            }),
+            listen<StorageTotals>('storage-cleared', () => {
+                setStorageStats({ usage: 0, trackCount: 0 })
+            }),
+            listen<StorageTotals>('storage-stats-changed', event => {
+                setStorageStats(prev => ({ ...prev, trackCount: event.payload.trackCount }))
+
+                clearTimeout(statsTimeoutRef.current)
+                statsTimeoutRef.current = setTimeout(refreshStorageStats, 1000)
+            }),
+        ]
+
+        return () => {
+            clearTimeout(statsTimeoutRef.current)
+            unlisteners.forEach(unlisten => unlisten.then(fn => fn()))
+        }
+    }, [refreshStorageStats])
+
+    // Progress of the current download comes through its own channel, this is the summary of the whole queue
+    useEffect(() => {
+        if (!isTauri()) return
 
-            // Update download progress state with data from Rust
-            setDownloadProgress({
-                speed,
-                downloaded,
-                total,
-                timeRemaining,
-            })
+        const unlisten = listen<QueueProgress>('download-queue-progress', event => {
+            setQueueProgress(event.payload)
         })
 
         return () => {
@@ -211,13 +265,42 @@ const useInitialState = () => {
             const { mediaItem, action } = next
             let shouldRemoveFromQueue = true
 
+            const saveOptions = {
+                queued: queue.length - 1,
+                onProgress: (progress: DownloadProgress) => {
+                    // Update the progress bar element
+                    if (progressBarRef.current) {
+                        progressBarRef.current.style.setProperty('--progress-percent', `${progress.progress ?? 0}%`)
+                    }
+
+                    setDownloadProgress(progress)
+                },
+            }
+
             try {
                 if (action === 'download') {
                     setCurrentDownloadingId(mediaItem.Id)
                     setDownloadProgress(null)
-                    const already = await audioStorage.hasTrack(mediaItem.Id)
+                    const already = await audioStorage.hasTrack(mediaItem.Id, next.mediaSourceId)
 
                     if (already) {
+                        // Shares the stored file with the new container instead of downloading it again
+                        if (next.containerId) {
+                            await audioStorage.saveTrack(
+                                mediaItem.Id,
+                                {
+                                    type: 'video',
+                                    timestamp: Date.now(),
+                                    bitrate: playback.bitrate,
+                                    mediaItem,
+                                    containerId: next.containerId,
+                                },
+                                undefined,
+                                undefined,
+                                saveOptions
+                            )
+                        }
+
                         patchMediaItem(mediaItem.Id, item => ({ ...item, offlineState: 'downloaded' }))
                     } else {
                         // The mediaItem object is saved so we modify it directly
@@ -248,7 +331,8 @@ const useInitialState = () => {
                                     mediaSourceId: next.mediaSourceId,
                                 },
                                 streamUrl,
-                                thumbnailUrl
+                                thumbnailUrl,
+                                saveOptions
                             )
                         } else {
                             const thumbnailUrl = api.getImageUrl(mediaItem, 'Primary', { width: 360, height: 360 })
@@ -262,20 +346,17 @@ const useInitialState = () => {
                                     mediaItem,
                                 },
                                 undefined,
-                                thumbnailUrl
+                                thumbnailUrl,
+                                saveOptions
                             )
                         }
 
-                        prependItemsToQueryData(['downloads', mediaItem.Type || ''], [mediaItem])
                         patchMediaItem(mediaItem.Id, item => ({ ...item, offlineState: 'downloaded' }))
                     }
                 } else if (action === 'remove') {
-                    await audioStorage.removeTrack(mediaItem.Id)
-                    removeItemFromQueryData(['downloads', mediaItem.Type || ''], mediaItem.Id)
+                    await audioStorage.removeTrack(mediaItem.Id, next.containerId)
                     patchMediaItem(mediaItem.Id, item => ({ ...item, offlineState: undefined }))
                 }
-
-                refreshStorageStats()
             } catch (error) {
                 console.error(`Task failed for ${action} id=${mediaItem.Id}`, error)
 
@@ -305,16 +386,7 @@ const useInitialState = () => {
         }
 
         runNext()
-    }, [
-        api,
-        audioStorage,
-        patchMediaItem,
-        playback.bitrate,
-        prependItemsToQueryData,
-        queue,
-        refreshStorageStats,
-        removeItemFromQueryData,
-    ])
+    }, [api, audioStorage, patchMediaItem, playback.bitrate, queue])
 
     // We need the addToDownloads in jellyfin API but we don't want to cause unnecessary re-renders
     window.addToDownloads = addToDownloads
@@ -375,6 +447,7 @@ const useInitialState = () => {
         progressBarRef,
         currentDownloadingId,
         downloadProgress,
+        queueProgress,
     }
 }
 
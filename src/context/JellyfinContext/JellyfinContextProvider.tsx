@@ -1,4 +1,5 @@
-import { ReactNode, useState } from 'react'
+import { invoke, isTauri } from '@tauri-apps/api/core'
+import { ReactNode, useEffect, useState } from 'react'
 import { IJellyfinAuth, initJellyfinApi } from '../../api/jellyfin'
 import { JellyfinContext } from './JellyfinContext'
 
@@ -7,6 +8,19 @@ export type IJellyfinContext = ReturnType<typeof useInitialState>
 const useInitialState = (auth: IJellyfinAuth) => {
     const [api] = useState(initJellyfinApi(auth))
 
+    // The storage backend needs the session to sync offline playback and downloads
+    useEffect(() => {
+        if (!isTauri()) return
+
+        invoke('storage_set_server', { auth }).catch(err => console.error('Failed to set storage server:', err))
+
+        return () => {
+            invoke('storage_set_server', { auth: null }).catch(err =>
+                console.error('Failed to clear storage server:', err)
+            )
+        }
+    }, [auth])
+
     return { ...api, auth } // Preferably we dont return the auth object here but we need it for legacy functions
 }
 
@@ -37,9 +37,28 @@ export const useJellyfinSearch = (searchQuery: string) => {
                 const limitedResults = [...movies, ...series, ...collections]
                 return limitedResults
             } else {
-                // Use offline search when no network
-                const offlineResults = await audioStorage.searchOfflineItems(debouncedSearchQuery, 10)
-                return offlineResults
+                // Use offline search when no network, grouped the same way
+                const [movies, series, collections, episodes] = await audioStorage.searchOfflineGrouped(
+                    debouncedSearchQuery,
+                    [
+                        { itemType: BaseItemKind.Movie, limit: 6 },
+                        { itemType: BaseItemKind.Series, limit: 6 },
+                        { itemType: BaseItemKind.BoxSet, limit: 6 },
+                        { itemType: BaseItemKind.Episode, limit: 6 },
+                    ]
+                )
+
+                if (!movies) {
+                    return []
+                }
+
+                // Episodes whose series isn't among the results, e.g. ones downloaded on their own, are listed themselves
+                const seriesIds = new Set(series.items.map(item => item.Id))
+                const standaloneEpisodes = episodes.items.filter(
+                    episode => !episode.SeriesId || !seriesIds.has(episode.SeriesId)
+                )
+
+                return [...movies.items, ...series.items, ...collections.items, ...standaloneEpisodes]
             }
         },
     })
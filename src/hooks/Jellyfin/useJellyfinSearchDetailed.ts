@@ -50,11 +50,18 @@ export const useJellyfinSearchDetailed = (searchQuery: string | undefined) => {
                     collections,
                 }
             } else {
-                const offlineSongs = await audioStorage.searchOfflineItems(debouncedSearchQuery, 50)
+                const groups = await audioStorage.searchOfflineGrouped(debouncedSearchQuery, [
+                    { itemType: BaseItemKind.Movie, limit: 12 },
+                    { itemType: BaseItemKind.Series, limit: 12 },
+                    { itemType: BaseItemKind.BoxSet, limit: 12 },
+                ])
+                const itemsOf = (itemType: BaseItemKind) =>
+                    groups.find(group => group.itemType === itemType)?.items || []
+
                 return {
-                    movies: offlineSongs,
-                    series: [],
-                    collections: [],
+                    movies: itemsOf(BaseItemKind.Movie),
+                    series: itemsOf(BaseItemKind.Series),
+                    collections: itemsOf(BaseItemKind.BoxSet),
                 }
             }
         },
@@ -1,30 +1,50 @@
+import { ItemSortBy } from '@jellyfin/sdk/lib/generated-client'
 import { useInfiniteQuery } from '@tanstack/react-query'
-import { MediaItem } from '../api/jellyfin'
 import { useAudioStorageContext } from '../context/AudioStorageContext/AudioStorageContext'
+import { OfflinePage } from '../context/AudioStorageContext/AudioStorageContextProvider'
 import { useFilterContext } from '../context/FilterContext/FilterContext'
 
+const offlineSorts: ItemSortBy[] = [
+    ItemSortBy.DateCreated,
+    ItemSortBy.SortName,
+    ItemSortBy.Name,
+    ItemSortBy.PremiereDate,
+    ItemSortBy.ParentIndexNumber,
+    ItemSortBy.IndexNumber,
+    ItemSortBy.Runtime,
+    ItemSortBy.CommunityRating,
+]
+
 export const useIndexedDbDownloadsData = () => {
     const audioStorage = useAudioStorageContext()
-    const { jellyItemKind } = useFilterContext()
+    const { jellyItemKind, jellySort } = useFilterContext()
     const itemsPerPage = 36
 
+    // Offline pages support the stable sorts only, random and playlist order fall back to date added
+    const offlineSortBy = offlineSorts.includes(jellySort.sortBy[0]) ? jellySort.sortBy[0] : ItemSortBy.DateCreated
+    const sortOrder = jellySort.sortOrder[0]
+
     const { data, isFetching, isPending, error, fetchNextPage, hasNextPage, isFetchingNextPage } = useInfiniteQuery<
-        MediaItem[],
+        OfflinePage,
         Error
     >({
-        queryKey: ['downloads', jellyItemKind],
-        queryFn: async ({ pageParam = 0 }) => {
-            return await audioStorage.getPageFromIndexedDb(pageParam as number, jellyItemKind, itemsPerPage)
-        },
-        getNextPageParam: (lastPage: MediaItem[], allPages: MediaItem[][]) => {
-            return lastPage.length === itemsPerPage ? allPages.length : undefined
+        queryKey: ['downloads', jellyItemKind, offlineSortBy, sortOrder],
+        queryFn: async ({ pageParam }) => {
+            return await audioStorage.getPageFromIndexedDb(
+                pageParam as string | undefined,
+                jellyItemKind,
+                itemsPerPage,
+                { sortBy: offlineSortBy, sortOrder }
+            )
         },
-        initialPageParam: 0,
+        getNextPageParam: (lastPage: OfflinePage) => lastPage.nextCursor,
+        initialPageParam: undefined,
         staleTime: 0,
         retry: false,
     })
 
-    const allItems = data ? data.pages.flat() : []
+    const allItems = data ? data.pages.flatMap(page => page.items) : []
+    const totalCount = data?.pages[data.pages.length - 1]?.totalCount ?? 0
 
     const loadMore = async () => {
         if (hasNextPage && !isFetchingNextPage) {
@@ -34,6 +54,7 @@ export const useIndexedDbDownloadsData = () => {
 
     return {
         items: allItems,
+        totalCount,
         isLoading: isFetching || isPending,
         error: error ? error.message : null,
         hasNextPage,
@@ -47,6 +47,13 @@ const patchData = (data: unknown, itemIds: string[], patch: IPatch): unknown =>
 
 type IPatch = (item: MediaItem) => MediaItem
 
+// Offline pages come with their total and cursor, the items sit in `items`
+type IPage = MediaItem[] | { items: MediaItem[] }
+
+const mapPageItems = (page: IPage, map: (items: MediaItem[]) => MediaItem[]): IPage => {
+    return Array.isArray(page) ? map(page) : { ...page, items: map(page.items) }
+}
+
 export const usePatchQueries = () => {
     const queryClient = useQueryClient()
 
@@ -94,11 +101,11 @@ export const usePatchQueries = () => {
 
                 setQueryData(query, data => {
                     if (isPages(data)) {
-                        const [first, ...pages] = data.pages
+                        const [first, ...pages] = data.pages as IPage[]
 
                         query.setData({
                             ...data,
-                            pages: [[...items, ...first], ...pages],
+                            pages: [mapPageItems(first, page => [...items, ...page]), ...pages],
                         })
                     } else {
                         query.setData([...items, ...(data as MediaItem[])])
@@ -117,7 +124,9 @@ export const usePatchQueries = () => {
                     if (isPages(data)) {
                         query.setData({
                             ...data,
-                            pages: data.pages.map(page => page.filter((item: MediaItem) => item.Id !== itemId)),
+                            pages: (data.pages as IPage[]).map(page =>
+                                mapPageItems(page, items => items.filter(item => item.Id !== itemId))
+                            ),
                         })
                     } else {
                         query.setData((data as MediaItem[]).filter(item => item.Id !== itemId))
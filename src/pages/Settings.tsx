@@ -16,6 +16,7 @@ import { open } from '@tauri-apps/plugin-shell'
 import { useCallback, useEffect, useState } from 'react'
 import { Link, useNavigate } from 'react-router-dom'
 import { useAudioStorageContext } from '../context/AudioStorageContext/AudioStorageContext'
+import { StorageSettings } from '../context/AudioStorageContext/AudioStorageContextProvider'
 import { useDownloadContext } from '../context/DownloadContext/DownloadContext'
 import { useJellyfinContext } from '../context/JellyfinContext/JellyfinContext'
 import { usePlaybackContext } from '../context/PlaybackContext/PlaybackContext'
@@ -26,6 +27,17 @@ import { persister } from '../queryClient'
 import { formatFileSize } from '../utils/formatFileSize'
 import './Settings.css'
 
+const GB = 1024 * 1024 * 1024
+
+const itemTypeLabels: Record<string, string> = {
+    Movie: 'Movies',
+    Episode: 'Episodes',
+    Season: 'Seasons',
+    Series: 'Series',
+    BoxSet: 'Collections',
+    Playlist: 'Playlists',
+}
+
 export const Settings = ({ onLogout }: { onLogout: () => void }) => {
     const navigate = useNavigate()
     const api = useJellyfinContext()
@@ -74,6 +86,7 @@ export const Settings = ({ onLogout }: { onLogout: () => void }) => {
     const { storageStats, refreshStorageStats, queueCount, clearQueue } = useDownloadContext()
 
     const [clearing, setClearing] = useState(false)
+    const [storageSettings, setStorageSettings] = useState<StorageSettings | null>(null)
     const { latestRelease, updateStatus, isCheckingUpdate } = useUpdateChecker(checkForUpdates)
     const [forceChecking, setForceChecking] = useState(false)
 
@@ -116,6 +129,46 @@ export const Settings = ({ onLogout }: { onLogout: () => void }) => {
         fetchData()
     }, [api])
 
+    useEffect(() => {
+        audioStorage.getSettings().then(setStorageSettings)
+    }, [audioStorage.getSettings])
+
+    const updateStorageSettings = useCallback(
+        async (changes: Partial<StorageSettings>) => {
+            if (!storageSettings) {
+                return
+            }
+
+            const previous = storageSettings
+            const next = { ...storageSettings, ...changes }
+            setStorageSettings(next)
+
+            try {
+                await audioStorage.setSettings(next)
+            } catch {
+                setStorageSettings(previous)
+                return
+            }
+
+            // The quota is reported with the stats
+            if ('storageQuotaBytes' in changes) {
+                refreshStorageStats()
+            }
+        },
+        [audioStorage, refreshStorageStats, storageSettings]
+    )
+
+    const fileKinds = storageStats.byFileKind
+        ? [
+              { label: 'Videos', size: storageStats.byFileKind.video },
+              { label: 'Images', size: storageStats.byFileKind.images },
+              { label: 'Subtitles', size: storageStats.byFileKind.subtitles },
+              { label: 'Temporary', size: storageStats.byFileKind.temp },
+              { label: 'Other', size: storageStats.byFileKind.other },
+          ].filter(kind => kind.size > 0)
+        : []
+    const overQuota = Boolean(storageStats.quota && storageStats.usage > storageStats.quota)
+
     const handleLogout = () => {
         resetSessionCount()
         onLogout()
@@ -138,13 +191,12 @@ export const Settings = ({ onLogout }: { onLogout: () => void }) => {
             queryClient.clear()
             await persister.removeClient()
             clearQueue()
-            await refreshStorageStats()
         } catch (error) {
             console.error('Failed to clear downloads:', error)
         } finally {
             setClearing(false)
         }
-    }, [audioStorage, clearQueue, queryClient, refreshStorageStats])
+    }, [audioStorage, clearQueue, queryClient])
 
     const handleOpenDownloadsFolder = useCallback(async () => {
         try {
@@ -668,9 +720,21 @@ export const Settings = ({ onLogout }: { onLogout: () => void }) => {
                                 </>
                             )}{' '}
                             /{' '}
-                            <span className="number">
+                            <span className={`number ${overQuota ? 'over' : ''}`}>
                                 {formatFileSize(storageStats.trackCount === 0 ? 0 : storageStats?.usage || 0)}
                             </span>
+                            {storageStats.quota ? (
+                                <>
+                                    {' '}
+                                    of <span className="number">{formatFileSize(storageStats.quota)}</span>
+                                </>
+                            ) : null}
+                            {storageStats.freeSpace != null && (
+                                <>
+                                    {' '}
+                                    - <span className="number">{formatFileSize(storageStats.freeSpace)}</span> free
+                                </>
+                            )}
                         </div>
                     </div>
                     <div className="actions noSelect">
@@ -681,6 +745,60 @@ export const Settings = ({ onLogout }: { onLogout: () => void }) => {
                         )}
                     </div>
                 </div>
+                {storageStats.trackCount > 0 && (
+                    <div className="breakdown">
+                        {fileKinds.length > 0 && (
+                            <div className="group">
+                                <div className="subtitle">Files</div>
+                                {fileKinds.map(kind => (
+                                    <div className="entry" key={kind.label}>
+                                        <div className="name">{kind.label}</div>
+                                        <div className="number">{formatFileSize(kind.size)}</div>
+                                    </div>
+                                ))}
+                            </div>
+                        )}
+                        {storageStats.byType && storageStats.byType.length > 0 && (
+                            <div className="group">
+                                <div className="subtitle">Types</div>
+                                {storageStats.byType.map(usage => (
+                                    <div className="entry" key={usage.itemType}>
+                                        <div className="name">
+                                            {itemTypeLabels[usage.itemType] || usage.itemType} ({usage.count})
+                                        </div>
+                                        <div className="number">{formatFileSize(usage.size)}</div>
+                                    </div>
+                                ))}
+                            </div>
+                        )}
+                        {storageStats.byContainer && storageStats.byContainer.length > 0 && (
+                            <div className="group">
+                                <div className="subtitle">Series and collections</div>
+                                {storageStats.byContainer.slice(0, 5).map(usage => (
+                                    <div className="entry" key={usage.id}>
+                                        <div className="name" title={usage.name}>
+                                            {usage.name} ({usage.count})
+                                        </div>
+                                        <div className="number">{formatFileSize(usage.size)}</div>
+                                    </div>
+                                ))}
+                            </div>
+                        )}
+                        {storageStats.largest && storageStats.largest.length > 0 && (
+                            <div className="group">
+                                <div className="subtitle">Largest</div>
+                                {storageStats.largest.slice(0, 5).map(usage => (
+                                    <div className="entry" key={usage.id}>
+                                        <div className="name" title={usage.name}>
+                                            {usage.name}
+                                        </div>
+                                        <div className="number">{formatFileSize(usage.size)}</div>
+                                    </div>
+                                ))}
+                            </div>
+                        )}
+                    </div>
+                )}
                 <div className="desc">
                     <div className="info">
                         Download your video library for seamless offline playback. Supports movies, tv shows, and
@@ -699,6 +817,115 @@ export const Settings = ({ onLogout }: { onLogout: () => void }) => {
                 </div>
             </div>
 
+            {storageSettings && (
+                <div className="section offline ui">
+                    <div className="title">Offline storage</div>
+                    <div className="inner row">
+                        <div className="container">
+                            <div className="desc">
+                                <div className="subtitle">Refresh downloads</div>
+                                <div className="subdesc">Update details of downloaded titles from the server</div>
+                            </div>
+                            <div className="sorting">
+                                <div className="filter">
+                                    <select
+                                        onChange={e =>
+                                            updateStorageSettings({
+                                                refreshIntervalMinutes: Number(e.target.value) || undefined,
+                                            })
+                                        }
+                                        value={storageSettings.refreshIntervalMinutes || 0}
+                                    >
+                                        <option value="0">Never</option>
+                                        <option value="15">Every 15 minutes</option>
+                                        <option value="60">Every hour</option>
+                                        <option value="360">Every 6 hours</option>
+                                        <option value="1440">Every day</option>
+                                    </select>
+                                    <div className="icon">
+                                        <ChevronDownIcon size={12} />
+                                    </div>
+                                </div>
+                            </div>
+                        </div>
+                    </div>
+                    <div className="inner row">
+                        <div className="container">
+                            <div className="desc">
+                                <div className="subtitle">Storage quota</div>
+                                <div className="subdesc">
+                                    Space downloads should stay under, shown next to the usage
+                                </div>
+                            </div>
+                            <div className="sorting">
+                                <div className="filter">
+                                    <select
+                                        onChange={e =>
+                                            updateStorageSettings({
+                                                storageQuotaBytes: Number(e.target.value) * GB || undefined,
+                                            })
+                                        }
+                                        value={Math.round((storageSettings.storageQuotaBytes || 0) / GB)}
+                                    >
+                                        <option value="0">No quota</option>
+                                        <option value="16">16 GB</option>
+                                        <option value="32">32 GB</option>
+                                        <option value="64">64 GB</option>
+                                        <option value="128">128 GB</option>
+                                        <option value="256">256 GB</option>
+                                        <option value="512">512 GB</option>
+                                        <option value="1024">1 TB</option>
+                                    </select>
+                                    <div className="icon">
+                                        <ChevronDownIcon size={12} />
+                                    </div>
+                                </div>
+                            </div>
+                        </div>
+                    </div>
+                    <div className="inner row">
+                        <div className="container">
+                            <div className="desc">
+                                <div className="subtitle">Upgrade downloads</div>
+                                <div className="subdesc">
+                                    Download titles again when the server gets a higher quality version
+                                </div>
+                            </div>
+                            <div className="option">
+                                <label className="switch">
+                                    <input
+                                        type="checkbox"
+                                        checked={storageSettings.autoUpgradeDownloads}
+                                        onChange={e => updateStorageSettings({ autoUpgradeDownloads: e.target.checked })}
+                                    ></input>
+                                    <span className="slider"></span>
+                                </label>
+                            </div>
+                        </div>
+                    </div>
+                    <div className="inner row">
+                        <div className="container">
+                            <div className="desc">
+                                <div className="subtitle">Encryption</div>
+                                <div className="subdesc">
+                                    Encrypt downloads on disk, including the ones already stored
+                                </div>
+                            </div>
+                            <div className="option">
+                                <label className="switch">
+                                    <input
+                                        type="checkbox"
+                                        checked={storageSettings.encryptStorage}
+                                        onChange={e => updateStorageSettings({ encryptStorage: e.target.checked })}
+                                    ></input>
+                                    <span className="slider"></span>
+                                </label>
+                            </div>
+                        </div>
+                    </div>
+                </div>
+            )}
+
             <div className="section shortcuts">
                 <div className="title">Shortcuts</div>
                 <div className="desc">